@@ -0,0 +1,73 @@
+// Round trip latency of one small request at a time against an echo server,
+// with and without writing to the socket straight from queue_write.
+// Run with `cargo bench -p mio-framed`.
+use bytes::{Bytes, IntoBuf};
+use mio_framed::{new_simple, App, Context, Core};
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROUND_TRIPS: u32 = 20_000;
+
+struct PingPong {
+    remaining: Rc<Cell<u32>>,
+}
+
+impl App for PingPong {
+    fn handle_frames(&mut self, ctx: &Context, id: usize, frames: Vec<Bytes>) {
+        for _ in frames {
+            self.remaining.set(self.remaining.get() - 1);
+            if self.remaining.get() > 0 {
+                ctx.write_frame(id, "ping".into_buf());
+            }
+        }
+    }
+}
+
+fn echo_server(addr: &'static str, write_immediately: bool) {
+    thread::spawn(move || {
+        let mut server = new_simple(|ctx, id, frames| {
+            for frame in frames {
+                ctx.write_frame(id, frame.into_buf());
+            }
+        });
+        server.set_write_immediately(write_immediately);
+        server.listen(addr).unwrap();
+        server.run().unwrap();
+    });
+}
+
+fn round_trips(addr: &str, write_immediately: bool) -> Duration {
+    let remaining = Rc::new(Cell::new(ROUND_TRIPS));
+    let mut client = Core::new(PingPong {
+        remaining: remaining.clone(),
+    });
+    client.set_write_immediately(write_immediately);
+    let id = client.connect(addr).unwrap();
+    let start = Instant::now();
+    client.write_frame(id, "ping".into_buf());
+    while remaining.get() > 0 {
+        client.turn(None).unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let modes = [
+        ("waiting", "127.0.0.1:13296", false),
+        ("immediate", "127.0.0.1:13297", true),
+    ];
+    for (_, addr, write_immediately) in modes.iter() {
+        echo_server(addr, *write_immediately);
+    }
+    thread::sleep(Duration::from_millis(100));
+    for (name, addr, write_immediately) in modes.iter() {
+        // Once to warm up, then for real
+        round_trips(addr, *write_immediately);
+        let elapsed = round_trips(addr, *write_immediately);
+        let micros = elapsed.as_secs_f64() * 1e6 / f64::from(ROUND_TRIPS);
+        println!("{:<10} {:>8.1} us/round trip", name, micros);
+    }
+}
@@ -0,0 +1,72 @@
+// Throughput of many small one-way frames, written one syscall per frame
+// with TCP_NODELAY, or corked and coalesced into one write per window.
+// Run with `cargo bench -p mio-framed`.
+use bytes::IntoBuf;
+use mio_framed::new_simple;
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FRAMES: u32 = 200_000;
+// Frames written between turns of the loop
+const BURST: u32 = 100;
+
+// Says "done" once a connection has sent FRAMES frames
+fn sink_server(addr: &'static str) {
+    thread::spawn(move || {
+        let mut counts: HashMap<usize, u32> = HashMap::new();
+        let mut server = new_simple(move |ctx, id, frames| {
+            let count = counts.entry(id).or_insert(0);
+            *count += frames.len() as u32;
+            if *count == FRAMES {
+                // Ids are reused once the client hangs up
+                counts.remove(&id);
+                ctx.write_frame(id, "done".into_buf());
+            }
+        });
+        server.listen(addr).unwrap();
+        server.run().unwrap();
+    });
+}
+
+fn send_all(addr: &str, cork: Option<Duration>) -> Duration {
+    let done = Rc::new(Cell::new(false));
+    let mut client = {
+        let done = done.clone();
+        new_simple(move |_ctx, _id, _frames| done.set(true))
+    };
+    client.set_nodelay(true);
+    client.set_cork(cork);
+    let id = client.connect(addr).unwrap();
+    let start = Instant::now();
+    for n in 0..FRAMES {
+        client.write_frame(id, n.to_le_bytes().to_vec().into_buf());
+        if n % BURST == 0 {
+            client.turn(Some(Duration::from_millis(0))).unwrap();
+        }
+    }
+    while !done.get() {
+        client.turn(None).unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let addr = "127.0.0.1:13323";
+    sink_server(addr);
+    thread::sleep(Duration::from_millis(100));
+    let modes = [
+        ("nodelay", None),
+        ("corked", Some(Duration::from_millis(5))),
+    ];
+    for (name, cork) in modes.iter() {
+        // Once to warm up, then for real
+        send_all(addr, *cork);
+        let elapsed = send_all(addr, *cork);
+        let rate = f64::from(FRAMES) / elapsed.as_secs_f64() / 1e3;
+        println!("{:<10} {:>8.1} k frames/s", name, rate);
+    }
+}
@@ -1,5 +1,5 @@
 use failure::Error;
-use mesh_msg::new_simple;
+use mio_framed::new_simple;
 
 use crossbeam::thread;
 use linefeed::{Interface, ReadResult};
@@ -15,7 +15,7 @@ fn main() -> Result<(), Error> {
         let iface = reader.clone();
 
         let mut core = new_simple(move |_ctx, _id, frames| {
-            if frames.len() > 0 {
+            if !frames.is_empty() {
                 writeln!(iface, "{:?}", frames).unwrap();
             }
         });
@@ -1,5 +1,5 @@
 use bytes::{Bytes, IntoBuf};
-use mesh_msg::{App, Context, Core};
+use mio_framed::{App, Context, Core};
 use std::io;
 
 struct BroadcastServer {}
@@ -1,6 +1,8 @@
 use crate::{Context, Core};
 use bytes::Bytes;
+use mio::Ready;
 
+use std::io;
 use std::marker::PhantomData;
 
 pub trait App {
@@ -10,7 +12,13 @@ pub trait App {
     fn handle_connect(&mut self, _ctx: &Context, _id: usize) {}
     fn handle_accept(&mut self, _ctx: &Context, _listen_socket: usize, _id: usize) {}
     fn handle_close(&mut self, _ctx: &Context, _id: usize) {}
+    // Dialing `id` failed before handle_connect, so no handle_close follows.
+    // Persistent peers keep the id and try again later.
+    fn handle_connect_error(&mut self, _ctx: &Context, _id: usize, _error: io::Error) {}
     fn handle_frames(&mut self, _ctx: &Context, _id: usize, _frames: Vec<Bytes>) {}
+    fn handle_timeout(&mut self, _ctx: &Context, _token: usize) {}
+    // A file descriptor registered with Context::watch_fd is ready
+    fn handle_ready(&mut self, _ctx: &Context, _token: usize, _readiness: Ready) {}
     fn handle_shutdown(&mut self) {}
 }
 
@@ -0,0 +1,521 @@
+use crate::{App, Context, Core};
+use bytes::{BufMut, Bytes, BytesMut, IntoBuf};
+use failure::{format_err, Error};
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+// Every frame is a kind byte and a little-endian u32 channel id.
+// Open and OpenConfirm follow it with the sender's receive window,
+// Window with the credit being returned.
+const KIND_OPEN: u8 = 0;
+const KIND_OPEN_CONFIRM: u8 = 1;
+const KIND_OPEN_REJECT: u8 = 2;
+const KIND_DATA: u8 = 3;
+const KIND_WINDOW: u8 = 4;
+const KIND_CLOSE: u8 = 5;
+const HEADER_LEN: usize = 5;
+
+pub const DEFAULT_WINDOW: u32 = 64 * 1024;
+// Big writes are cut up so other channels can get a word in
+const MAX_CHUNK: usize = 16 * 1024;
+
+pub trait ChannelApp {
+    fn handle_init(&mut self, _ctx: &mut ChannelContext) {}
+    fn handle_connect(&mut self, _ctx: &mut ChannelContext, _id: usize) {}
+    fn handle_accept(&mut self, _ctx: &mut ChannelContext, _listen_socket: usize, _id: usize) {}
+    // Called after every channel on the connection has been closed
+    fn handle_close(&mut self, _ctx: &mut ChannelContext, _id: usize) {}
+    // The peer wants a channel, return false to reject it
+    fn handle_channel_open(
+        &mut self,
+        _ctx: &mut ChannelContext,
+        _id: usize,
+        _channel: u32,
+        _label: Bytes,
+    ) -> bool {
+        false
+    }
+    // A channel we opened was accepted (true) or rejected (false)
+    fn handle_channel_opened(
+        &mut self,
+        _ctx: &mut ChannelContext,
+        _id: usize,
+        _channel: u32,
+        _accepted: bool,
+    ) {
+    }
+    // The peer can only send more once the data is handed to
+    // ChannelContext::consume, now or later
+    fn handle_channel_data(
+        &mut self,
+        _ctx: &mut ChannelContext,
+        _id: usize,
+        _channel: u32,
+        _data: Bytes,
+    ) {
+    }
+    // The peer closed the channel, or the connection went away
+    fn handle_channel_close(&mut self, _ctx: &mut ChannelContext, _id: usize, _channel: u32) {}
+    fn handle_timeout(&mut self, _ctx: &mut ChannelContext, _token: usize) {}
+    fn handle_shutdown(&mut self) {}
+}
+
+#[derive(Debug, PartialEq)]
+enum ChannelState {
+    // Open sent, waiting for the peer to confirm
+    Opening,
+    Open,
+}
+
+struct Channel {
+    state: ChannelState,
+    // Close requested locally, sent once the queue drains
+    closing: bool,
+    // Bytes the peer is still willing to receive
+    send_window: u32,
+    queue: VecDeque<Bytes>,
+    // Bytes received since we last returned credit
+    consumed: u32,
+}
+
+impl Channel {
+    fn new(state: ChannelState, send_window: u32) -> Self {
+        Channel {
+            state,
+            closing: false,
+            send_window,
+            queue: VecDeque::new(),
+            consumed: 0,
+        }
+    }
+
+    fn queued(&self) -> usize {
+        self.queue.iter().map(|data| data.len()).sum()
+    }
+}
+
+pub struct ChannelContext<'a> {
+    ctx: &'a Context,
+    state: &'a mut ChannelsState,
+}
+
+impl<'a> ChannelContext<'a> {
+    pub fn context(&self) -> &Context {
+        self.ctx
+    }
+    // Data may be sent right away, it's held until the peer accepts
+    pub fn open<B: AsRef<[u8]>>(&mut self, id: usize, label: B) -> u32 {
+        // Outbound connections use odd ids and inbound even, so both ends can open
+        let outbound = self.ctx.connection(id).is_some_and(|c| c.outbound);
+        let next = self.state.next_channel.entry(id).or_insert(0);
+        *next += 1;
+        let channel = *next * 2 - outbound as u32;
+        let window = self.state.window;
+        self.state
+            .channels
+            .insert((id, channel), Channel::new(ChannelState::Opening, 0));
+        write_frame(
+            self.ctx,
+            id,
+            KIND_OPEN,
+            channel,
+            &[&window.to_le_bytes(), label.as_ref()],
+        );
+        channel
+    }
+    pub fn send<B: Into<Bytes>>(&mut self, id: usize, channel: u32, data: B) -> Result<(), Error> {
+        let entry = self
+            .state
+            .channels
+            .get_mut(&(id, channel))
+            .filter(|entry| !entry.closing)
+            .ok_or_else(|| format_err!("Channel {} on connection {} is not open", channel, id))?;
+        entry.queue.push_back(data.into());
+        self.state.flush(self.ctx, id, channel);
+        Ok(())
+    }
+    // Queued data is still delivered before the peer hears about the close
+    pub fn close(&mut self, id: usize, channel: u32) {
+        if let Some(entry) = self.state.channels.get_mut(&(id, channel)) {
+            entry.closing = true;
+            self.state.flush(self.ctx, id, channel);
+        }
+    }
+    pub fn channels<'b>(&'b self, id: usize) -> impl Iterator<Item = u32> + 'b {
+        self.state
+            .channels
+            .keys()
+            .filter(move |(conn, _)| *conn == id)
+            .map(|(_, channel)| *channel)
+    }
+    // The app is done with `len` bytes received on the channel,
+    // so the peer may send that much more
+    pub fn consume(&mut self, id: usize, channel: u32, len: usize) {
+        self.state.consumed(self.ctx, id, channel, len);
+    }
+    // Bytes waiting for the peer to open up its window
+    pub fn queued(&self, id: usize, channel: u32) -> usize {
+        self.state
+            .channels
+            .get(&(id, channel))
+            .map_or(0, |entry| entry.queued())
+    }
+    pub fn set_timeout(&self, token: usize, delay: Duration) {
+        self.ctx.set_timeout(token, delay);
+    }
+    pub fn cancel_timeout(&self, token: usize) {
+        self.ctx.cancel_timeout(token);
+    }
+}
+
+fn write_frame(ctx: &Context, id: usize, kind: u8, channel: u32, parts: &[&[u8]]) {
+    let len = parts.iter().map(|part| part.len()).sum::<usize>();
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + len);
+    frame.put_u8(kind);
+    frame.put_u32_le(channel);
+    for part in parts {
+        frame.put_slice(part);
+    }
+    ctx.write_frame(id, frame.freeze().into_buf());
+}
+
+fn read_u32(data: &[u8]) -> Option<u32> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(data.get(..4)?);
+    Some(u32::from_le_bytes(bytes))
+}
+
+struct ChannelsState {
+    // Receive window offered to peers for each channel
+    window: u32,
+    next_channel: HashMap<usize, u32>,
+    channels: HashMap<(usize, u32), Channel>,
+}
+
+impl ChannelsState {
+    // Write as much of the queue as the peer's window allows
+    fn flush(&mut self, ctx: &Context, id: usize, channel: u32) {
+        let entry = match self.channels.get_mut(&(id, channel)) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if entry.state == ChannelState::Opening {
+            return;
+        }
+        while entry.send_window > 0 {
+            let mut data = match entry.queue.pop_front() {
+                Some(data) => data,
+                None => break,
+            };
+            let len = data.len().min(MAX_CHUNK).min(entry.send_window as usize);
+            if len < data.len() {
+                entry.queue.push_front(data.split_off(len));
+            }
+            entry.send_window -= len as u32;
+            write_frame(ctx, id, KIND_DATA, channel, &[&data]);
+        }
+        if entry.closing && entry.queue.is_empty() {
+            self.channels.remove(&(id, channel));
+            write_frame(ctx, id, KIND_CLOSE, channel, &[]);
+        }
+    }
+
+    // Hand credit back once the app has consumed half the window
+    fn consumed(&mut self, ctx: &Context, id: usize, channel: u32, len: usize) {
+        let window = self.window;
+        if let Some(entry) = self.channels.get_mut(&(id, channel)) {
+            entry.consumed += len as u32;
+            if entry.consumed >= window / 2 {
+                let credit = entry.consumed.to_le_bytes();
+                entry.consumed = 0;
+                write_frame(ctx, id, KIND_WINDOW, channel, &[&credit]);
+            }
+        }
+    }
+}
+
+pub struct Channels<A: ChannelApp> {
+    app: A,
+    state: ChannelsState,
+}
+
+pub fn new_channels<A: ChannelApp>(app: A) -> Core<Channels<A>> {
+    Core::new(Channels::new(app))
+}
+
+impl<A: ChannelApp> Channels<A> {
+    pub fn new(app: A) -> Self {
+        let state = ChannelsState {
+            window: DEFAULT_WINDOW,
+            next_channel: HashMap::new(),
+            channels: HashMap::new(),
+        };
+        Channels { app, state }
+    }
+
+    // How many unacknowledged bytes a peer may send on each channel
+    pub fn window(mut self, window: u32) -> Self {
+        self.state.window = window.max(1);
+        self
+    }
+
+    fn wrap_context<'a>(&'a mut self, ctx: &'a Context) -> (&'a mut A, ChannelContext<'a>) {
+        let ctx = ChannelContext {
+            ctx,
+            state: &mut self.state,
+        };
+        (&mut self.app, ctx)
+    }
+
+    fn handle_frame(&mut self, ctx: &Context, id: usize, mut frame: Bytes) {
+        if frame.len() < HEADER_LEN {
+            // XXX app.handle_decode_error?
+            return;
+        }
+        let kind = frame[0];
+        let channel = read_u32(&frame[1..]).unwrap_or_default();
+        let body = frame.split_off(HEADER_LEN);
+        let key = (id, channel);
+        let opening = self
+            .state
+            .channels
+            .get(&key)
+            .is_some_and(|entry| entry.state == ChannelState::Opening);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        match kind {
+            // An id that is already in use can't be opened again
+            KIND_OPEN if ctx.state.channels.contains_key(&key) => {
+                write_frame(ctx.ctx, id, KIND_OPEN_REJECT, channel, &[]);
+            }
+            KIND_OPEN => {
+                let send_window = match read_u32(&body) {
+                    Some(window) => window,
+                    None => return,
+                };
+                let label = body.slice_from(4);
+                let entry = Channel::new(ChannelState::Open, send_window);
+                ctx.state.channels.insert(key, entry);
+                if app.handle_channel_open(&mut ctx, id, channel, label) {
+                    let window = ctx.state.window.to_le_bytes();
+                    write_frame(ctx.ctx, id, KIND_OPEN_CONFIRM, channel, &[&window]);
+                    ctx.state.flush(ctx.ctx, id, channel);
+                } else {
+                    ctx.state.channels.remove(&key);
+                    write_frame(ctx.ctx, id, KIND_OPEN_REJECT, channel, &[]);
+                }
+            }
+            // Only answers an open of ours, a stray or repeated one is ignored
+            KIND_OPEN_CONFIRM if opening => {
+                let send_window = match read_u32(&body) {
+                    Some(window) => window,
+                    None => return,
+                };
+                if let Some(entry) = ctx.state.channels.get_mut(&key) {
+                    entry.state = ChannelState::Open;
+                    // Keeps any credit a Window already granted
+                    entry.send_window = entry.send_window.saturating_add(send_window);
+                }
+                app.handle_channel_opened(&mut ctx, id, channel, true);
+                ctx.state.flush(ctx.ctx, id, channel);
+            }
+            KIND_OPEN_REJECT if opening => {
+                ctx.state.channels.remove(&key);
+                app.handle_channel_opened(&mut ctx, id, channel, false);
+            }
+            // XXX Nothing stops a peer from ignoring its window
+            KIND_DATA if ctx.state.channels.contains_key(&key) => {
+                app.handle_channel_data(&mut ctx, id, channel, body);
+            }
+            KIND_WINDOW => {
+                let credit = read_u32(&body).unwrap_or_default();
+                if let Some(entry) = ctx.state.channels.get_mut(&key) {
+                    entry.send_window = entry.send_window.saturating_add(credit);
+                }
+                ctx.state.flush(ctx.ctx, id, channel);
+            }
+            // Anything we still had queued is dropped
+            KIND_CLOSE if ctx.state.channels.remove(&key).is_some() => {
+                app.handle_channel_close(&mut ctx, id, channel);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<A: ChannelApp> App for Channels<A> {
+    fn handle_init(&mut self, ctx: &Context) {
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_init(&mut ctx)
+    }
+    fn handle_connect(&mut self, ctx: &Context, id: usize) {
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_connect(&mut ctx, id)
+    }
+    fn handle_accept(&mut self, ctx: &Context, listen_socket: usize, id: usize) {
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_accept(&mut ctx, listen_socket, id)
+    }
+    fn handle_close(&mut self, ctx: &Context, id: usize) {
+        let mut closed: Vec<u32> = self
+            .state
+            .channels
+            .keys()
+            .filter(|(conn, _)| *conn == id)
+            .map(|(_, channel)| *channel)
+            .collect();
+        closed.sort();
+        self.state.next_channel.remove(&id);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        for channel in closed {
+            ctx.state.channels.remove(&(id, channel));
+            app.handle_channel_close(&mut ctx, id, channel);
+        }
+        app.handle_close(&mut ctx, id)
+    }
+    fn handle_frames(&mut self, ctx: &Context, id: usize, frames: Vec<Bytes>) {
+        for frame in frames {
+            self.handle_frame(ctx, id, frame);
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &Context, token: usize) {
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_timeout(&mut ctx, token)
+    }
+    fn handle_shutdown(&mut self) {
+        self.app.handle_shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    type Log = Rc<RefCell<Vec<(u32, usize)>>>;
+
+    const BULK: u32 = 1;
+    // Logged when the sink starts reading "bulk"
+    const READING: (u32, usize) = (0, 0);
+
+    // Accepts everything except "forbidden", but leaves "bulk" unread
+    // until a timeout goes off
+    struct Sink {
+        log: Log,
+        closed: Rc<RefCell<Vec<u32>>>,
+        conn: usize,
+        unread: usize,
+        reading: bool,
+    }
+
+    impl ChannelApp for Sink {
+        fn handle_channel_open(
+            &mut self,
+            ctx: &mut ChannelContext,
+            id: usize,
+            channel: u32,
+            label: Bytes,
+        ) -> bool {
+            if channel == BULK {
+                self.conn = id;
+                ctx.set_timeout(0, Duration::from_millis(100));
+            }
+            label != "forbidden"
+        }
+        fn handle_channel_data(
+            &mut self,
+            ctx: &mut ChannelContext,
+            id: usize,
+            channel: u32,
+            data: Bytes,
+        ) {
+            self.log.borrow_mut().push((channel, data.len()));
+            if channel == BULK && !self.reading {
+                self.unread += data.len();
+            } else {
+                ctx.consume(id, channel, data.len());
+            }
+        }
+        fn handle_timeout(&mut self, ctx: &mut ChannelContext, _token: usize) {
+            self.log.borrow_mut().push(READING);
+            self.reading = true;
+            ctx.consume(self.conn, BULK, self.unread);
+        }
+        fn handle_channel_close(&mut self, _ctx: &mut ChannelContext, _id: usize, channel: u32) {
+            self.closed.borrow_mut().push(channel);
+        }
+    }
+
+    struct Source {
+        opened: Rc<RefCell<Vec<(u32, bool)>>>,
+    }
+
+    impl ChannelApp for Source {
+        fn handle_connect(&mut self, ctx: &mut ChannelContext, id: usize) {
+            let bulk = ctx.open(id, "bulk");
+            ctx.send(id, bulk, vec![0; 200 * 1024]).unwrap();
+            ctx.close(id, bulk);
+            let chat = ctx.open(id, "chat");
+            ctx.send(id, chat, "hi").unwrap();
+            ctx.open(id, "forbidden");
+        }
+        fn handle_channel_opened(
+            &mut self,
+            _ctx: &mut ChannelContext,
+            _id: usize,
+            channel: u32,
+            accepted: bool,
+        ) {
+            self.opened.borrow_mut().push((channel, accepted));
+        }
+    }
+
+    #[test]
+    fn windows_keep_channels_independent() {
+        let addr = "127.0.0.1:13293";
+        let log = Rc::new(RefCell::new(vec![]));
+        let closed = Rc::new(RefCell::new(vec![]));
+        let opened = Rc::new(RefCell::new(vec![]));
+
+        let sink = Sink {
+            log: log.clone(),
+            closed: closed.clone(),
+            conn: 0,
+            unread: 0,
+            reading: false,
+        };
+        let mut server = Core::new(Channels::new(sink).window(16 * 1024));
+        server.listen(addr).unwrap();
+        let mut client = new_channels(Source {
+            opened: opened.clone(),
+        });
+        client.connect(addr).unwrap();
+
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while closed.borrow().is_empty() && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+
+        let log = log.borrow();
+        let (bulk, chat) = (BULK, 3);
+        let total = |log: &[(u32, usize)]| -> usize {
+            let bulk = log.iter().filter(|(c, _)| *c == bulk);
+            bulk.map(|(_, n)| n).sum()
+        };
+        assert_eq!(total(&log), 200 * 1024);
+        assert!(log.iter().all(|(_, len)| *len <= 16 * 1024));
+        // Unread, "bulk" stopped at one window, and "chat" got through anyway
+        let reading = log.iter().position(|entry| *entry == READING).unwrap();
+        assert_eq!(total(&log[..reading]), 16 * 1024);
+        assert!(log[..reading].contains(&(chat, 2)));
+        assert_eq!(*closed.borrow(), vec![bulk]);
+        let mut opened = opened.borrow().clone();
+        opened.sort();
+        assert_eq!(opened, vec![(bulk, true), (chat, true), (5, false)]);
+    }
+}
@@ -0,0 +1,161 @@
+use bytes::Bytes;
+use rand::Rng;
+
+use crate::SocketOptions;
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_millis(100), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            jitter: 0.5,
+        }
+    }
+
+    // Fraction of each delay that is randomized, 0.0 disables jitter
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    // attempt 1 is the first redial after a close
+    pub fn delay<R: Rng>(&self, attempt: u32, rng: &mut R) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31);
+        let base = self
+            .initial
+            .checked_mul(1 << exp)
+            .map_or(self.max, |d| d.min(self.max));
+        if self.jitter <= 0.0 {
+            return base;
+        }
+        let scale = 1.0 - self.jitter * rng.gen::<f64>();
+        let nanos = base.as_secs() as f64 * 1e9 + f64::from(base.subsec_nanos());
+        Duration::from_nanos((nanos * scale) as u64)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    reconnect: Option<Backoff>,
+    queue_limit: usize,
+    socket: Option<SocketOptions>,
+}
+
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Redial after every close, keeping the same connection id
+    pub fn persistent(mut self, backoff: Backoff) -> Self {
+        self.reconnect = Some(backoff);
+        self
+    }
+
+    // Hold up to `limit` frames written while a persistent peer is down,
+    // and replay them once it reconnects
+    pub fn queue_while_down(mut self, limit: usize) -> Self {
+        self.queue_limit = limit;
+        self
+    }
+
+    // Instead of the Core's, see Core::set_socket_options
+    pub fn socket(mut self, options: SocketOptions) -> Self {
+        self.socket = Some(options);
+        self
+    }
+
+    pub(crate) fn socket_options(&self) -> Option<&SocketOptions> {
+        self.socket.as_ref()
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.reconnect.is_some()
+    }
+}
+
+pub(crate) struct Peer {
+    // What was passed to connect, names are looked up again on each redial
+    pub host: String,
+    pub socket: SocketOptions,
+    pub backoff: Backoff,
+    pub attempt: u32,
+    pub established: bool,
+    queue_limit: usize,
+    queue: VecDeque<Bytes>,
+}
+
+impl Peer {
+    pub fn new(host: String, socket: SocketOptions, options: &ConnectOptions) -> Option<Self> {
+        let backoff = options.reconnect.clone()?;
+        Some(Peer {
+            host,
+            socket,
+            backoff,
+            attempt: 0,
+            established: false,
+            queue_limit: options.queue_limit,
+            queue: VecDeque::new(),
+        })
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        self.attempt += 1;
+        self.established = false;
+        self.backoff.delay(self.attempt, &mut rand::thread_rng())
+    }
+
+    pub fn enqueue(&mut self, buf: Bytes) {
+        // XXX Dropping newest frames when full; maybe make this configurable
+        if self.queue.len() < self.queue_limit {
+            self.queue.push_back(buf);
+        }
+    }
+
+    pub fn drain_queue(&mut self) -> impl Iterator<Item = Bytes> + '_ {
+        self.queue.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).jitter(0.0);
+        let mut rng = rand::thread_rng();
+        assert_eq!(backoff.delay(1, &mut rng), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2, &mut rng), Duration::from_millis(200));
+        assert_eq!(backoff.delay(4, &mut rng), Duration::from_millis(800));
+        assert_eq!(backoff.delay(5, &mut rng), Duration::from_secs(1));
+        assert_eq!(backoff.delay(100, &mut rng), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_in_range() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let delay = backoff.delay(3, &mut rng);
+            assert!(delay <= Duration::from_millis(400));
+            assert!(delay >= Duration::from_millis(200));
+        }
+    }
+}
@@ -1,20 +1,49 @@
 use mio::net::{TcpListener, TcpStream};
+use mio::unix::{EventedFd, UnixReady};
 use mio::{Evented, Events, Poll, PollOpt, Ready, Token};
 use mio_extras::channel::{channel, Receiver, Sender};
+use mio_extras::timer::{self, Timeout, Timer};
 
-use bytes::{Buf, IntoBuf};
+use bytes::{Buf, Bytes, IntoBuf};
 use slab::Slab;
 
-use failure::Error;
+use failure::{bail, Error};
 
 use std::collections::HashMap;
 use std::io;
 use std::io::Result as IOResult;
+use std::net::SocketAddr;
+use std::os::unix::io::{OwnedFd, RawFd};
+use std::sync::{Mutex, Weak};
+use std::time::Duration;
 
-use crate::{App, FramedStream};
+use crate::connect::Peer;
+use crate::dial::{check_host, interleave, Dial, Resolver, ATTEMPT_DELAY};
+use crate::framed_stream::{into_bytes, WriteHalf};
+use crate::{App, ConnectOptions, FramedStream, SocketOptions};
 
-enum ControlMsg {
-    WriteFrame(usize, Box<Buf + Send>),
+pub(crate) enum ControlMsg {
+    WriteFrame(usize, Bytes),
+    SetTimeout(usize, Duration),
+    CancelTimeout(usize),
+    WatchFd(usize, RawFd, Ready),
+    UnwatchFd(usize),
+    // Closed only after it's been unwatched
+    CloseFd(usize, OwnedFd),
+    // One payload for every connection on a worker, except maybe one
+    Broadcast(Bytes, Option<usize>),
+    Multicast(Vec<usize>, Bytes),
+    // A WriteHandle queued frames on an idle connection
+    StartWriting(usize),
+    // Per connection write tuning from the Context
+    Flush(usize),
+    SetNodelay(usize, bool),
+    SetCork(usize, Option<Duration>),
+    // A connection accepted by a MultiCore acceptor, with its listen id
+    Adopt(TcpStream, SocketAddr, usize),
+    // A host name lookup for the dial at this slab index and generation finished
+    Resolved(usize, u64, IOResult<Vec<SocketAddr>>),
+    Shutdown,
     /*
     Connect,
     Listen,
@@ -24,18 +53,43 @@ enum ControlMsg {
 }
 
 enum Socket {
-    Listen(TcpListener),
+    // Accepted streams get the listener's options
+    Listen(TcpListener, SocketOptions),
     Stream(FramedStream),
     Control(Receiver<ControlMsg>),
+    Timer(Timer<TimerEvent>),
+    // Some other file descriptor the app wants readiness for, with its token
+    Fd(usize, RawFd),
+    // Placeholder keeping the id of a persistent peer while it is down
+    Idle,
+    // Keeps the id of a connection to a host name, or of a redialing
+    // persistent peer, until an attempt wins
+    Dialing(Dial),
+    // One address being tried for the Dialing connection at that index
+    Attempt(usize, TcpStream, SocketAddr),
+}
+
+enum TimerEvent {
+    Redial(usize),
+    App(usize),
+    // The cork window on a stream is up
+    Flush(usize),
+    // Time for the Dialing connection at this index and generation
+    // to try its next address too
+    Attempt(usize, u64),
 }
 
 impl Evented for Socket {
     fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> IOResult<()> {
         use Socket::*;
         match self {
-            Listen(conn) => conn.register(poll, token, interest, opts),
+            Listen(conn, _) => conn.register(poll, token, interest, opts),
             Stream(conn) => conn.register(poll, token, interest, opts),
             Control(conn) => conn.register(poll, token, interest, opts),
+            Timer(timer) => timer.register(poll, token, interest, opts),
+            Fd(_, fd) => EventedFd(fd).register(poll, token, interest, opts),
+            Attempt(_, stream, _) => stream.register(poll, token, interest, opts),
+            Idle | Dialing(_) => Ok(()),
         }
     }
     fn reregister(
@@ -47,34 +101,41 @@ impl Evented for Socket {
     ) -> IOResult<()> {
         use Socket::*;
         match self {
-            Listen(conn) => conn.reregister(poll, token, interest, opts),
+            Listen(conn, _) => conn.reregister(poll, token, interest, opts),
             Stream(conn) => conn.reregister(poll, token, interest, opts),
             Control(conn) => conn.reregister(poll, token, interest, opts),
+            Timer(timer) => timer.reregister(poll, token, interest, opts),
+            Fd(_, fd) => EventedFd(fd).reregister(poll, token, interest, opts),
+            Attempt(_, stream, _) => stream.reregister(poll, token, interest, opts),
+            Idle | Dialing(_) => Ok(()),
         }
     }
     fn deregister(&self, poll: &Poll) -> IOResult<()> {
         use Socket::*;
         match self {
-            Listen(conn) => conn.deregister(poll),
+            Listen(conn, _) => conn.deregister(poll),
             Stream(conn) => conn.deregister(poll),
             Control(conn) => conn.deregister(poll),
+            Timer(timer) => timer.deregister(poll),
+            Fd(_, fd) => EventedFd(fd).deregister(poll),
+            Attempt(_, stream, _) => stream.deregister(poll),
+            Idle | Dialing(_) => Ok(()),
         }
     }
 }
 
 impl Socket {
-    pub fn framed_stream(stream: TcpStream) -> Self {
-        let stream = FramedStream::new(stream);
-        Socket::Stream(stream)
-    }
     pub fn register_and_save(self, poll: &mut Poll, slab: &mut Slab<Self>) -> IOResult<usize> {
+        self.register_and_save_with(Ready::readable(), poll, slab)
+    }
+    pub fn register_and_save_with(
+        self,
+        interest: Ready,
+        poll: &mut Poll,
+        slab: &mut Slab<Self>,
+    ) -> IOResult<usize> {
         let entry = slab.vacant_entry();
-        poll.register(
-            &self,
-            Token(entry.key()),
-            Ready::readable(),
-            PollOpt::edge(),
-        )?;
+        poll.register(&self, Token(entry.key()), interest, PollOpt::edge())?;
         let key = entry.key();
         entry.insert(self);
         Ok(key)
@@ -85,65 +146,457 @@ impl Socket {
 pub struct Core<A: App> {
     // XXX TODO Maybe someday for optimization
     // XXX TODO Move slab, poll into Context/Inner
-    // XXX TODO split slab into read/write pair (Slab/Map?)<Arc<Mutex<Socket>>>
-    //          only write half goes in Context/Inner?
+    // XXX TODO Streams share their write half with WriteHandles,
+    //          maybe Context should write through it too?
     app: A,
     slab: Slab<Socket>,
     ctx: Context,
     poll: Poll,
     events: Events,
-    control_tx: Sender<ControlMsg>,
+    timer: usize,
+    timeouts: HashMap<usize, Timeout>,
+    peers: HashMap<usize, Peer>,
+    // App token to slab index for watched fds
+    fds: HashMap<usize, usize>,
+    shutdown: bool,
+    write_immediately: bool,
+    // For listen and connect calls that don't bring their own
+    socket: SocketOptions,
+    cork: Option<Duration>,
+    // Started by the first connect to a host name
+    resolver: Option<Resolver>,
+    dial_generation: u64,
 }
 
 impl<A: App> Core<A> {
     pub fn new(app: A) -> Self {
+        let (control_tx, control_rx) = channel();
+        let workers = vec![control_tx.clone()];
+        Self::worker(app, control_tx, control_rx, 0, workers)
+    }
+
+    // One of several Cores sharing an id space, see MultiCore
+    pub(crate) fn worker(
+        app: A,
+        control_tx: Sender<ControlMsg>,
+        control_rx: Receiver<ControlMsg>,
+        worker: usize,
+        workers: Vec<Sender<ControlMsg>>,
+    ) -> Self {
         let mut slab: Slab<Socket> = Slab::new();
         let mut poll = Poll::new().unwrap();
-        let (control_tx, control_rx) = channel();
-        let ctx = Context::new(control_tx.clone());
+        let ctx = Context::new(control_tx, worker, workers);
         let _ = Socket::Control(control_rx).register_and_save(&mut poll, &mut slab);
+        // The default 100ms tick is too coarse for short timeouts
+        let timer = timer::Builder::default()
+            .tick_duration(Duration::from_millis(5))
+            .build();
+        let timer = Socket::Timer(timer)
+            .register_and_save(&mut poll, &mut slab)
+            .unwrap();
         let events = Events::with_capacity(1024);
-        Self {
+        let mut core = Self {
             app,
             slab,
             ctx,
             poll,
             events,
-            control_tx,
+            timer,
+            timeouts: HashMap::new(),
+            peers: HashMap::new(),
+            fds: HashMap::new(),
+            shutdown: false,
+            write_immediately: true,
+            socket: SocketOptions::new(),
+            cork: None,
+            resolver: None,
+            dial_generation: 0,
+        };
+        core.app.handle_init(&core.ctx);
+        core
+    }
+
+    // Ids handed to the app are slab indexes interleaved across workers,
+    // so any worker can tell which one owns a connection
+    fn id(&self, idx: usize) -> usize {
+        idx * self.ctx.workers.len() + self.ctx.worker
+    }
+
+    fn idx(&self, id: usize) -> Option<usize> {
+        let workers = self.ctx.workers.len();
+        if id % workers == self.ctx.worker {
+            Some(id / workers)
+        } else {
+            None
         }
     }
 
+    // See FramedStream::set_write_immediately, applies to streams opened after this
+    pub fn set_write_immediately(&mut self, on: bool) {
+        self.write_immediately = on;
+    }
+
+    // Used by listen, connect and connect_with unless ConnectOptions has its own
+    pub fn set_socket_options(&mut self, options: SocketOptions) {
+        self.socket = options;
+    }
+
+    // TCP_NODELAY for streams opened after this, see Context::set_nodelay
+    pub fn set_nodelay(&mut self, on: bool) {
+        self.socket = self.socket.clone().nodelay(on);
+    }
+
+    // Cork streams opened after this, see Context::set_cork
+    pub fn set_cork(&mut self, window: Option<Duration>) {
+        self.cork = window;
+    }
+
+    fn framed(&self, stream: TcpStream) -> Socket {
+        let mut stream = FramedStream::new(stream);
+        stream.set_write_immediately(self.write_immediately);
+        stream.set_cork(self.cork);
+        Socket::Stream(stream)
+    }
+
     // XXX TODO move to Context/Inner
     pub fn listen(&mut self, addr: &str) -> Result<usize, Error> {
+        self.listen_with(addr, self.socket.clone())
+    }
+
+    pub fn listen_with(&mut self, addr: &str, options: SocketOptions) -> Result<usize, Error> {
         let addr = addr.parse()?;
-        let server = Socket::Listen(TcpListener::bind(&addr)?);
-        let id = server.register_and_save(&mut self.poll, &mut self.slab)?;
-        self.ctx.listening(id);
-        self.app.handle_listen(&self.ctx, id);
+        let listener = options.listener(addr)?;
+        let local_addr = listener.local_addr()?;
+        let server = Socket::Listen(listener, options);
+        let idx = server.register_and_save(&mut self.poll, &mut self.slab)?;
+        let id = self.id(idx);
+        self.listened(id, local_addr);
         Ok(id)
     }
 
+    pub(crate) fn listened(&mut self, id: usize, local_addr: SocketAddr) {
+        self.ctx.listening(id, local_addr);
+        self.app.handle_listen(&self.ctx, id);
+    }
+
     // XXX TODO move to Context/Inner
     pub fn connect(&mut self, addr: &str) -> Result<usize, Error> {
-        let addr = addr.parse()?;
-        let server = Socket::framed_stream(TcpStream::connect(&addr)?);
-        let id = server.register_and_save(&mut self.poll, &mut self.slab)?;
-        self.ctx.connected(id);
-        self.app.handle_connect(&self.ctx, id);
+        self.connect_with(addr, ConnectOptions::new())
+    }
+
+    // `addr` is a socket address or host:port. Names are looked up off the
+    // loop and then tried address by address, see Dial; handle_connect
+    // waits for the first attempt, and failing them all closes the id.
+    // A lookup that fails, or finds nothing to try, ends in
+    // App::handle_connect_error instead.
+    pub fn connect_with(&mut self, addr: &str, options: ConnectOptions) -> Result<usize, Error> {
+        let literal = addr.parse::<SocketAddr>();
+        if literal.is_err() && !check_host(addr) {
+            bail!("Expected host:port, got {}", addr);
+        }
+        let socket = options.socket_options().unwrap_or(&self.socket).clone();
+        let server = match literal {
+            Ok(sockaddr) => self.framed(socket.connect(sockaddr)?),
+            Err(_) => Socket::Dialing(self.new_dial(socket.clone())),
+        };
+        // Writable interest tells us when the connection is established
+        let idx = server.register_and_save_with(
+            Ready::readable() | Ready::writable(),
+            &mut self.poll,
+            &mut self.slab,
+        )?;
+        let id = self.id(idx);
+        if let Some(peer) = Peer::new(addr.to_string(), socket, &options) {
+            self.peers.insert(idx, peer);
+            self.ctx.reconnecting(id, 0);
+        }
+        match literal {
+            Ok(sockaddr) => {
+                self.ctx.connected(id, sockaddr);
+                self.app.handle_connect(&self.ctx, id);
+            }
+            Err(_) => {
+                if let Err(e) = self.resolve(idx, addr.to_string()) {
+                    self.peers.remove(&idx);
+                    self.ctx.reconnects.remove(&id);
+                    self.slab.remove(idx);
+                    return Err(e.into());
+                }
+            }
+        }
         Ok(id)
     }
 
+    fn new_dial(&mut self, options: SocketOptions) -> Dial {
+        self.dial_generation += 1;
+        Dial::new(self.dial_generation, options)
+    }
+
+    // The Dialing connection at `idx`, if it's still the one from `generation`
+    fn dial(&mut self, idx: usize, generation: u64) -> Option<&mut Dial> {
+        match self.slab.get_mut(idx) {
+            Some(Socket::Dialing(dial)) if dial.generation == generation => Some(dial),
+            _ => None,
+        }
+    }
+
+    fn resolve(&mut self, idx: usize, host: String) -> IOResult<()> {
+        if self.resolver.is_none() {
+            self.resolver = Some(Resolver::new(self.ctx.sender.clone())?);
+        }
+        let generation = match self.slab.get(idx) {
+            Some(Socket::Dialing(dial)) => dial.generation,
+            _ => return Ok(()),
+        };
+        if let Some(resolver) = &self.resolver {
+            resolver.resolve(idx, generation, host);
+        }
+        Ok(())
+    }
+
+    fn resolved(&mut self, idx: usize, generation: u64, addrs: IOResult<Vec<SocketAddr>>) {
+        match (self.dial(idx, generation), addrs) {
+            (Some(dial), Ok(addrs)) => dial.remaining = interleave(addrs),
+            (Some(dial), Err(e)) => dial.error = Some(e),
+            (None, _) => return,
+        }
+        self.next_attempt(idx);
+    }
+
+    // Starts connecting to the next address of a Dialing connection,
+    // giving up once none are left and no attempt is still going
+    fn next_attempt(&mut self, idx: usize) {
+        loop {
+            let (addr, options, idle, generation) = match self.slab.get_mut(idx) {
+                Some(Socket::Dialing(dial)) => (
+                    dial.remaining.pop_front(),
+                    dial.options.clone(),
+                    dial.attempts.is_empty(),
+                    dial.generation,
+                ),
+                _ => return,
+            };
+            let addr = match addr {
+                Some(addr) => addr,
+                None if idle => return self.disconnected(idx),
+                None => return,
+            };
+            let attempt = options.connect(addr).and_then(|stream| {
+                Socket::Attempt(idx, stream, addr).register_and_save_with(
+                    Ready::readable() | Ready::writable(),
+                    &mut self.poll,
+                    &mut self.slab,
+                )
+            });
+            let attempt = match attempt {
+                Ok(attempt) => attempt,
+                Err(e) => {
+                    if let Some(Socket::Dialing(dial)) = self.slab.get_mut(idx) {
+                        dial.error = Some(e);
+                    }
+                    continue;
+                }
+            };
+            let mut announce = false;
+            if let Some(Socket::Dialing(dial)) = self.slab.get_mut(idx) {
+                dial.attempts.push(attempt);
+                if !dial.redial {
+                    announce = dial.announced.is_none();
+                    dial.announced.get_or_insert(addr);
+                }
+            }
+            if let Some(Socket::Timer(timer)) = self.slab.get_mut(self.timer) {
+                timer.set_timeout(ATTEMPT_DELAY, TimerEvent::Attempt(idx, generation));
+            }
+            if announce {
+                let id = self.id(idx);
+                self.ctx.connected(id, addr);
+                self.app.handle_connect(&self.ctx, id);
+            }
+            return;
+        }
+    }
+
+    fn drop_attempt(&mut self, idx: usize, attempt: usize) -> Option<(TcpStream, SocketAddr)> {
+        if let Some(Socket::Dialing(dial)) = self.slab.get_mut(idx) {
+            dial.attempts.retain(|other| *other != attempt);
+        }
+        if !self.slab.contains(attempt) {
+            return None;
+        }
+        let socket = self.slab.remove(attempt);
+        let _ = self.poll.deregister(&socket);
+        match socket {
+            Socket::Attempt(_, stream, addr) => Some((stream, addr)),
+            _ => None,
+        }
+    }
+
+    // The first attempt to connect wins, the rest are closed
+    fn attempt_connected(&mut self, idx: usize, attempt: usize) {
+        let (stream, addr) = match self.drop_attempt(idx, attempt) {
+            Some(connected) => connected,
+            None => return,
+        };
+        let mut dial = match std::mem::replace(&mut self.slab[idx], Socket::Idle) {
+            Socket::Dialing(dial) => dial,
+            other => {
+                self.slab[idx] = other;
+                return;
+            }
+        };
+        for other in dial.attempts.drain(..) {
+            let _ = self.poll.deregister(&self.slab.remove(other));
+        }
+        let socket = self.framed(stream);
+        let interest = Ready::readable() | Ready::writable();
+        if let Err(e) = self
+            .poll
+            .register(&socket, Token(idx), interest, PollOpt::edge())
+        {
+            dial.error = Some(e);
+            self.slab[idx] = Socket::Dialing(dial);
+            return self.disconnected(idx);
+        }
+        self.slab[idx] = socket;
+        let id = self.id(idx);
+        if dial.announced != Some(addr) {
+            self.ctx.connected(id, addr);
+        }
+        self.replay(idx, dial.queue.into_iter().collect());
+        if dial.redial {
+            self.app.handle_connect(&self.ctx, id);
+        }
+    }
+
+    // Frames stay queued on the Peer, and the app hears nothing,
+    // until an attempt connects
+    fn redial(&mut self, idx: usize) {
+        let (host, options) = match self.peers.get(&idx) {
+            Some(peer) => (peer.host.clone(), peer.socket.clone()),
+            None => return,
+        };
+        let mut dial = self.new_dial(options);
+        dial.redial = true;
+        match host.parse() {
+            Ok(addr) => {
+                dial.remaining.push_back(addr);
+                self.slab[idx] = Socket::Dialing(dial);
+                self.next_attempt(idx);
+            }
+            Err(_) => {
+                self.slab[idx] = Socket::Dialing(dial);
+                if self.resolve(idx, host).is_err() {
+                    self.slab[idx] = Socket::Idle;
+                    self.schedule_redial(idx);
+                }
+            }
+        }
+    }
+
+    // Queues frames written while there was no stream, oldest first
+    fn replay(&mut self, idx: usize, mut payloads: Vec<Bytes>) {
+        if let Some(peer) = self.peers.get_mut(&idx) {
+            payloads.splice(0..0, peer.drain_queue());
+        }
+        if let Some(Socket::Stream(stream)) = self.slab.get_mut(idx) {
+            let mut corked = false;
+            for payload in payloads {
+                let queued = stream.queue_bytes(payload, &mut self.poll, Token(idx));
+                corked |= queued.unwrap_or(false);
+            }
+            if corked {
+                self.uncork_later(idx);
+            }
+        }
+    }
+
+    // Tells the app the connection is gone, or that it never came up if
+    // handle_connect wasn't called yet. Persistent peers redial later.
+    fn disconnected(&mut self, idx: usize) {
+        let id = self.id(idx);
+        let announced = self.ctx.closed(id).is_some();
+        let error = match std::mem::replace(&mut self.slab[idx], Socket::Idle) {
+            Socket::Dialing(dial) => {
+                // Kept for the redial, like frames written while Idle
+                if let Some(peer) = self.peers.get_mut(&idx) {
+                    for payload in dial.queue {
+                        peer.enqueue(payload);
+                    }
+                }
+                dial.error
+            }
+            _ => None,
+        };
+        let error = error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address"));
+        if self.peers.contains_key(&idx) {
+            self.schedule_redial(idx);
+        }
+        if announced {
+            self.app.handle_close(&self.ctx, id);
+        } else {
+            self.app.handle_connect_error(&self.ctx, id, error);
+        }
+        if !self.peers.contains_key(&idx) {
+            self.slab.remove(idx);
+        }
+    }
+
+    // A failed listener gets handle_close, there was no dial to report on
+    fn stopped_listening(&mut self, idx: usize) {
+        let id = self.id(idx);
+        self.ctx.unlisten(id);
+        self.app.handle_close(&self.ctx, id);
+        self.slab.remove(idx);
+    }
+
+    fn schedule_redial(&mut self, idx: usize) {
+        let id = self.id(idx);
+        if let Some(peer) = self.peers.get_mut(&idx) {
+            let delay = peer.next_delay();
+            self.ctx.reconnecting(id, peer.attempt);
+            if let Some(Socket::Timer(timer)) = self.slab.get_mut(self.timer) {
+                timer.set_timeout(delay, TimerEvent::Redial(idx));
+            }
+        }
+    }
+
     // XXX TODO move to Context/Inner
-    pub fn write_frame<B: Buf + Send + 'static>(&mut self, idx: usize, buf: B) {
+    pub fn write_frame<B: Buf + Send + 'static>(&mut self, id: usize, buf: B) {
+        self.write_bytes(id, into_bytes(buf))
+    }
+
+    fn write_bytes(&mut self, id: usize, payload: Bytes) {
+        let idx = match self.idx(id) {
+            Some(idx) => idx,
+            None => {
+                let _ = self
+                    .ctx
+                    .sender_for(id)
+                    .send(ControlMsg::WriteFrame(id, payload));
+                return;
+            }
+        };
         match self.slab.get_mut(idx) {
-            Some(Socket::Listen(_)) => {
+            Some(Socket::Listen(..)) => {
                 // Should return error
             }
             Some(Socket::Stream(stream)) => {
                 // Should return error
-                let _ = stream.queue_write(buf, &mut self.poll, Token(idx));
+                if let Ok(true) = stream.queue_bytes(payload, &mut self.poll, Token(idx)) {
+                    self.uncork_later(idx);
+                }
             }
-            Some(Socket::Control(_)) => {
+            Some(Socket::Idle) => {
+                if let Some(peer) = self.peers.get_mut(&idx) {
+                    peer.enqueue(payload);
+                }
+            }
+            Some(Socket::Dialing(dial)) => dial.queue.push_back(payload),
+            Some(Socket::Control(_))
+            | Some(Socket::Timer(_))
+            | Some(Socket::Fd(..))
+            | Some(Socket::Attempt(..)) => {
                 // Should return error
             }
             None => {
@@ -152,183 +605,886 @@ impl<A: App> Core<A> {
         }
     }
 
+    // Every frame shares the one payload
+    fn broadcast(&mut self, payload: Bytes, except: Option<usize>) {
+        let (workers, worker) = (self.ctx.workers.len(), self.ctx.worker);
+        let mut corked = vec![];
+        for (idx, socket) in self.slab.iter_mut() {
+            if let Socket::Stream(stream) = socket {
+                if except != Some(idx * workers + worker) {
+                    let queued = stream.queue_bytes(payload.clone(), &mut self.poll, Token(idx));
+                    if let Ok(true) = queued {
+                        corked.push(idx);
+                    }
+                }
+            }
+        }
+        for idx in corked {
+            self.uncork_later(idx);
+        }
+    }
+
+    // Writes out frames held back by a cork right away
+    pub fn flush(&mut self, id: usize) {
+        let idx = match self.idx(id) {
+            Some(idx) => idx,
+            None => {
+                let _ = self.ctx.sender_for(id).send(ControlMsg::Flush(id));
+                return;
+            }
+        };
+        if let Some(Socket::Stream(stream)) = self.slab.get_mut(idx) {
+            let _ = stream.flush(&mut self.poll, Token(idx));
+        }
+    }
+
+    // Flushes a corked stream once its window is up. Timer ticks are 5ms,
+    // so shorter windows are rounded up to that.
+    fn uncork_later(&mut self, idx: usize) {
+        let window = match self.slab.get(idx) {
+            Some(Socket::Stream(stream)) => stream.cork(),
+            _ => None,
+        };
+        if let (Some(window), Some(Socket::Timer(timer))) = (window, self.slab.get_mut(self.timer))
+        {
+            timer.set_timeout(window, TimerEvent::Flush(idx));
+        }
+    }
+
     pub fn close(&mut self, _idx: usize) {
         unimplemented!()
     }
 
     pub fn run(&mut self) -> IOResult<()> {
         loop {
-            self.poll.poll(&mut self.events, None)?;
-            for event in self.events.iter() {
-                let Token(idx) = event.token();
-                let retain: bool = match self.slab.get_mut(idx) {
-                    Some(Socket::Listen(server)) => match server.accept() {
-                        Ok((stream, _client_addr)) => {
-                            let conn_id = Socket::framed_stream(stream)
-                                .register_and_save(&mut self.poll, &mut self.slab)
-                                .expect("Register Stream");
-                            self.ctx.accepted(conn_id);
-                            self.app.handle_accept(&self.ctx, idx, conn_id);
-                            true
-                        }
-                        Err(_e) => {
-                            // XXX app.handle_accept_error(&self.ctx, idx, e.into());
-                            false
+            self.turn(None)?;
+        }
+    }
+
+    // Set once a Shutdown control message arrived
+    pub(crate) fn is_shutdown(&self) -> bool {
+        self.shutdown
+    }
+
+    pub(crate) fn shutdown(&mut self) {
+        self.app.handle_shutdown();
+    }
+
+    pub fn turn(&mut self, timeout: Option<Duration>) -> IOResult<()> {
+        self.poll.poll(&mut self.events, timeout)?;
+        let events: Vec<(usize, Ready)> = self
+            .events
+            .iter()
+            .map(|event| (event.token().0, event.readiness()))
+            .collect();
+        for (idx, readiness) in events {
+            self.handle_event(idx, readiness);
+        }
+        Ok(())
+    }
+
+    fn handle_event(&mut self, idx: usize, readiness: Ready) {
+        let retain: bool = match self.slab.get_mut(idx) {
+            // Edge triggered, so accept everything that is pending
+            Some(Socket::Listen(..)) => loop {
+                let accepted = match self.slab.get_mut(idx) {
+                    Some(Socket::Listen(server, options)) => server.accept().inspect(|accepted| {
+                        // XXX Options that fail to apply are ignored
+                        let _ = options.configure(&accepted.0);
+                    }),
+                    _ => break true,
+                };
+                match accepted {
+                    Ok((stream, client_addr)) => {
+                        let listen_id = self.id(idx);
+                        self.adopt(stream, client_addr, listen_id);
+                    }
+                    Err(e) => match e.kind() {
+                        io::ErrorKind::WouldBlock => break true,
+                        // Only that connection failed, the rest are still pending
+                        io::ErrorKind::Interrupted
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::ConnectionReset => continue,
+                        // Not listening anymore, nothing more will come
+                        io::ErrorKind::InvalidInput => {
+                            self.stopped_listening(idx);
+                            break true;
                         }
+                        // Out of descriptors or memory, try again on the next
+                        // connection rather than spinning on the error
+                        // XXX app.handle_accept_error(&self.ctx, idx, e.into());
+                        _ => break true,
                     },
-                    Some(Socket::Stream(stream)) => {
-                        let mut retain = true;
-                        if event.readiness().is_readable() {
-                            let (frames, rv) = stream.read_frames();
-                            if frames.len() > 0 {
-                                self.app.handle_frames(&self.ctx, idx, frames);
-                            }
-                            if let Some(err) = rv {
-                                if err.kind() != io::ErrorKind::UnexpectedEof {
-                                    // XXX app.handle_read_err(&self.ctx, idx, err.into());
-                                }
-                                retain = false;
-                            };
+                }
+            },
+            Some(Socket::Stream(stream)) => {
+                let mut retain = true;
+                let unix_readiness = UnixReady::from(readiness);
+                // Errors and hangups surface through the next read
+                if readiness.is_readable() || unix_readiness.is_error() || unix_readiness.is_hup() {
+                    let (frames, rv) = stream.read_frames();
+                    if !frames.is_empty() {
+                        let id = idx * self.ctx.workers.len() + self.ctx.worker;
+                        self.app.handle_frames(&self.ctx, id, frames);
+                    }
+                    if let Some(err) = rv {
+                        if err.kind() != io::ErrorKind::UnexpectedEof {
+                            // XXX app.handle_read_err(&self.ctx, idx, err.into());
+                        }
+                        retain = false;
+                    };
+                }
+                if retain && readiness.is_writable() {
+                    let pre = stream.interest();
+                    match stream.handle_write() {
+                        Ok(_n) => {}
+                        Err(_err) => {
+                            retain = false;
+                        }
+                    }
+                    if pre != stream.interest() {
+                        let _ = self.poll.reregister(
+                            stream,
+                            Token(idx),
+                            stream.interest(),
+                            PollOpt::edge(),
+                        );
+                    }
+                }
+                if retain {
+                    if let Some(peer) = self.peers.get_mut(&idx) {
+                        if !peer.established {
+                            peer.established = true;
+                            peer.attempt = 0;
+                            let id = idx * self.ctx.workers.len() + self.ctx.worker;
+                            self.ctx.reconnecting(id, 0);
                         }
-                        if event.readiness().is_writable() {
-                            let pre = stream.interest();
-                            match stream.handle_write() {
-                                Ok(_n) => {}
-                                Err(_err) => {
-                                    retain = false;
+                    }
+                }
+                retain
+            }
+            Some(Socket::Control(ctl)) => {
+                let mut messages = vec![];
+                loop {
+                    match ctl.try_recv() {
+                        Ok(msg) => {
+                            messages.push(msg);
+                            //dbg!(msg);
+                            // XXX Do I really need to use a channel
+                            // XXX Can we just handle writes directly?
+                            // XXX The problem is dealing with the Poll
+                            // XXX Maybe just use the control socket to deliver new registrations for newly pending writes
+                            // XXX Maybe I can even optimistically try acquiring the poll
+                            // XXX So we don't need the message passing overhead when not needed
+                        }
+                        Err(e) => {
+                            use std::sync::mpsc::TryRecvError::*;
+                            match e {
+                                Empty => break,
+                                Disconnected => {
+                                    // Should probably do something here??
+                                    break;
                                 }
                             }
-                            if pre != stream.interest() {
-                                let _ = self.poll.reregister(
-                                    stream,
-                                    Token(idx),
-                                    stream.interest(),
-                                    PollOpt::edge(),
-                                );
-                            }
                         }
-                        retain
                     }
-                    Some(Socket::Control(ctl)) => {
-                        let mut messages = vec![];
-                        loop {
-                            match ctl.try_recv() {
-                                Ok(msg) => {
-                                    messages.push(msg);
-                                    //dbg!(msg);
-                                    // XXX Do I really need to use a channel
-                                    // XXX Can we just handle writes directly?
-                                    // XXX The problem is dealing with the Poll
-                                    // XXX Maybe just use the control socket to deliver new registrations for newly pending writes
-                                    // XXX Maybe I can even optimistically try acquiring the poll
-                                    // XXX So we don't need the message passing overhead when not needed
-                                }
-                                Err(e) => {
-                                    use std::sync::mpsc::TryRecvError::*;
-                                    match e {
-                                        Empty => break,
-                                        Disconnected => {
-                                            // Should probably do something here??
-                                            break;
-                                        }
+                }
+                for msg in messages {
+                    match msg {
+                        ControlMsg::WriteFrame(id, payload) => self.write_bytes(id, payload),
+                        ControlMsg::Broadcast(payload, except) => self.broadcast(payload, except),
+                        ControlMsg::Multicast(ids, payload) => {
+                            for id in ids {
+                                self.write_bytes(id, payload.clone());
+                            }
+                        }
+                        ControlMsg::StartWriting(id) => {
+                            if let Some(idx) = self.idx(id) {
+                                if let Some(Socket::Stream(stream)) = self.slab.get_mut(idx) {
+                                    if let Ok(true) =
+                                        stream.start_writing(&mut self.poll, Token(idx))
+                                    {
+                                        self.uncork_later(idx);
                                     }
                                 }
                             }
                         }
-                        for msg in messages {
-                            match msg {
-                                ControlMsg::WriteFrame(idx, buf) => {
-                                    if let Some(Socket::Stream(stream)) = self.slab.get_mut(idx) {
-                                        let _ = stream.queue_write(buf, &mut self.poll, Token(idx));
-                                    }
+                        ControlMsg::Flush(id) => self.flush(id),
+                        ControlMsg::SetNodelay(id, on) => {
+                            if let Some(Socket::Stream(stream)) =
+                                self.idx(id).and_then(|idx| self.slab.get(idx))
+                            {
+                                let _ = stream.set_nodelay(on);
+                            }
+                        }
+                        ControlMsg::SetCork(id, window) => {
+                            if let Some(idx) = self.idx(id) {
+                                if let Some(Socket::Stream(stream)) = self.slab.get_mut(idx) {
+                                    stream.set_cork(window);
                                 }
+                                // Anything already held back goes by the new window
+                                match window {
+                                    Some(_) => self.uncork_later(idx),
+                                    None => self.flush(id),
+                                }
+                            }
+                        }
+                        ControlMsg::SetTimeout(token, delay) => {
+                            self.cancel_timeout(token);
+                            if let Some(Socket::Timer(timer)) = self.slab.get_mut(self.timer) {
+                                let timeout = timer.set_timeout(delay, TimerEvent::App(token));
+                                self.timeouts.insert(token, timeout);
+                            }
+                        }
+                        ControlMsg::CancelTimeout(token) => self.cancel_timeout(token),
+                        ControlMsg::WatchFd(token, fd, interest) => {
+                            // Registering again reports readiness that is already there
+                            self.unwatch_fd(token);
+                            if let Ok(idx) = Socket::Fd(token, fd).register_and_save_with(
+                                interest,
+                                &mut self.poll,
+                                &mut self.slab,
+                            ) {
+                                self.fds.insert(token, idx);
+                            }
+                        }
+                        ControlMsg::UnwatchFd(token) => self.unwatch_fd(token),
+                        ControlMsg::CloseFd(token, fd) => {
+                            self.unwatch_fd(token);
+                            drop(fd);
+                        }
+                        ControlMsg::Adopt(stream, addr, listen_id) => {
+                            self.adopt(stream, addr, listen_id)
+                        }
+                        ControlMsg::Resolved(idx, generation, addrs) => {
+                            self.resolved(idx, generation, addrs)
+                        }
+                        ControlMsg::Shutdown => self.shutdown = true,
+                    }
+                }
+                true
+            }
+            Some(Socket::Timer(timer)) => {
+                let mut timeouts = vec![];
+                while let Some(timeout) = timer.poll() {
+                    timeouts.push(timeout);
+                }
+                for timeout in timeouts {
+                    match timeout {
+                        TimerEvent::Redial(idx) => self.redial(idx),
+                        TimerEvent::Attempt(idx, generation) => {
+                            if self.dial(idx, generation).is_some() {
+                                self.next_attempt(idx);
                             }
                         }
-                        true
+                        TimerEvent::Flush(idx) => {
+                            if let Some(Socket::Stream(stream)) = self.slab.get_mut(idx) {
+                                let _ = stream.flush(&mut self.poll, Token(idx));
+                            }
+                        }
+                        TimerEvent::App(token) => {
+                            self.timeouts.remove(&token);
+                            self.app.handle_timeout(&self.ctx, token);
+                        }
                     }
-                    _ => unreachable!(),
+                }
+                true
+            }
+            Some(Socket::Fd(token, _)) => {
+                let token = *token;
+                self.app.handle_ready(&self.ctx, token, readiness);
+                true
+            }
+            Some(Socket::Attempt(dialing, stream, _)) => {
+                let dialing = *dialing;
+                let unix_readiness = UnixReady::from(readiness);
+                // Connected once writable with no error pending
+                let error = match stream.take_error() {
+                    Ok(None) if unix_readiness.is_error() || unix_readiness.is_hup() => {
+                        Some(io::ErrorKind::NotConnected.into())
+                    }
+                    Ok(None) => None,
+                    Ok(Some(e)) | Err(e) => Some(e),
                 };
-                if !retain {
-                    self.ctx.closed(idx);
-                    self.app.handle_close(&self.ctx, idx);
-                    self.slab.remove(idx);
+                if let Some(error) = error {
+                    self.drop_attempt(dialing, idx);
+                    if let Some(Socket::Dialing(dial)) = self.slab.get_mut(dialing) {
+                        dial.error = Some(error);
+                    }
+                    self.next_attempt(dialing);
+                } else if readiness.is_writable() {
+                    self.attempt_connected(dialing, idx);
                 }
+                true
+            }
+            Some(Socket::Idle) | Some(Socket::Dialing(_)) => true,
+            None => {
+                // Stale event for a socket closed earlier in this batch
+                true
+            }
+        };
+        if !retain {
+            self.disconnected(idx);
+        }
+    }
+
+    fn adopt(&mut self, stream: TcpStream, client_addr: SocketAddr, listen_id: usize) {
+        let conn_idx = self
+            .framed(stream)
+            .register_and_save(&mut self.poll, &mut self.slab)
+            .expect("Register Stream");
+        let conn_id = self.id(conn_idx);
+        self.ctx.accepted(conn_id, client_addr);
+        self.app.handle_accept(&self.ctx, listen_id, conn_id);
+    }
+
+    fn cancel_timeout(&mut self, token: usize) {
+        if let Some(timeout) = self.timeouts.remove(&token) {
+            if let Some(Socket::Timer(timer)) = self.slab.get_mut(self.timer) {
+                timer.cancel_timeout(&timeout);
             }
         }
     }
 
+    fn unwatch_fd(&mut self, token: usize) {
+        if let Some(idx) = self.fds.remove(&token) {
+            // The fd may already be closed, which unregisters it anyway
+            let _ = self.poll.deregister(&self.slab[idx]);
+            self.slab.remove(idx);
+        }
+    }
+
     pub fn write_handle(&self, idx: usize) -> WriteHandle {
-        let sender = self.control_tx.clone();
-        WriteHandle { idx, sender }
+        let sender = self.ctx.sender_for(idx).clone();
+        let half = match self.idx(idx).and_then(|i| self.slab.get(i)) {
+            Some(Socket::Stream(stream)) => stream.write_half(),
+            _ => Weak::new(),
+        };
+        WriteHandle { idx, sender, half }
     }
 }
 
 pub struct Context {
     connections: HashMap<usize, ConnectionDetails>,
     listening: HashMap<usize, ListenDetails>,
+    reconnects: HashMap<usize, u32>,
     sender: Sender<ControlMsg>,
+    // Every worker's control channel, just our own outside a MultiCore
+    workers: Vec<Sender<ControlMsg>>,
+    worker: usize,
 }
 
 impl Context {
-    fn new(sender: Sender<ControlMsg>) -> Self {
+    fn new(sender: Sender<ControlMsg>, worker: usize, workers: Vec<Sender<ControlMsg>>) -> Self {
         let connections = HashMap::new();
         let listening = HashMap::new();
+        let reconnects = HashMap::new();
         Self {
             connections,
             listening,
+            reconnects,
             sender,
+            workers,
+            worker,
         }
     }
-    fn connected(&mut self, id: usize) {
-        self.connections.insert(id, ConnectionDetails::new());
+    fn sender_for(&self, id: usize) -> &Sender<ControlMsg> {
+        &self.workers[id % self.workers.len()]
     }
-    fn accepted(&mut self, id: usize) {
-        self.connections.insert(id, ConnectionDetails::new());
+    fn connected(&mut self, id: usize, peer_addr: SocketAddr) {
+        self.connections
+            .insert(id, ConnectionDetails::new(peer_addr, true));
+    }
+    fn accepted(&mut self, id: usize, peer_addr: SocketAddr) {
+        self.connections
+            .insert(id, ConnectionDetails::new(peer_addr, false));
     }
     fn closed(&mut self, id: usize) -> Option<ConnectionDetails> {
         self.connections.remove(&id)
     }
-    fn listening(&mut self, id: usize) {
-        self.listening.insert(id, ListenDetails::new());
+    fn listening(&mut self, id: usize, local_addr: SocketAddr) {
+        self.listening.insert(id, ListenDetails::new(local_addr));
+    }
+    fn unlisten(&mut self, id: usize) {
+        self.listening.remove(&id);
     }
+    fn reconnecting(&mut self, id: usize, attempt: u32) {
+        self.reconnects.insert(id, attempt);
+    }
+    // Which MultiCore worker this is, always 0 for a plain Core
+    pub fn worker(&self) -> usize {
+        self.worker
+    }
+    // Connections on this worker only
     pub fn connection_ids<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
-        self.connections.keys().map(|id| *id)
+        self.connections.keys().copied()
+    }
+    // Only persistent peers have an attempt counter.
+    // In handle_connect it is the dial that just connected (0 for the first),
+    // in handle_close it is the redial that is about to be scheduled.
+    pub fn reconnect_attempt(&self, id: usize) -> Option<u32> {
+        self.reconnects.get(&id).copied()
+    }
+    pub fn connection(&self, id: usize) -> Option<&ConnectionDetails> {
+        self.connections.get(&id)
+    }
+    pub fn listen_addrs<'a>(&'a self) -> impl Iterator<Item = SocketAddr> + 'a {
+        self.listening.values().map(|details| details.local_addr)
     }
+    // Works for connections on any worker
     pub fn write_frame<B: Buf + Send + 'static>(&self, id: usize, buf: B) {
+        self.sender_for(id)
+            .send(ControlMsg::WriteFrame(id, into_bytes(buf)))
+            .unwrap();
+    }
+    // Writes to every connection, on every worker, encoding the frame once
+    pub fn broadcast<B: Buf + Send + 'static>(&self, buf: B) {
+        self.send_broadcast(into_bytes(buf), None);
+    }
+    // Like broadcast, but skipping `except`, usually whoever sent the frame
+    pub fn broadcast_except<B: Buf + Send + 'static>(&self, except: usize, buf: B) {
+        self.send_broadcast(into_bytes(buf), Some(except));
+    }
+    fn send_broadcast(&self, payload: Bytes, except: Option<usize>) {
+        for sender in self.workers.iter() {
+            sender
+                .send(ControlMsg::Broadcast(payload.clone(), except))
+                .unwrap();
+        }
+    }
+    // Writes to each of `ids`, with one control message per worker involved
+    pub fn multicast<B: Buf + Send + 'static>(&self, ids: &[usize], buf: B) {
+        let payload = into_bytes(buf);
+        let mut by_worker = vec![vec![]; self.workers.len()];
+        for id in ids {
+            by_worker[id % self.workers.len()].push(*id);
+        }
+        for (sender, ids) in self.workers.iter().zip(by_worker) {
+            if !ids.is_empty() {
+                sender
+                    .send(ControlMsg::Multicast(ids, payload.clone()))
+                    .unwrap();
+            }
+        }
+    }
+    // Writes out anything a cork is holding back on `id`
+    pub fn flush(&self, id: usize) {
+        self.sender_for(id).send(ControlMsg::Flush(id)).unwrap();
+    }
+    // Disables Nagle's algorithm on `id`, for latency over throughput
+    pub fn set_nodelay(&self, id: usize, on: bool) {
+        self.sender_for(id)
+            .send(ControlMsg::SetNodelay(id, on))
+            .unwrap();
+    }
+    // Holds frames queued on `id` for up to `window` so they go out in one
+    // write, or with None writes them straight away again
+    pub fn set_cork(&self, id: usize, window: Option<Duration>) {
+        self.sender_for(id)
+            .send(ControlMsg::SetCork(id, window))
+            .unwrap();
+    }
+    // Calls App::handle_timeout with `token` after `delay`,
+    // replacing any pending timeout with the same token
+    pub fn set_timeout(&self, token: usize, delay: Duration) {
+        self.sender
+            .send(ControlMsg::SetTimeout(token, delay))
+            .unwrap();
+    }
+    pub fn cancel_timeout(&self, token: usize) {
+        self.sender.send(ControlMsg::CancelTimeout(token)).unwrap();
+    }
+    // Calls App::handle_ready with `token` whenever `fd` is ready for `interest`,
+    // replacing any earlier watch with the same token.
+    // The caller still owns `fd` and should give it to close_fd rather than
+    // close it, the number could be reused before the unwatch is handled.
+    pub fn watch_fd(&self, token: usize, fd: RawFd, interest: Ready) {
+        self.sender
+            .send(ControlMsg::WatchFd(token, fd, interest))
+            .unwrap();
+    }
+    pub fn unwatch_fd(&self, token: usize) {
+        self.sender.send(ControlMsg::UnwatchFd(token)).unwrap();
+    }
+    // Unwatches `token`, then closes `fd`
+    pub fn close_fd<F: Into<OwnedFd>>(&self, token: usize, fd: F) {
         self.sender
-            .send(ControlMsg::WriteFrame(id, Box::new(buf)))
+            .send(ControlMsg::CloseFd(token, fd.into()))
             .unwrap();
     }
 }
 
-pub struct ConnectionDetails;
+pub struct ConnectionDetails {
+    pub peer_addr: SocketAddr,
+    pub outbound: bool,
+}
 
 impl ConnectionDetails {
-    pub fn new() -> Self {
-        ConnectionDetails
+    pub fn new(peer_addr: SocketAddr, outbound: bool) -> Self {
+        ConnectionDetails {
+            peer_addr,
+            outbound,
+        }
     }
 }
 
-pub struct ListenDetails;
+pub struct ListenDetails {
+    pub local_addr: SocketAddr,
+}
 
 impl ListenDetails {
-    pub fn new() -> Self {
-        ListenDetails
+    pub fn new(local_addr: SocketAddr) -> Self {
+        ListenDetails { local_addr }
     }
 }
 
 pub struct WriteHandle {
     idx: usize,
     sender: Sender<ControlMsg>,
+    // Straight into the connection's write buffer while it is up, so the
+    // loop only hears about it when it has to start writing
+    half: Weak<Mutex<WriteHalf>>,
 }
 
 impl WriteHandle {
     pub fn write_frame<B: IntoBuf + Send + 'static>(&mut self, buf: B)
     where
-        B::Buf: Send,
+        B::Buf: Send + 'static,
     {
+        let payload = into_bytes(buf.into_buf());
+        if let Some(half) = self.half.upgrade() {
+            // XXX Frames that are too big are dropped, as in queue_write
+            if let Ok(true) = half.lock().unwrap().push(payload) {
+                self.sender
+                    .send(ControlMsg::StartWriting(self.idx))
+                    .unwrap();
+            }
+            return;
+        }
         self.sender
-            .send(ControlMsg::WriteFrame(self.idx, Box::new(buf.into_buf())))
+            .send(ControlMsg::WriteFrame(self.idx, payload))
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{new_simple, Backoff};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    type EventLog = Rc<RefCell<Vec<(&'static str, Option<u32>)>>>;
+
+    struct Recorder {
+        events: EventLog,
+    }
+
+    impl App for Recorder {
+        fn handle_connect(&mut self, ctx: &Context, id: usize) {
+            let attempt = ctx.reconnect_attempt(id);
+            self.events.borrow_mut().push(("connect", attempt));
+        }
+        fn handle_close(&mut self, ctx: &Context, id: usize) {
+            let attempt = ctx.reconnect_attempt(id);
+            self.events.borrow_mut().push(("close", attempt));
+        }
+        fn handle_connect_error(&mut self, ctx: &Context, id: usize, _error: io::Error) {
+            let attempt = ctx.reconnect_attempt(id);
+            self.events.borrow_mut().push(("error", attempt));
+        }
+    }
+
+    #[test]
+    fn persistent_peer_redials_and_replays() {
+        let addr = "127.0.0.1:13291";
+        let received = Rc::new(RefCell::new(vec![]));
+        let make_server = || {
+            let received = received.clone();
+            let mut server = new_simple(move |_ctx, _id, frames| {
+                received.borrow_mut().extend(frames);
+            });
+            server.listen(addr).unwrap();
+            server
+        };
+
+        let events = Rc::new(RefCell::new(vec![]));
+        let mut client = Core::new(Recorder {
+            events: events.clone(),
+        });
+        let options = ConnectOptions::new()
+            .persistent(Backoff::new(
+                Duration::from_millis(10),
+                Duration::from_millis(50),
+            ))
+            .queue_while_down(8);
+
+        let mut server = make_server();
+        let id = client.connect_with(addr, options).unwrap();
+        let tick = Some(Duration::from_millis(10));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.ctx.connection_ids().count() == 0 && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+        drop(server);
+        while client.ctx.connection_ids().count() != 0 && Instant::now() < deadline {
+            client.turn(tick).unwrap();
+        }
+        client.write_frame(id, "queued".into_buf());
+        // A few redials fail while nobody listens, without the app noticing
+        let down = Instant::now() + Duration::from_millis(150);
+        while Instant::now() < down {
+            client.turn(tick).unwrap();
+        }
+        assert!(client.ctx.reconnect_attempt(id).unwrap() > 1);
+
+        let mut server = make_server();
+        while received.borrow().is_empty() && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+
+        assert_eq!(*received.borrow(), vec!["queued"]);
+        let (errors, events): (Vec<_>, Vec<_>) =
+            events.borrow().iter().partition(|event| event.0 == "error");
+        assert!(!errors.is_empty());
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], ("connect", Some(0)));
+        assert_eq!(events[1], ("close", Some(1)));
+        let (last, attempt) = events[2];
+        assert_eq!(last, "connect");
+        assert!(attempt.unwrap() > 1);
+    }
+
+    #[test]
+    fn write_handle_writes_from_another_thread() {
+        let addr = "127.0.0.1:13295";
+        let received = Rc::new(RefCell::new(vec![]));
+        let mut server = {
+            let received = received.clone();
+            new_simple(move |_ctx, _id, frames| received.borrow_mut().extend(frames))
+        };
+        server.listen(addr).unwrap();
+        let mut client = new_simple(|_ctx, _id, _frames| {});
+        let id = client.connect(addr).unwrap();
+
+        let mut handle = client.write_handle(id);
+        let writer = std::thread::spawn(move || {
+            for n in 0..1000u32 {
+                handle.write_frame(n.to_le_bytes().to_vec());
+            }
+        });
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.borrow().len() < 1000 && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+        writer.join().unwrap();
+
+        let received = received.borrow();
+        assert_eq!(received.len(), 1000);
+        for (n, frame) in received.iter().enumerate() {
+            assert_eq!(&frame[..], &(n as u32).to_le_bytes()[..]);
+        }
+    }
+
+    #[test]
+    fn broadcast_skips_the_sender() {
+        let addr = "127.0.0.1:13298";
+        let mut server = new_simple(|ctx, id, frames| {
+            for frame in frames {
+                if frame == "one" {
+                    let others: Vec<usize> = ctx.connection_ids().filter(|c| *c != id).collect();
+                    ctx.multicast(&others[..1], frame.into_buf());
+                } else {
+                    ctx.broadcast_except(id, frame.into_buf());
+                }
+            }
+        });
+        server.listen(addr).unwrap();
+        let received = Rc::new(RefCell::new(vec![]));
+        let mut client = {
+            let received = received.clone();
+            new_simple(move |_ctx, id, frames| {
+                received
+                    .borrow_mut()
+                    .extend(frames.into_iter().map(|f| (id, f)));
+            })
+        };
+        let ids: Vec<usize> = (0..3).map(|_| client.connect(addr).unwrap()).collect();
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.ctx.connection_ids().count() < 3 && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+
+        client.write_frame(ids[0], "all".into_buf());
+        client.write_frame(ids[0], "one".into_buf());
+        let mut turns = 0;
+        while turns < 20 && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+            if received.borrow().len() >= 3 {
+                turns += 1;
+            }
+        }
+        let received = received.borrow();
+        assert_eq!(received.len(), 3);
+        assert!(received.iter().all(|(id, _)| *id != ids[0]));
+        let all: Vec<usize> = received
+            .iter()
+            .filter(|(_, f)| f == "all")
+            .map(|r| r.0)
+            .collect();
+        assert_eq!(all.len(), 2);
+        assert_ne!(all[0], all[1]);
+    }
+
+    #[test]
+    fn cork_holds_frames_until_flushed() {
+        let addr = "127.0.0.1:13299";
+        let received = Rc::new(RefCell::new(vec![]));
+        let mut server = {
+            let received = received.clone();
+            new_simple(move |_ctx, _id, frames| received.borrow_mut().extend(frames))
+        };
+        server.listen(addr).unwrap();
+        let mut client = new_simple(|_ctx, _id, _frames| {});
+        client.set_nodelay(true);
+        client.set_cork(Some(Duration::from_secs(60)));
+        let id = client.connect(addr).unwrap();
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut turns = 0;
+        while turns < 10 && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+            if server.ctx.connection_ids().count() == 1 {
+                turns += 1;
+            }
+        }
+
+        for frame in &["one", "two", "three"] {
+            client.write_frame(id, frame.into_buf());
+        }
+        for _ in 0..10 {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+        assert!(received.borrow().is_empty());
+        client.ctx.flush(id);
+        while received.borrow().len() < 3 && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+        assert_eq!(*received.borrow(), vec!["one", "two", "three"]);
+
+        // A short window flushes on its own
+        client.ctx.set_cork(id, Some(Duration::from_millis(20)));
+        client.write_frame(id, "four".into_buf());
+        while received.borrow().len() < 4 && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+        assert_eq!(received.borrow()[3], "four");
+    }
+
+    #[test]
+    fn connects_by_host_name() {
+        let received = Rc::new(RefCell::new(vec![]));
+        let mut server = {
+            let received = received.clone();
+            new_simple(move |_ctx, _id, frames| received.borrow_mut().extend(frames))
+        };
+        server.listen("127.0.0.1:13326").unwrap();
+        let events = Rc::new(RefCell::new(vec![]));
+        let mut client = Core::new(Recorder {
+            events: events.clone(),
+        });
+        assert!(client.connect("localhost").is_err());
+        let id = client.connect("localhost:13326").unwrap();
+        // Queued until an address connects
+        client.write_frame(id, "early".into_buf());
+        client.connect("no-such-host.invalid:13326").unwrap();
+        // Resolves, but nothing listens there
+        client.connect("localhost:13327").unwrap();
+
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let count = |name| events.borrow().iter().filter(|e| e.0 == name).count();
+        while (received.borrow().is_empty() || count("close") + count("error") < 2)
+            && Instant::now() < deadline
+        {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+        assert_eq!(*received.borrow(), vec!["early"]);
+        let peer_addr = client.ctx.connection(id).unwrap().peer_addr;
+        assert_eq!(peer_addr, "127.0.0.1:13326".parse().unwrap());
+        // The lookup failure was never announced, so it gets no close
+        assert_eq!(count("error"), 1);
+        assert_eq!(count("close"), 1);
+        assert_eq!(client.ctx.connection_ids().collect::<Vec<_>>(), vec![id]);
+    }
+
+    #[test]
+    fn stale_lookups_are_ignored() {
+        let events = Rc::new(RefCell::new(vec![]));
+        let mut client = Core::new(Recorder {
+            events: events.clone(),
+        });
+        let id = client.connect("localhost:13327").unwrap();
+        let idx = client.idx(id).unwrap();
+        let generation = match &client.slab[idx] {
+            Socket::Dialing(dial) => dial.generation,
+            _ => panic!("not dialing"),
+        };
+        // Meant for whatever had the slot before
+        let failed = || Err(io::ErrorKind::NotFound.into());
+        client.resolved(idx, generation - 1, failed());
+        assert!(events.borrow().is_empty());
+        client.resolved(idx, generation, failed());
+        assert_eq!(*events.borrow(), vec![("error", None)]);
+    }
+
+    #[test]
+    fn persistent_host_keeps_frames_from_failed_dials() {
+        let addr = "127.0.0.1:13329";
+        let events = Rc::new(RefCell::new(vec![]));
+        let mut client = Core::new(Recorder {
+            events: events.clone(),
+        });
+        let options = ConnectOptions::new()
+            .persistent(Backoff::new(
+                Duration::from_millis(10),
+                Duration::from_millis(50),
+            ))
+            .queue_while_down(8);
+        let id = client.connect_with("localhost:13329", options).unwrap();
+        client.write_frame(id, "early".into_buf());
+        let tick = Some(Duration::from_millis(5));
+        let down = Instant::now() + Duration::from_millis(150);
+        while Instant::now() < down {
+            client.turn(tick).unwrap();
+        }
+        assert!(client.ctx.reconnect_attempt(id).unwrap() > 1);
+
+        let received = Rc::new(RefCell::new(vec![]));
+        let mut server = {
+            let received = received.clone();
+            new_simple(move |_ctx, _id, frames| received.borrow_mut().extend(frames))
+        };
+        server.listen(addr).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.borrow().is_empty() && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+        assert_eq!(*received.borrow(), vec!["early"]);
+    }
+}
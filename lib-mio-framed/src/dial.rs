@@ -0,0 +1,125 @@
+use mio_extras::channel::Sender;
+
+use bytes::Bytes;
+
+use std::collections::VecDeque;
+use std::io::{self, Result as IOResult};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::core::ControlMsg;
+use crate::SocketOptions;
+
+// How long an attempt gets before the next address is tried alongside it,
+// the Connection Attempt Delay from RFC 8305
+pub(crate) const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Looks up host names one at a time, so the event loop never blocks on DNS.
+// Answers come back as ControlMsg::Resolved with the slab index and the
+// generation of the Dial that asked.
+pub(crate) struct Resolver {
+    requests: mpsc::Sender<(usize, u64, String)>,
+}
+
+impl Resolver {
+    pub fn new(control: Sender<ControlMsg>) -> IOResult<Self> {
+        let (requests, rx) = mpsc::channel::<(usize, u64, String)>();
+        // Exits once the Core, and with it `requests`, is dropped
+        thread::Builder::new()
+            .name("mio-framed-resolver".to_string())
+            .spawn(move || {
+                for (idx, generation, host) in rx {
+                    let addrs = host.to_socket_addrs().map(|addrs| addrs.collect());
+                    let resolved = ControlMsg::Resolved(idx, generation, addrs);
+                    if control.send(resolved).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Resolver { requests })
+    }
+
+    pub fn resolve(&self, idx: usize, generation: u64, host: String) {
+        let _ = self.requests.send((idx, generation, host));
+    }
+}
+
+// A connection to a host name, or a persistent peer coming back,
+// from lookup until one attempt wins
+pub(crate) struct Dial {
+    // Tells lookups and timers meant for an earlier Dial in the same slot apart
+    pub generation: u64,
+    pub options: SocketOptions,
+    // Addresses not tried yet, in the order to try them
+    pub remaining: VecDeque<SocketAddr>,
+    // Slab indexes of the attempts in flight
+    pub attempts: Vec<usize>,
+    // Frames written before there was a stream to queue them on
+    pub queue: VecDeque<Bytes>,
+    // Reported to the app with handle_connect
+    pub announced: Option<SocketAddr>,
+    // Redials only announce once an attempt has connected
+    pub redial: bool,
+    // Why the last lookup or attempt failed
+    pub error: Option<io::Error>,
+}
+
+impl Dial {
+    pub fn new(generation: u64, options: SocketOptions) -> Self {
+        Dial {
+            generation,
+            options,
+            remaining: VecDeque::new(),
+            attempts: vec![],
+            queue: VecDeque::new(),
+            announced: None,
+            redial: false,
+            error: None,
+        }
+    }
+}
+
+// Alternates address families, starting with whichever the resolver put first
+pub(crate) fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(|addr| addr.is_ipv6());
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut ordered = VecDeque::new();
+    while !preferred.is_empty() || !other.is_empty() {
+        ordered.extend(preferred.pop_front());
+        ordered.extend(other.pop_front());
+    }
+    ordered
+}
+
+// Must look like host:port, anything else fails straight away
+pub(crate) fn check_host(host: &str) -> bool {
+    match host.rsplit_once(':') {
+        Some((name, port)) => !name.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleaves_families_starting_with_the_first() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ordered: Vec<String> = interleave(addrs).iter().map(|a| a.to_string()).collect();
+        assert_eq!(
+            ordered,
+            vec!["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]
+        );
+        assert!(check_host("localhost:80"));
+        assert!(!check_host("localhost"));
+        assert!(!check_host(":80"));
+    }
+}
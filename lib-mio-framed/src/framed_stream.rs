@@ -1,15 +1,108 @@
+use iovec::IoVec;
+use mio::net::TcpStream;
 use mio::{Evented, Poll, PollOpt, Ready, Token};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
+use std::any::Any;
+use std::collections::VecDeque;
 use std::io::Result as IOResult;
-use std::io::{Error, ErrorKind, Read, Write};
+use std::io::{Cursor, Error, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+// Segments handed to a single writev, IOV_MAX on Linux
+const MAX_IOVECS: usize = 1024;
+// A corked stream flushes early once this much is queued
+const CORK_LIMIT: usize = 64 * 1024;
 
 pub trait Stream: Read + Write + Evented + Send {}
 impl<T> Stream for T where T: Read + Write + Evented + Send {}
 
-impl Stream {
-    pub fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> IOResult<usize> {
+// What FramedStream needs beyond a Stream. TCP gets writev and nodelay,
+// anything else writes a buffer at a time.
+trait RawStream: Stream {
+    fn write_bufs(&mut self, bufs: &[&IoVec]) -> IOResult<usize>;
+    fn set_nodelay(&self, nodelay: bool) -> IOResult<()>;
+}
+
+impl RawStream for TcpStream {
+    fn write_bufs(&mut self, bufs: &[&IoVec]) -> IOResult<usize> {
+        TcpStream::write_bufs(self, bufs)
+    }
+    fn set_nodelay(&self, nodelay: bool) -> IOResult<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+}
+
+// Any other Stream, written one buffer at a time
+struct Plain<S>(S);
+
+impl<S: Stream> Read for Plain<S> {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Stream> Write for Plain<S> {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> IOResult<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: Stream> Evented for Plain<S> {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> IOResult<()> {
+        self.0.register(poll, token, interest, opts)
+    }
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> IOResult<()> {
+        self.0.reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> IOResult<()> {
+        self.0.deregister(poll)
+    }
+}
+
+impl<S: Stream> RawStream for Plain<S> {
+    fn write_bufs(&mut self, bufs: &[&IoVec]) -> IOResult<usize> {
+        self.0.write(&bufs[0][..])
+    }
+    fn set_nodelay(&self, _nodelay: bool) -> IOResult<()> {
+        Ok(())
+    }
+}
+
+// Takes the Bytes out of the usual Bufs instead of copying them, so a
+// payload written to many connections is shared by all of them
+pub(crate) fn into_bytes<B: Buf + 'static>(mut buf: B) -> Bytes {
+    let any: &mut dyn Any = &mut buf;
+    if let Some(cursor) = any.downcast_mut::<Cursor<Bytes>>() {
+        let position = cursor.position() as usize;
+        return cursor.get_ref().slice_from(position);
+    }
+    if let Some(cursor) = any.downcast_mut::<Cursor<Vec<u8>>>() {
+        let position = cursor.position() as usize;
+        return Bytes::from(std::mem::take(cursor.get_mut())).slice_from(position);
+    }
+    if let Some(cursor) = any.downcast_mut::<Cursor<&'static [u8]>>() {
+        let position = cursor.position() as usize;
+        return Bytes::from_static(cursor.get_ref()).slice_from(position);
+    }
+    let mut copy = BytesMut::with_capacity(buf.remaining());
+    copy.put(buf);
+    copy.freeze()
+}
+
+impl dyn Stream {
+    pub fn read_bufmut<B: BufMut>(&mut self, buf: &mut B) -> IOResult<usize> {
         unsafe {
             let b = buf.bytes_mut();
             let rv = self.read(b)?;
@@ -46,11 +139,89 @@ impl Stream {
     }
 }
 
+// The write side of a FramedStream, shared with WriteHandles on other threads
+pub(crate) struct WriteHalf {
+    // Length headers and payloads, never empty ones
+    segments: VecDeque<Bytes>,
+    len: usize,
+    // The loop has writable interest, or has been asked to add it
+    flushing: bool,
+}
+
+impl WriteHalf {
+    // Frames `payload`, returning true if the loop has to start writing
+    pub(crate) fn push(&mut self, payload: Bytes) -> IOResult<bool> {
+        let msg_size = payload.len();
+        if msg_size > u16::MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidData, "Message too big"));
+        }
+        // Small enough to be stored inline, no allocation
+        let header = Bytes::from(&(msg_size as u16).to_le_bytes()[..]);
+        self.segments.push_back(header);
+        self.len += 2 + msg_size;
+        if !payload.is_empty() {
+            self.segments.push_back(payload);
+        }
+        Ok(!std::mem::replace(&mut self.flushing, true))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    fn consume(&mut self, mut written: usize) {
+        self.len -= written;
+        while written > 0 {
+            let front = self
+                .segments
+                .front_mut()
+                .expect("Wrote more than was queued");
+            if front.len() > written {
+                front.advance(written);
+                return;
+            }
+            written -= front.len();
+            self.segments.pop_front();
+        }
+    }
+
+    fn write_to(&mut self, stream: &mut dyn RawStream) -> IOResult<usize> {
+        let mut count = 0;
+        loop {
+            if self.is_empty() {
+                break;
+            }
+            let segments = self.segments.iter().take(MAX_IOVECS);
+            let bufs: Vec<&IoVec> = segments.map(|segment| (&segment[..]).into()).collect();
+            match stream.write_bufs(&bufs) {
+                Ok(0) => {
+                    // XXX TODO When precisely will this happen?
+                    break;
+                }
+                Err(e) => {
+                    if e.kind() == ErrorKind::WouldBlock {
+                        break;
+                    }
+                    return Err(e);
+                }
+                Ok(n) => {
+                    self.consume(n);
+                    count += n;
+                    // Successful read
+                }
+            }
+        }
+        Ok(count)
+    }
+}
+
 pub struct FramedStream {
-    stream: Box<Stream>,
+    stream: Box<dyn RawStream>,
     read_buf: BytesMut,
-    write_buf: BytesMut,
+    write: Arc<Mutex<WriteHalf>>,
     interest: Ready,
+    write_immediately: bool,
+    cork: Option<Duration>,
 }
 
 impl Evented for FramedStream {
@@ -74,31 +245,62 @@ impl Evented for FramedStream {
 impl FramedStream {
     pub fn new<S: Stream + 'static>(stream: S) -> Self {
         let interest = Ready::readable();
-        let write_buf = BytesMut::with_capacity(8192);
-        let stream = Box::new(stream);
+        let write = Arc::new(Mutex::new(WriteHalf {
+            segments: VecDeque::new(),
+            len: 0,
+            flushing: false,
+        }));
+        // A TcpStream is kept as one, so writes can use writev
+        let mut stream = Some(stream);
+        let any: &mut dyn Any = &mut stream;
+        let stream: Box<dyn RawStream> = match any.downcast_mut::<Option<TcpStream>>() {
+            Some(tcp) => Box::new(tcp.take().unwrap()),
+            None => Box::new(Plain(stream.unwrap())),
+        };
         let read_buf = BytesMut::with_capacity(8192);
         FramedStream {
             stream,
             read_buf,
-            write_buf,
+            write,
             interest,
+            write_immediately: true,
+            cork: None,
         }
     }
+    // Try the socket straight away when nothing is queued, instead of
+    // waiting a poll cycle for it to be writable. On by default.
+    pub fn set_write_immediately(&mut self, on: bool) {
+        self.write_immediately = on;
+    }
+    pub fn set_nodelay(&self, nodelay: bool) -> IOResult<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+    // Hold frames for up to `window` after the first one is queued, so they
+    // go out together. Core arms the timer, anyone else has to call flush.
+    pub fn set_cork(&mut self, window: Option<Duration>) {
+        self.cork = window;
+    }
+    pub fn cork(&self) -> Option<Duration> {
+        self.cork
+    }
+    // Gone once this stream is dropped, so writers can fall back to the loop
+    pub(crate) fn write_half(&self) -> Weak<Mutex<WriteHalf>> {
+        Arc::downgrade(&self.write)
+    }
     pub fn interest(&self) -> Ready {
         self.interest
     }
     fn ensure_read_buf_capacity(&mut self) {
         let buf = &mut self.read_buf;
-        let buf_len = buf.len();
-        let buf_capacity = buf.capacity();
-        if buf.len() > 2 {
+        // Room for the rest of the current frame, and never a zero-length
+        // read, which would look like the peer hung up
+        let mut wanted = 1024;
+        if buf.len() >= 2 {
             let msg_size = u16::from_le_bytes([buf[0], buf[1]]) as usize;
-            let buf_msg_len = buf_len - 2;
-            if msg_size > buf_msg_len + buf_capacity {
-                buf.reserve(msg_size - buf_msg_len);
-            }
-        } else if buf_capacity < 1024 {
-            buf.reserve(4096);
+            wanted = wanted.max((msg_size + 2).saturating_sub(buf.len()));
+        }
+        if buf.remaining_mut() < wanted {
+            buf.reserve(wanted.max(4096));
         }
     }
     pub fn read_frames(&mut self) -> (Vec<Bytes>, Option<Error>) {
@@ -107,7 +309,8 @@ impl FramedStream {
         'outer: loop {
             self.ensure_read_buf_capacity();
             let buf = &mut self.read_buf;
-            match self.stream.read_buf(buf) {
+            let stream: &mut dyn Stream = &mut *self.stream;
+            match stream.read_bufmut(buf) {
                 Ok(0) => {
                     err = Some(Error::new(ErrorKind::UnexpectedEof, "Connection Closed"));
                     break 'outer;
@@ -122,7 +325,7 @@ impl FramedStream {
                     // Successful read
                 }
             }
-            'inner: while buf.len() > 2 {
+            'inner: while buf.len() >= 2 {
                 let msg_size = u16::from_le_bytes([buf[0], buf[1]]) as usize;
                 let buf_msg_len = buf.len() - 2;
                 if msg_size <= buf_msg_len {
@@ -134,26 +337,68 @@ impl FramedStream {
                 }
             }
         }
-        return (frames, err);
+        (frames, err)
     }
 
+    // A corked stream needs flushing once the window is up
     pub fn queue_write<B: Buf + Send + 'static>(
         &mut self,
         buf: B,
         poll: &mut Poll,
         token: Token,
     ) -> IOResult<()> {
-        // XXX TODO Optimistically attempt writing immediately?
-        let msg_size = buf.remaining();
-        if msg_size > std::u16::MAX as usize {
-            return Err(Error::new(ErrorKind::InvalidData, "Message too big"));
+        self.queue_bytes(into_bytes(buf), poll, token)?;
+        Ok(())
+    }
+
+    // True if the stream is corked and wants flushing after its window
+    pub(crate) fn queue_bytes(
+        &mut self,
+        payload: Bytes,
+        poll: &mut Poll,
+        token: Token,
+    ) -> IOResult<bool> {
+        let mut half = self.write.lock().unwrap();
+        let started = half.push(payload)?;
+        let full = self.cork.is_some() && half.len >= CORK_LIMIT;
+        drop(half);
+        if full {
+            self.flush(poll, token)?;
+            return Ok(false);
         }
-        if self.write_buf.capacity() < msg_size + 2 {
-            self.write_buf.reserve(msg_size + 2);
+        if !started {
+            // Already corked or waiting for writable
+            return Ok(false);
         }
-        self.write_buf.put_u16_le(msg_size as u16);
-        self.write_buf.put(buf);
+        self.start_writing(poll, token)
+    }
+
+    // The write half went from empty to having something queued
+    pub(crate) fn start_writing(&mut self, poll: &mut Poll, token: Token) -> IOResult<bool> {
+        if self.cork.is_some() {
+            return Ok(true);
+        }
+        self.flush(poll, token)?;
+        Ok(false)
+    }
+
+    // Write out whatever is queued, waiting for writable if it doesn't all fit
+    pub fn flush(&mut self, poll: &mut Poll, token: Token) -> IOResult<()> {
+        let mut half = self.write.lock().unwrap();
+        if self.write_immediately && !half.is_empty() {
+            // Errors turn up again with the next writable event
+            let _ = half.write_to(&mut *self.stream);
+        }
+        if half.is_empty() {
+            half.flushing = false;
+            return Ok(());
+        }
+        drop(half);
+        self.want_write(poll, token)
+    }
 
+    // Something was queued through the write half, start writing it out
+    pub fn want_write(&mut self, poll: &mut Poll, token: Token) -> IOResult<()> {
         if !self.interest.is_writable() {
             self.interest.insert(Ready::writable());
             poll.reregister(self, token, self.interest(), PollOpt::edge())?;
@@ -162,35 +407,10 @@ impl FramedStream {
     }
 
     pub fn handle_write(&mut self) -> IOResult<usize> {
-        // XXX TODO use vectored writes?
-        // Not quite sure how to populate the array of iovecs
-        // https://docs.rs/mio/0.6.16/mio/net/struct.TcpStream.html#method.read_bufs
-        // https://docs.rs/bytes/0.4.11/bytes/trait.Buf.html#method.bytes_vec
-
-        let mut count = 0;
-        loop {
-            if self.write_buf.is_empty() {
-                break;
-            }
-            match self.stream.write(&self.write_buf) {
-                Ok(0) => {
-                    // XXX TODO When precisely will this happen?
-                    break;
-                }
-                Err(e) => {
-                    if e.kind() == ErrorKind::WouldBlock {
-                        break;
-                    }
-                    return Err(e);
-                }
-                Ok(n) => {
-                    self.write_buf.advance(n);
-                    count += n;
-                    // Successful read
-                }
-            }
-        }
-        if self.write_buf.is_empty() {
+        let mut half = self.write.lock().unwrap();
+        let count = half.write_to(&mut *self.stream)?;
+        if half.is_empty() {
+            half.flushing = false;
             self.interest.remove(Ready::writable());
         } else {
             // Pending unhandled writes remain
@@ -198,3 +418,96 @@ impl FramedStream {
         Ok(count)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::IntoBuf;
+    use mio::unix::EventedFd;
+
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn shared_payloads_are_not_copied() {
+        let payload = Bytes::from(vec![7u8; 4096]);
+        let mut buf = payload.clone().into_buf();
+        buf.advance(96);
+        let queued = into_bytes(buf);
+        assert_eq!(queued.as_ptr(), payload[96..].as_ptr());
+        assert_eq!(queued.len(), 4000);
+
+        let mut half = WriteHalf {
+            segments: VecDeque::new(),
+            len: 0,
+            flushing: false,
+        };
+        assert!(half.push(queued).unwrap());
+        assert!(!half.push(Bytes::new()).unwrap());
+        assert_eq!(half.segments.len(), 3);
+        half.consume(1001);
+        assert_eq!(half.segments.len(), 2);
+        assert_eq!(half.segments[0].len(), 3001);
+        assert_eq!(half.len, 3001 + 2);
+    }
+
+    // Any Evented byte stream will do, not just TCP
+    struct Unix(UnixStream);
+
+    impl Read for Unix {
+        fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Unix {
+        fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> IOResult<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Evented for Unix {
+        fn register(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> IOResult<()> {
+            EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+        }
+        fn reregister(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> IOResult<()> {
+            EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+        }
+        fn deregister(&self, poll: &Poll) -> IOResult<()> {
+            EventedFd(&self.0.as_raw_fd()).deregister(poll)
+        }
+    }
+
+    #[test]
+    fn frames_other_streams() {
+        let (a, b) = UnixStream::pair().unwrap();
+        b.set_nonblocking(true).unwrap();
+        let mut sender = FramedStream::new(Unix(a));
+        let mut receiver = FramedStream::new(Unix(b));
+        assert!(sender.set_nodelay(true).is_ok());
+        let mut half = sender.write.lock().unwrap();
+        half.push(Bytes::from(&b"hello"[..])).unwrap();
+        half.push(Bytes::from(&b"world"[..])).unwrap();
+        while !half.is_empty() {
+            half.write_to(&mut *sender.stream).unwrap();
+        }
+        let (frames, err) = receiver.read_frames();
+        assert_eq!(frames, vec![Bytes::from("hello"), Bytes::from("world")]);
+        assert!(err.is_none());
+    }
+}
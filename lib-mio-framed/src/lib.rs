@@ -1,10 +1,23 @@
 mod app;
+mod channel;
+mod connect;
 mod core;
+mod dial;
 mod framed_stream;
+mod multi;
+mod rpc;
+mod socket;
 
 pub use crate::app::{new_simple, App, SimpleApp, new_serde, SerdeApp, SerdeAppCore};
-pub use crate::core::{Context, Core};
+pub use crate::channel::{new_channels, ChannelApp, ChannelContext, Channels, DEFAULT_WINDOW};
+pub use crate::connect::{Backoff, ConnectOptions};
+pub use crate::core::{ConnectionDetails, Context, Core, ListenDetails};
 pub use crate::framed_stream::FramedStream;
+pub use crate::multi::{new_shared, MultiCore, MultiHandle, Shared};
+pub use crate::rpc::{new_rpc, Rpc, RpcApp, RpcContext, RpcError};
+pub use crate::socket::{Keepalive, SocketOptions};
+pub use mio::unix::UnixReady;
+pub use mio::Ready;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,278 @@
+use mio::net::TcpListener;
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio_extras::channel::{channel, Sender};
+
+use bytes::Bytes;
+use failure::{format_err, Error};
+
+use std::io;
+use std::io::Result as IOResult;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::core::ControlMsg;
+use crate::{App, Context, Core, SocketOptions};
+
+// Wakes the acceptor to stop, listeners use their index as the token
+const SHUTDOWN: Token = Token(usize::MAX - 1);
+
+// A Core per worker thread, each with its own Poll, and an acceptor thread
+// handing out new connections round-robin. Connection ids are unique across
+// workers, so Context::write_frame works for any of them.
+pub struct MultiCore<F> {
+    workers: usize,
+    factory: F,
+    listeners: Vec<(TcpListener, SocketOptions)>,
+}
+
+impl<A, F> MultiCore<F>
+where
+    A: App,
+    F: Fn(usize) -> A + Send + Sync + 'static,
+{
+    // `factory` builds each worker's App, on that worker's thread
+    pub fn new(workers: usize, factory: F) -> Self {
+        MultiCore {
+            workers: workers.max(1),
+            factory,
+            listeners: vec![],
+        }
+    }
+
+    // The id passed to handle_accept as the listen socket
+    pub fn listen(&mut self, addr: &str) -> Result<usize, Error> {
+        self.listen_with(addr, SocketOptions::new())
+    }
+
+    // The acceptor applies `options` to each connection before handing it out
+    pub fn listen_with(&mut self, addr: &str, options: SocketOptions) -> Result<usize, Error> {
+        let addr = addr.parse()?;
+        self.listeners.push((options.listener(addr)?, options));
+        Ok(self.listeners.len() - 1)
+    }
+
+    pub fn start(self) -> Result<MultiHandle, Error> {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..self.workers).map(|_| channel()).unzip();
+        let mut listening = vec![];
+        for (id, (listener, _)) in self.listeners.iter().enumerate() {
+            listening.push((id, listener.local_addr()?));
+        }
+        let factory = Arc::new(self.factory);
+        let mut workers = vec![];
+        for (worker, control_rx) in receivers.into_iter().enumerate() {
+            let senders = senders.clone();
+            let factory = factory.clone();
+            let listening = listening.clone();
+            let thread = thread::Builder::new()
+                .name(format!("mio-framed-worker-{}", worker))
+                .spawn(move || {
+                    let control_tx = senders[worker].clone();
+                    let app = factory(worker);
+                    let mut core = Core::worker(app, control_tx, control_rx, worker, senders);
+                    for (id, local_addr) in listening {
+                        core.listened(id, local_addr);
+                    }
+                    while !core.is_shutdown() {
+                        core.turn(None)?;
+                    }
+                    core.shutdown();
+                    Ok(())
+                })?;
+            workers.push(thread);
+        }
+
+        let (registration, stop) = Registration::new2();
+        let listeners = self.listeners;
+        let targets = senders.clone();
+        let acceptor = thread::Builder::new()
+            .name("mio-framed-acceptor".to_string())
+            .spawn(move || accept(listeners, registration, targets))?;
+        Ok(MultiHandle {
+            acceptor,
+            workers,
+            senders,
+            stop,
+        })
+    }
+}
+
+fn accept(
+    listeners: Vec<(TcpListener, SocketOptions)>,
+    registration: Registration,
+    workers: Vec<Sender<ControlMsg>>,
+) -> IOResult<()> {
+    let poll = Poll::new()?;
+    for (id, (listener, _)) in listeners.iter().enumerate() {
+        poll.register(listener, Token(id), Ready::readable(), PollOpt::edge())?;
+    }
+    poll.register(&registration, SHUTDOWN, Ready::readable(), PollOpt::edge())?;
+    let mut events = Events::with_capacity(128);
+    let mut next = 0;
+    loop {
+        poll.poll(&mut events, None)?;
+        for event in events.iter() {
+            let id = event.token().0;
+            if event.token() == SHUTDOWN {
+                return Ok(());
+            }
+            let (listener, options) = &listeners[id];
+            // Edge triggered, so accept everything that is pending
+            loop {
+                match listener.accept() {
+                    Ok((stream, addr)) => {
+                        // XXX Options that fail to apply are ignored
+                        let _ = options.configure(&stream);
+                        let _ = workers[next].send(ControlMsg::Adopt(stream, addr, id));
+                        next = (next + 1) % workers.len();
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    // XXX app.handle_accept_error?
+                    Err(_e) => break,
+                }
+            }
+        }
+    }
+}
+
+pub struct MultiHandle {
+    acceptor: JoinHandle<IOResult<()>>,
+    workers: Vec<JoinHandle<IOResult<()>>>,
+    senders: Vec<Sender<ControlMsg>>,
+    stop: SetReadiness,
+}
+
+impl MultiHandle {
+    // Stops accepting, then stops every worker after its current turn,
+    // calling handle_shutdown on each worker's App
+    pub fn shutdown(self) -> Result<(), Error> {
+        self.stop.set_readiness(Ready::readable())?;
+        for sender in self.senders.iter() {
+            let _ = sender.send(ControlMsg::Shutdown);
+        }
+        let threads = Some(self.acceptor).into_iter().chain(self.workers);
+        for thread in threads {
+            thread
+                .join()
+                .map_err(|_| format_err!("mio-framed thread panicked"))??;
+        }
+        Ok(())
+    }
+}
+
+// One App shared by every worker. Each callback holds the lock, so
+// handle_shutdown is called once per worker.
+pub struct Shared<A>(pub Arc<Mutex<A>>);
+
+impl<A> Clone for Shared<A> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+pub fn new_shared<A: App + Send + 'static>(
+    workers: usize,
+    app: Arc<Mutex<A>>,
+) -> MultiCore<impl Fn(usize) -> Shared<A> + Send + Sync + 'static> {
+    let shared = Shared(app);
+    MultiCore::new(workers, move |_worker| shared.clone())
+}
+
+impl<A: App> App for Shared<A> {
+    fn handle_init(&mut self, ctx: &Context) {
+        self.0.lock().unwrap().handle_init(ctx)
+    }
+    fn handle_listen(&mut self, ctx: &Context, id: usize) {
+        self.0.lock().unwrap().handle_listen(ctx, id)
+    }
+    fn handle_connect(&mut self, ctx: &Context, id: usize) {
+        self.0.lock().unwrap().handle_connect(ctx, id)
+    }
+    fn handle_accept(&mut self, ctx: &Context, listen_socket: usize, id: usize) {
+        self.0.lock().unwrap().handle_accept(ctx, listen_socket, id)
+    }
+    fn handle_close(&mut self, ctx: &Context, id: usize) {
+        self.0.lock().unwrap().handle_close(ctx, id)
+    }
+    fn handle_frames(&mut self, ctx: &Context, id: usize, frames: Vec<Bytes>) {
+        self.0.lock().unwrap().handle_frames(ctx, id, frames)
+    }
+    fn handle_timeout(&mut self, ctx: &Context, token: usize) {
+        self.0.lock().unwrap().handle_timeout(ctx, token)
+    }
+    fn handle_ready(&mut self, ctx: &Context, token: usize, readiness: Ready) {
+        self.0.lock().unwrap().handle_ready(ctx, token, readiness)
+    }
+    fn handle_shutdown(&mut self) {
+        self.0.lock().unwrap().handle_shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_simple;
+
+    use bytes::IntoBuf;
+
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    #[derive(Default)]
+    struct Relay {
+        conns: Vec<usize>,
+        workers: HashSet<usize>,
+    }
+
+    // Everything said on one connection goes to all of them, on any worker
+    impl App for Relay {
+        fn handle_accept(&mut self, ctx: &Context, _listen_socket: usize, id: usize) {
+            self.conns.push(id);
+            self.workers.insert(ctx.worker());
+        }
+        fn handle_frames(&mut self, ctx: &Context, _id: usize, frames: Vec<Bytes>) {
+            for frame in frames {
+                for conn in self.conns.iter() {
+                    ctx.write_frame(*conn, frame.clone().into_buf());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spreads_connections_and_writes_across_workers() {
+        let addr = "127.0.0.1:13294";
+        let relay = Arc::new(Mutex::new(Relay::default()));
+        let mut multi = new_shared(3, relay.clone());
+        multi.listen(addr).unwrap();
+        let handle = multi.start().unwrap();
+
+        let received = Rc::new(RefCell::new(vec![]));
+        let mut client = {
+            let received = received.clone();
+            new_simple(move |_ctx, id, frames| {
+                received
+                    .borrow_mut()
+                    .extend(frames.into_iter().map(|f| (id, f)));
+            })
+        };
+        let ids: Vec<usize> = (0..6).map(|_| client.connect(addr).unwrap()).collect();
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while relay.lock().unwrap().conns.len() < 6 && Instant::now() < deadline {
+            client.turn(tick).unwrap();
+        }
+        client.write_frame(ids[0], "hello".into_buf());
+        while received.borrow().len() < 6 && Instant::now() < deadline {
+            client.turn(tick).unwrap();
+        }
+        handle.shutdown().unwrap();
+
+        assert_eq!(relay.lock().unwrap().workers.len(), 3);
+        let received = received.borrow();
+        let conns: HashSet<usize> = received.iter().map(|(id, _)| *id).collect();
+        assert_eq!(conns, ids.into_iter().collect());
+        assert!(received.iter().all(|(_, frame)| frame == "hello"));
+    }
+}
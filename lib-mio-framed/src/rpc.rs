@@ -0,0 +1,344 @@
+use crate::{App, Context, Core};
+use bytes::{BufMut, Bytes, BytesMut, IntoBuf};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+// Every frame starts with a kind byte, all but messages follow it with a
+// little-endian u64 correlation id
+const KIND_MESSAGE: u8 = 0;
+const KIND_REQUEST: u8 = 1;
+const KIND_RESPONSE: u8 = 2;
+const KIND_ERROR: u8 = 3;
+const HEADER_LEN: usize = 9;
+
+// Timer tokens are tagged in the low bit so requests and the app can't collide
+const TIMER_BITS: usize = 1;
+const TIMER_APP: usize = 0;
+const TIMER_REQUEST: usize = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcError {
+    Timeout,
+    Closed,
+    // The handler answered with RpcContext::respond_error
+    Remote(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "request timed out"),
+            RpcError::Closed => write!(f, "connection closed before a response arrived"),
+            RpcError::Remote(message) => write!(f, "remote error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+pub trait RpcApp {
+    fn handle_init(&mut self, _ctx: &mut RpcContext) {}
+    fn handle_connect(&mut self, _ctx: &mut RpcContext, _id: usize) {}
+    fn handle_accept(&mut self, _ctx: &mut RpcContext, _listen_socket: usize, _id: usize) {}
+    // Called after every pending request on the connection has failed
+    fn handle_close(&mut self, _ctx: &mut RpcContext, _id: usize) {}
+    // One-way messages sent with RpcContext::send
+    fn handle_frames(&mut self, _ctx: &mut RpcContext, _id: usize, _frames: Vec<Bytes>) {}
+    // Answer with RpcContext::respond or respond_error, now or later
+    fn handle_request(
+        &mut self,
+        _ctx: &mut RpcContext,
+        _id: usize,
+        _request: u64,
+        _payload: Bytes,
+    ) {
+    }
+    fn handle_response(
+        &mut self,
+        _ctx: &mut RpcContext,
+        _id: usize,
+        _request: u64,
+        _response: Result<Bytes, RpcError>,
+    ) {
+    }
+    fn handle_timeout(&mut self, _ctx: &mut RpcContext, _token: usize) {}
+    fn handle_shutdown(&mut self) {}
+}
+
+pub struct RpcContext<'a> {
+    ctx: &'a Context,
+    state: &'a mut RpcState,
+}
+
+impl<'a> RpcContext<'a> {
+    pub fn context(&self) -> &Context {
+        self.ctx
+    }
+    pub fn send<B: AsRef<[u8]>>(&self, id: usize, payload: B) {
+        write_frame(self.ctx, id, KIND_MESSAGE, None, payload.as_ref());
+    }
+    // Returns the correlation id later passed to RpcApp::handle_response
+    pub fn request<B: AsRef<[u8]>>(&mut self, id: usize, payload: B, timeout: Duration) -> u64 {
+        self.state.next_request += 1;
+        let request = self.state.next_request;
+        self.state.pending.insert(request, id);
+        write_frame(self.ctx, id, KIND_REQUEST, Some(request), payload.as_ref());
+        self.ctx
+            .set_timeout(timer_token(TIMER_REQUEST, request as usize), timeout);
+        request
+    }
+    pub fn respond<B: AsRef<[u8]>>(&self, id: usize, request: u64, payload: B) {
+        write_frame(self.ctx, id, KIND_RESPONSE, Some(request), payload.as_ref());
+    }
+    pub fn respond_error(&self, id: usize, request: u64, message: &str) {
+        write_frame(self.ctx, id, KIND_ERROR, Some(request), message.as_bytes());
+    }
+    pub fn pending_requests(&self) -> usize {
+        self.state.pending.len()
+    }
+    pub fn set_timeout(&self, token: usize, delay: Duration) {
+        self.ctx.set_timeout(timer_token(TIMER_APP, token), delay);
+    }
+    pub fn cancel_timeout(&self, token: usize) {
+        self.ctx.cancel_timeout(timer_token(TIMER_APP, token));
+    }
+}
+
+fn timer_token(kind: usize, token: usize) -> usize {
+    token << TIMER_BITS | kind
+}
+
+fn write_frame(ctx: &Context, id: usize, kind: u8, request: Option<u64>, payload: &[u8]) {
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    frame.put_u8(kind);
+    if let Some(request) = request {
+        frame.put_u64_le(request);
+    }
+    frame.put_slice(payload);
+    ctx.write_frame(id, frame.freeze().into_buf());
+}
+
+#[derive(Default)]
+struct RpcState {
+    next_request: u64,
+    // Outstanding requests and the connection they went out on
+    pending: HashMap<u64, usize>,
+}
+
+pub struct Rpc<A: RpcApp> {
+    app: A,
+    state: RpcState,
+}
+
+pub fn new_rpc<A: RpcApp>(app: A) -> Core<Rpc<A>> {
+    Core::new(Rpc::new(app))
+}
+
+impl<A: RpcApp> Rpc<A> {
+    pub fn new(app: A) -> Self {
+        Rpc {
+            app,
+            state: RpcState::default(),
+        }
+    }
+
+    fn wrap_context<'a>(&'a mut self, ctx: &'a Context) -> (&'a mut A, RpcContext<'a>) {
+        let ctx = RpcContext {
+            ctx,
+            state: &mut self.state,
+        };
+        (&mut self.app, ctx)
+    }
+}
+
+impl<A: RpcApp> App for Rpc<A> {
+    fn handle_init(&mut self, ctx: &Context) {
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_init(&mut ctx)
+    }
+    fn handle_connect(&mut self, ctx: &Context, id: usize) {
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_connect(&mut ctx, id)
+    }
+    fn handle_accept(&mut self, ctx: &Context, listen_socket: usize, id: usize) {
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_accept(&mut ctx, listen_socket, id)
+    }
+    fn handle_close(&mut self, ctx: &Context, id: usize) {
+        let mut failed: Vec<u64> = self
+            .state
+            .pending
+            .iter()
+            .filter(|(_, conn)| **conn == id)
+            .map(|(request, _)| *request)
+            .collect();
+        failed.sort();
+        let (app, mut ctx) = self.wrap_context(ctx);
+        for request in failed {
+            ctx.state.pending.remove(&request);
+            ctx.ctx
+                .cancel_timeout(timer_token(TIMER_REQUEST, request as usize));
+            app.handle_response(&mut ctx, id, request, Err(RpcError::Closed));
+        }
+        app.handle_close(&mut ctx, id)
+    }
+    fn handle_frames(&mut self, ctx: &Context, id: usize, frames: Vec<Bytes>) {
+        let (app, mut ctx) = self.wrap_context(ctx);
+        let mut messages = vec![];
+        for mut frame in frames {
+            let kind = match frame.first() {
+                Some(kind) => *kind,
+                None => continue,
+            };
+            if kind == KIND_MESSAGE {
+                frame.advance(1);
+                messages.push(frame);
+                continue;
+            }
+            if frame.len() < HEADER_LEN {
+                // XXX app.handle_decode_error?
+                continue;
+            }
+            let mut request = [0; 8];
+            request.copy_from_slice(&frame[1..HEADER_LEN]);
+            let request = u64::from_le_bytes(request);
+            let payload = frame.split_off(HEADER_LEN);
+            match kind {
+                KIND_REQUEST => app.handle_request(&mut ctx, id, request, payload),
+                KIND_RESPONSE | KIND_ERROR => {
+                    // Late responses to timed out requests are dropped
+                    if ctx.state.pending.get(&request) != Some(&id) {
+                        continue;
+                    }
+                    ctx.state.pending.remove(&request);
+                    ctx.ctx
+                        .cancel_timeout(timer_token(TIMER_REQUEST, request as usize));
+                    let response = if kind == KIND_RESPONSE {
+                        Ok(payload)
+                    } else {
+                        let message = String::from_utf8_lossy(&payload).into_owned();
+                        Err(RpcError::Remote(message))
+                    };
+                    app.handle_response(&mut ctx, id, request, response);
+                }
+                _ => {}
+            }
+        }
+        if !messages.is_empty() {
+            app.handle_frames(&mut ctx, id, messages);
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &Context, token: usize) {
+        let (app, mut ctx) = self.wrap_context(ctx);
+        match token & ((1 << TIMER_BITS) - 1) {
+            TIMER_REQUEST => {
+                let request = (token >> TIMER_BITS) as u64;
+                if let Some(id) = ctx.state.pending.remove(&request) {
+                    app.handle_response(&mut ctx, id, request, Err(RpcError::Timeout));
+                }
+            }
+            _ => app.handle_timeout(&mut ctx, token >> TIMER_BITS),
+        }
+    }
+    fn handle_shutdown(&mut self) {
+        self.app.handle_shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    struct Server;
+
+    impl RpcApp for Server {
+        fn handle_request(
+            &mut self,
+            ctx: &mut RpcContext,
+            id: usize,
+            request: u64,
+            payload: Bytes,
+        ) {
+            match &payload[..] {
+                b"ignore" => {}
+                b"fail" => ctx.respond_error(id, request, "no such thing"),
+                _ => ctx.respond(id, request, payload.to_ascii_uppercase()),
+            }
+        }
+    }
+
+    type Responses = Rc<RefCell<Vec<(&'static str, Result<Bytes, RpcError>)>>>;
+
+    struct Client {
+        sent: HashMap<u64, &'static str>,
+        responses: Responses,
+    }
+
+    impl RpcApp for Client {
+        fn handle_connect(&mut self, ctx: &mut RpcContext, id: usize) {
+            let requests = [
+                ("echo", Duration::from_secs(5)),
+                ("fail", Duration::from_secs(5)),
+                ("ignore", Duration::from_millis(20)),
+            ];
+            for (payload, timeout) in requests.iter() {
+                let request = ctx.request(id, payload, *timeout);
+                self.sent.insert(request, payload);
+            }
+            // Never answered, fails once the server goes away
+            let request = ctx.request(id, "ignore", Duration::from_secs(5));
+            self.sent.insert(request, "closed");
+        }
+        fn handle_response(
+            &mut self,
+            _ctx: &mut RpcContext,
+            _id: usize,
+            request: u64,
+            response: Result<Bytes, RpcError>,
+        ) {
+            let sent = self.sent[&request];
+            self.responses.borrow_mut().push((sent, response));
+        }
+    }
+
+    #[test]
+    fn responses_timeouts_and_closes() {
+        let addr = "127.0.0.1:13292";
+        let responses = Rc::new(RefCell::new(vec![]));
+        let mut server = new_rpc(Server);
+        server.listen(addr).unwrap();
+        let mut client = new_rpc(Client {
+            sent: HashMap::new(),
+            responses: responses.clone(),
+        });
+        client.connect(addr).unwrap();
+
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while responses.borrow().len() < 3 && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+        drop(server);
+        while responses.borrow().len() < 4 && Instant::now() < deadline {
+            client.turn(tick).unwrap();
+        }
+
+        let mut responses = responses.borrow().clone();
+        responses.sort_by_key(|(sent, _)| *sent);
+        assert_eq!(
+            responses,
+            vec![
+                ("closed", Err(RpcError::Closed)),
+                ("echo", Ok(Bytes::from("ECHO"))),
+                ("fail", Err(RpcError::Remote("no such thing".into()))),
+                ("ignore", Err(RpcError::Timeout)),
+            ]
+        );
+    }
+}
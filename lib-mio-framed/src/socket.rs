@@ -0,0 +1,235 @@
+use mio::net::{TcpListener, TcpStream};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+
+use std::io::Result as IOResult;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+// Same as mio's TcpListener::bind
+const DEFAULT_BACKLOG: i32 = 1024;
+
+#[derive(Clone, Debug)]
+pub struct Keepalive {
+    time: Duration,
+    interval: Option<Duration>,
+    retries: Option<u32>,
+}
+
+impl Keepalive {
+    // Start probing after the connection has been idle for `time`
+    pub fn new(time: Duration) -> Self {
+        Keepalive {
+            time,
+            interval: None,
+            retries: None,
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    // Unanswered probes before the connection is dropped
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
+    fn params(&self) -> TcpKeepalive {
+        let mut params = TcpKeepalive::new().with_time(self.time);
+        if let Some(interval) = self.interval {
+            params = params.with_interval(interval);
+        }
+        if let Some(retries) = self.retries {
+            params = params.with_retries(retries);
+        }
+        params
+    }
+}
+
+// Anything left unset keeps the OS default, except SO_REUSEADDR,
+// which listeners turn on like mio does
+#[derive(Clone, Debug, Default)]
+pub struct SocketOptions {
+    reuse_address: Option<bool>,
+    reuse_port: bool,
+    backlog: Option<i32>,
+    keepalive: Option<Keepalive>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    nodelay: Option<bool>,
+    bind_addr: Option<SocketAddr>,
+    only_v6: Option<bool>,
+    linger: Option<Duration>,
+}
+
+impl SocketOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.reuse_address = Some(reuse);
+        self
+    }
+
+    // Lets several listeners bind the same port, the kernel spreads
+    // connections between them
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.reuse_port = reuse;
+        self
+    }
+
+    // Listeners only
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = Some(backlog.min(i32::MAX as u32) as i32);
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    // Outbound connections only, binds the local end before connecting
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.bind_addr = Some(addr);
+        self
+    }
+
+    // IPv6 sockets only, false accepts IPv4-mapped addresses too
+    pub fn only_v6(mut self, only_v6: bool) -> Self {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    // Block close for up to `linger` to send what is left,
+    // zero resets the connection instead
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    pub(crate) fn listener(&self, addr: SocketAddr) -> IOResult<TcpListener> {
+        let socket = self.socket(addr)?;
+        socket.set_reuse_address(self.reuse_address.unwrap_or(true))?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog.unwrap_or(DEFAULT_BACKLOG))?;
+        TcpListener::from_std(socket.into())
+    }
+
+    pub(crate) fn connect(&self, addr: SocketAddr) -> IOResult<TcpStream> {
+        let socket = self.socket(addr)?;
+        if let Some(reuse) = self.reuse_address {
+            socket.set_reuse_address(reuse)?;
+        }
+        if let Some(bind_addr) = self.bind_addr {
+            socket.bind(&bind_addr.into())?;
+        }
+        TcpStream::connect_stream(socket.into(), &addr)
+    }
+
+    fn socket(&self, addr: SocketAddr) -> IOResult<Socket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        if let (Some(only_v6), SocketAddr::V6(_)) = (self.only_v6, addr) {
+            socket.set_only_v6(only_v6)?;
+        }
+        if self.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        self.configure(&socket)?;
+        Ok(socket)
+    }
+
+    // The per connection options, for sockets that came from accept
+    pub(crate) fn configure<S: AsRawFd>(&self, socket: &S) -> IOResult<()> {
+        let socket = SockRef::from(socket);
+        if let Some(keepalive) = &self.keepalive {
+            socket.set_tcp_keepalive(&keepalive.params())?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if let Some(linger) = self.linger {
+            socket.set_linger(Some(linger))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn options_reach_both_ends() {
+        let addr = "127.0.0.1:13324".parse().unwrap();
+        let options = SocketOptions::new()
+            .reuse_port(true)
+            .backlog(16)
+            .keepalive(
+                Keepalive::new(Duration::from_secs(30))
+                    .interval(Duration::from_secs(5))
+                    .retries(3),
+            )
+            .recv_buffer_size(64 * 1024)
+            .nodelay(true)
+            .linger(Duration::from_secs(1));
+        let listener = options.listener(addr).unwrap();
+        // SO_REUSEPORT lets a second listener share the port
+        drop(options.listener(addr).unwrap());
+
+        let source = "127.0.0.1:13325".parse().unwrap();
+        // SO_REUSEADDR in case the last run left the source port in TIME_WAIT
+        let outbound = options.clone().reuse_address(true).bind_addr(source);
+        let client = outbound.connect(addr).unwrap();
+        assert_eq!(client.local_addr().unwrap(), source);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let accepted = loop {
+            match listener.accept() {
+                Ok((stream, peer_addr)) => {
+                    assert_eq!(peer_addr, source);
+                    break stream;
+                }
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(5)),
+                Err(e) => panic!("{}", e),
+            }
+        };
+        options.configure(&accepted).unwrap();
+
+        for socket in &[SockRef::from(&client), SockRef::from(&accepted)] {
+            assert!(socket.nodelay().unwrap());
+            assert!(socket.keepalive().unwrap());
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+            assert_eq!(socket.keepalive_retries().unwrap(), 3);
+            assert_eq!(socket.linger().unwrap(), Some(Duration::from_secs(1)));
+            assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        }
+    }
+}
@@ -1,12 +1,155 @@
+use bytes::Bytes;
 use failure::Error;
-use mio_framed::new_simple;
+use mesh_msg::{PtyClient, PtyResponse, Service, ServiceContext, ServiceHost, WindowSize};
+use mio_framed::{new_simple, Backoff, ConnectOptions, Core, Ready};
 
 use crossbeam::thread;
-use linefeed::{Interface, ReadResult};
+use linefeed::terminal::{RawRead, Size, Terminal};
+use linefeed::{DefaultTerminal, Interface, ReadResult};
 
+use std::cell::Cell;
+use std::env;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
+
+const STDIN: usize = 0;
+const TIMER_RESIZE: usize = 0;
+// Resizes are only noticed when the terminal is read, so read it now and then
+const RESIZE_CHECK: Duration = Duration::from_millis(250);
+
+fn window_size(size: Size) -> WindowSize {
+    WindowSize {
+        rows: size.lines as u16,
+        cols: size.columns as u16,
+    }
+}
+
+// Bridges the local terminal, already in raw mode, to a remote pty session
+struct Shell {
+    terminal: Arc<DefaultTerminal>,
+    pty: PtyClient,
+    program: String,
+    session: Option<(usize, u64)>,
+    exit: Rc<Cell<Option<i32>>>,
+}
+
+impl Shell {
+    fn read_terminal(&mut self, ctx: &ServiceContext) {
+        let (conn, id) = match self.session {
+            Some(session) => session,
+            None => return,
+        };
+        let mut buf = vec![];
+        loop {
+            buf.clear();
+            match self.terminal.lock_read().read(&mut buf) {
+                Ok(RawRead::Bytes(0)) => break,
+                Ok(RawRead::Bytes(_)) => {
+                    let _ = self.pty.input(ctx, conn, id, &buf);
+                }
+                Ok(RawRead::Resize(size)) => {
+                    let _ = self.pty.resize(ctx, conn, id, window_size(size));
+                }
+                Ok(RawRead::Signal(_)) => {}
+                Err(_) => {
+                    let _ = self.pty.close(ctx, conn, id);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Service for Shell {
+    fn handle_connect(&mut self, ctx: &mut ServiceContext, conn: usize) {
+        if self.session.is_some() {
+            return;
+        }
+        let size = match self.terminal.lock_write().size() {
+            Ok(size) => window_size(size),
+            Err(_) => WindowSize { rows: 24, cols: 80 },
+        };
+        let term = env::var("TERM").ok();
+        let program = &self.program;
+        match self
+            .pty
+            .open(ctx, conn, program, &[], term.as_deref(), size)
+        {
+            Ok(id) => self.session = Some((conn, id)),
+            Err(_) => self.exit.set(Some(1)),
+        }
+        ctx.watch_fd(STDIN, io::stdin().as_raw_fd(), Ready::readable());
+        ctx.set_timeout(TIMER_RESIZE, RESIZE_CHECK);
+    }
+    fn handle_close(&mut self, _ctx: &mut ServiceContext, conn: usize) {
+        if self.session.is_some_and(|(session, _)| session == conn) {
+            self.exit.set(Some(1));
+        }
+    }
+    fn handle_frames(&mut self, _ctx: &mut ServiceContext, _conn: usize, frames: Vec<Bytes>) {
+        let mut stdout = io::stdout();
+        for frame in frames {
+            match PtyClient::decode(&frame) {
+                Ok(PtyResponse::Output { data, .. }) => {
+                    let _ = stdout.write_all(&data);
+                }
+                Ok(PtyResponse::Exited { status, .. }) => {
+                    self.exit.set(Some(status.code.unwrap_or(1)));
+                }
+                Ok(PtyResponse::Error { message, .. }) => {
+                    eprint!("{}\r\n", message);
+                    self.exit.set(Some(1));
+                }
+                _ => {}
+            }
+        }
+        let _ = stdout.flush();
+    }
+    fn handle_timeout(&mut self, ctx: &mut ServiceContext, _token: usize) {
+        self.read_terminal(ctx);
+        ctx.set_timeout(TIMER_RESIZE, RESIZE_CHECK);
+    }
+    fn handle_ready(&mut self, ctx: &mut ServiceContext, _token: usize, _readiness: Ready) {
+        self.read_terminal(ctx);
+    }
+}
+
+// Attach to a shell on the node at `addr`, like a small ssh
+fn shell(addr: &str, program: &str) -> Result<i32, Error> {
+    let terminal = Arc::new(DefaultTerminal::new()?);
+    let exit = Rc::new(Cell::new(None));
+    let shell = Shell {
+        terminal: terminal.clone(),
+        pty: PtyClient::new(),
+        program: program.into(),
+        session: None,
+        exit: exit.clone(),
+    };
+    let mut core = Core::new(ServiceHost::new().client("pty", shell));
+    core.connect(addr)?;
+
+    // Raw mode with signals off, so ^C and friends go to the remote side
+    let state = terminal.lock_read().prepare(true, Default::default())?;
+    let mut result = Ok(());
+    while exit.get().is_none() && result.is_ok() {
+        result = core.turn(None);
+    }
+    terminal.lock_read().restore(state)?;
+    result?;
+    Ok(exit.get().unwrap_or(1))
+}
 
 fn main() -> Result<(), Error> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("shell") {
+        let addr = args.get(1).map_or("127.0.0.1:13266", String::as_str);
+        let program = args.get(2).map_or("sh", String::as_str);
+        std::process::exit(shell(addr, program)?);
+    }
+
     thread::scope(|s| {
         let reader = Arc::new(Interface::new("my-application").unwrap());
 
@@ -15,12 +158,15 @@ fn main() -> Result<(), Error> {
         let iface = reader.clone();
 
         let mut core = new_simple(move |_ctx, _id, frames| {
-            if frames.len() > 0 {
+            if !frames.is_empty() {
                 writeln!(iface, "{:?}", frames).unwrap();
             }
         });
 
-        let idx = core.connect("127.0.0.1:13265").unwrap();
+        let options = ConnectOptions::new()
+            .persistent(Backoff::default())
+            .queue_while_down(1024);
+        let idx = core.connect_with("127.0.0.1:13265", options).unwrap();
         let mut write_handle = core.write_handle(idx);
 
         write_handle.write_frame("Hello");
@@ -1,21 +1,38 @@
 use bytes::{Bytes, IntoBuf};
+use mesh_msg::{PtyService, ServiceHost};
 use mio_framed::{App, Context, Core};
+use std::env;
 use std::io;
+use std::process;
 
 struct BroadcastServer {}
 
 impl App for BroadcastServer {
     fn handle_frames(&mut self, ctx: &Context, _id: usize, frames: Vec<Bytes>) {
         for frame in frames.into_iter() {
-            let msg = frame.into_buf();
-            for conn in ctx.connection_ids() {
-                ctx.write_frame(conn, msg.clone());
-            }
+            ctx.broadcast(frame.into_buf());
         }
     }
 }
 
 fn main() -> io::Result<()> {
+    // Serve shells for `client shell` instead. Anyone who can reach the port
+    // gets one, so it has to be asked for by name.
+    if env::args().nth(1).as_deref() == Some("pty") {
+        if env::args().nth(2).as_deref() != Some("--unauthenticated") {
+            eprintln!(
+                "server pty hands a shell to anyone who can connect, with no authentication."
+            );
+            eprintln!("Run `server pty --unauthenticated` if that is really what you want.");
+            process::exit(2);
+        }
+        eprintln!("WARNING: serving UNAUTHENTICATED shells on 127.0.0.1:13266");
+        let pty = PtyService::new().allow("sh").allow("bash");
+        let mut core = Core::new(ServiceHost::new().offer("pty", pty));
+        let _ = core.listen("127.0.0.1:13266");
+        return core.run();
+    }
+
     let mut core = Core::new(BroadcastServer {});
     let _ = core.listen("127.0.0.1:13265");
 
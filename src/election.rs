@@ -0,0 +1,668 @@
+use bytes::Bytes;
+use mio_framed::{App, Context, Core};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeSet, HashSet};
+use std::time::Duration;
+
+use crate::transport::{timer_kind, timer_token, Transport, TIMER_APP};
+use crate::NodeId;
+
+const TIMER_TICK: usize = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ElectionMessage {
+    RequestVote { term: u64 },
+    Vote { term: u64, granted: bool },
+    Heartbeat { term: u64 },
+    HeartbeatAck { term: u64 },
+}
+
+impl ElectionMessage {
+    fn term(&self) -> u64 {
+        match self {
+            ElectionMessage::RequestVote { term }
+            | ElectionMessage::Vote { term, .. }
+            | ElectionMessage::Heartbeat { term }
+            | ElectionMessage::HeartbeatAck { term } => *term,
+        }
+    }
+}
+
+// Terms, votes and timeouts, shared by Elector and raft's Replica. What
+// counts as a quorum is up to them, raft's voters change over time.
+pub(crate) struct Campaign {
+    pub node: NodeId,
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    pub role: Role,
+    pub leader: Option<NodeId>,
+    pub election_ticks: u32,
+    // Ticks since the leader or a candidate we voted for was heard from
+    pub elapsed: u32,
+    timeout: u32,
+    votes: HashSet<NodeId>,
+    // Followers that answered this election timeout, a leader without a
+    // quorum of them steps down
+    pub heard_from: HashSet<NodeId>,
+    rng: SmallRng,
+}
+
+impl Campaign {
+    pub fn new(node: NodeId) -> Self {
+        let mut campaign = Campaign {
+            node,
+            term: 0,
+            voted_for: None,
+            role: Role::Follower,
+            leader: None,
+            election_ticks: 10,
+            elapsed: 0,
+            timeout: 0,
+            votes: HashSet::new(),
+            heard_from: HashSet::new(),
+            rng: SmallRng::seed_from_u64(node.0),
+        };
+        campaign.reset_timeout();
+        campaign
+    }
+
+    pub fn set_election_ticks(&mut self, ticks: u32) {
+        self.election_ticks = ticks.max(2);
+        self.reset_timeout();
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = SmallRng::seed_from_u64(seed);
+        self.reset_timeout();
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn reset_timeout(&mut self) {
+        self.elapsed = 0;
+        self.timeout = self
+            .rng
+            .gen_range(self.election_ticks, 2 * self.election_ticks);
+    }
+
+    pub fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_timeout();
+    }
+
+    pub fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.node);
+        self.elapsed = 0;
+        self.heard_from.clear();
+    }
+
+    // Starts a new term voting for ourselves, true if that alone is a quorum
+    pub fn stand<Q: Fn(&HashSet<NodeId>) -> bool>(&mut self, quorum: Q) -> bool {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.node);
+        self.votes.clear();
+        self.votes.insert(self.node);
+        self.reset_timeout();
+        quorum(&self.votes)
+    }
+
+    // Followers vote for the first candidate of a term that asks, as long as
+    // it is `eligible`
+    pub fn grant(&mut self, candidate: NodeId, eligible: bool) -> bool {
+        let granted = self.role == Role::Follower
+            && self.voted_for.is_none_or(|voted| voted == candidate)
+            && eligible;
+        if granted {
+            self.voted_for = Some(candidate);
+            self.reset_timeout();
+        }
+        granted
+    }
+
+    // True once the votes make a quorum
+    pub fn count_vote<Q: Fn(&HashSet<NodeId>) -> bool>(
+        &mut self,
+        from: NodeId,
+        granted: bool,
+        quorum: Q,
+    ) -> bool {
+        if self.role != Role::Candidate || !granted {
+            return false;
+        }
+        self.votes.insert(from);
+        quorum(&self.votes)
+    }
+
+    // Counts a tick, true once a follower or candidate should stand
+    pub fn tick(&mut self) -> bool {
+        self.elapsed = self.elapsed.saturating_add(1);
+        self.role != Role::Leader && self.elapsed >= self.timeout
+    }
+
+    // Leaders, every tick. Check quorum once per election timeout, so a
+    // partitioned leader doesn't linger. False if it stepped down.
+    pub fn check_quorum<Q: Fn(&HashSet<NodeId>) -> bool>(&mut self, quorum: Q) -> bool {
+        if self.elapsed < self.election_ticks {
+            return true;
+        }
+        let mut heard_from = std::mem::take(&mut self.heard_from);
+        heard_from.insert(self.node);
+        if !quorum(&heard_from) {
+            self.become_follower(self.term, None);
+            return false;
+        }
+        self.elapsed = 0;
+        true
+    }
+}
+
+// Raft's election without the log, driven by ticks and messages only so it
+// runs the same over Core timers or in a simulation. Messages to send pile
+// up until taken with take_messages.
+pub struct Elector {
+    campaign: Campaign,
+    // Everyone else who votes
+    peers: BTreeSet<NodeId>,
+    outbox: Vec<(NodeId, ElectionMessage)>,
+}
+
+impl Elector {
+    pub fn new(node: NodeId, voters: &[NodeId]) -> Self {
+        let peers = voters
+            .iter()
+            .copied()
+            .filter(|peer| *peer != node)
+            .collect();
+        Elector {
+            campaign: Campaign::new(node),
+            peers,
+            outbox: vec![],
+        }
+    }
+
+    // Followers wait between this and twice this many ticks before standing
+    pub fn election_ticks(mut self, ticks: u32) -> Self {
+        self.campaign.set_election_ticks(ticks);
+        self
+    }
+
+    // Randomized timeouts come from this, the node id by default
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.campaign.seed(seed);
+        self
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.campaign.node
+    }
+    pub fn term(&self) -> u64 {
+        self.campaign.term
+    }
+    pub fn role(&self) -> Role {
+        self.campaign.role
+    }
+    pub fn is_leader(&self) -> bool {
+        self.campaign.is_leader()
+    }
+    pub fn leader(&self) -> Option<NodeId> {
+        self.campaign.leader
+    }
+
+    pub fn take_messages(&mut self) -> Vec<(NodeId, ElectionMessage)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    // Votes and heartbeat answers only ever come from peers, plus ourselves
+    fn quorum_check(&self) -> impl Fn(&HashSet<NodeId>) -> bool {
+        let voters = self.peers.len() + 1;
+        move |nodes| nodes.len() > voters / 2
+    }
+
+    fn broadcast(&mut self, message: ElectionMessage) {
+        for peer in self.peers.iter() {
+            self.outbox.push((*peer, message));
+        }
+    }
+
+    fn become_leader(&mut self) {
+        self.campaign.become_leader();
+        let term = self.campaign.term;
+        self.broadcast(ElectionMessage::Heartbeat { term });
+    }
+
+    fn campaign(&mut self) {
+        let quorum = self.quorum_check();
+        if self.campaign.stand(quorum) {
+            self.become_leader();
+        } else {
+            let term = self.campaign.term;
+            self.broadcast(ElectionMessage::RequestVote { term });
+        }
+    }
+
+    // Call every heartbeat interval
+    pub fn tick(&mut self) {
+        if self.campaign.tick() {
+            self.campaign();
+            return;
+        }
+        if !self.campaign.is_leader() {
+            return;
+        }
+        let term = self.campaign.term;
+        self.broadcast(ElectionMessage::Heartbeat { term });
+        let quorum = self.quorum_check();
+        self.campaign.check_quorum(quorum);
+    }
+
+    pub fn step(&mut self, from: NodeId, message: ElectionMessage) {
+        if !self.peers.contains(&from) {
+            return;
+        }
+        let term = message.term();
+        if term > self.campaign.term {
+            let leader = match message {
+                ElectionMessage::Heartbeat { .. } => Some(from),
+                _ => None,
+            };
+            self.campaign.become_follower(term, leader);
+        }
+        if term < self.campaign.term {
+            // Let stale leaders and candidates know they are behind
+            let term = self.campaign.term;
+            match message {
+                ElectionMessage::RequestVote { .. } => {
+                    let granted = false;
+                    self.outbox
+                        .push((from, ElectionMessage::Vote { term, granted }));
+                }
+                ElectionMessage::Heartbeat { .. } => {
+                    self.outbox
+                        .push((from, ElectionMessage::HeartbeatAck { term }));
+                }
+                _ => {}
+            }
+            return;
+        }
+        match message {
+            ElectionMessage::RequestVote { term } => {
+                let granted = self.campaign.grant(from, true);
+                self.outbox
+                    .push((from, ElectionMessage::Vote { term, granted }));
+            }
+            ElectionMessage::Vote { granted, .. } => {
+                let quorum = self.quorum_check();
+                if self.campaign.count_vote(from, granted, quorum) {
+                    self.become_leader();
+                }
+            }
+            ElectionMessage::Heartbeat { term } => {
+                if self.campaign.role != Role::Follower || self.campaign.leader != Some(from) {
+                    self.campaign.become_follower(term, Some(from));
+                }
+                self.campaign.elapsed = 0;
+                self.outbox
+                    .push((from, ElectionMessage::HeartbeatAck { term }));
+            }
+            ElectionMessage::HeartbeatAck { .. } => {
+                if self.campaign.is_leader() {
+                    self.campaign.heard_from.insert(from);
+                }
+            }
+        }
+    }
+}
+
+pub trait ElectionApp {
+    fn handle_init(&mut self, _ctx: &mut ElectionContext) {}
+    fn handle_connect(&mut self, _ctx: &mut ElectionContext, _id: usize) {}
+    fn handle_accept(&mut self, _ctx: &mut ElectionContext, _listen_socket: usize, _id: usize) {}
+    fn handle_close(&mut self, _ctx: &mut ElectionContext, _id: usize) {}
+    // The leader this node knows of changed, None while an election runs
+    fn handle_leader_change(&mut self, _ctx: &mut ElectionContext, _leader: Option<NodeId>) {}
+    fn handle_frames(&mut self, _ctx: &mut ElectionContext, _id: usize, _frames: Vec<Bytes>) {}
+    fn handle_timeout(&mut self, _ctx: &mut ElectionContext, _token: usize) {}
+    fn handle_shutdown(&mut self) {}
+}
+
+pub struct ElectionContext<'a> {
+    ctx: &'a Context,
+    state: &'a mut ElectionState,
+}
+
+impl<'a> ElectionContext<'a> {
+    pub fn context(&self) -> &Context {
+        self.ctx
+    }
+    pub fn node_id(&self) -> NodeId {
+        self.state.elector.node_id()
+    }
+    pub fn is_leader(&self) -> bool {
+        self.state.elector.is_leader()
+    }
+    pub fn leader(&self) -> Option<NodeId> {
+        self.state.elector.leader()
+    }
+    pub fn term(&self) -> u64 {
+        self.state.elector.term()
+    }
+    // The node on the other end of a connection, once its Hello arrived
+    pub fn node_of(&self, id: usize) -> Option<NodeId> {
+        self.state.transport.node_of(id)
+    }
+    pub fn send<B: AsRef<[u8]>>(&self, id: usize, frame: B) {
+        self.state.transport.send_app(self.ctx, id, frame.as_ref());
+    }
+    pub fn set_timeout(&self, token: usize, delay: Duration) {
+        self.ctx.set_timeout(timer_token(TIMER_APP, token), delay);
+    }
+    pub fn cancel_timeout(&self, token: usize) {
+        self.ctx.cancel_timeout(timer_token(TIMER_APP, token));
+    }
+}
+
+struct ElectionState {
+    elector: Elector,
+    tick_interval: Duration,
+    transport: Transport,
+    // What the app was last told
+    reported: Option<NodeId>,
+}
+
+impl ElectionState {
+    fn flush(&mut self, ctx: &Context) {
+        for (to, message) in self.elector.take_messages() {
+            self.transport.send_to(ctx, to, &message);
+        }
+    }
+}
+
+pub struct Election<A: ElectionApp> {
+    app: A,
+    state: ElectionState,
+}
+
+pub fn new_election<A: ElectionApp>(node: NodeId, voters: &[NodeId], app: A) -> Core<Election<A>> {
+    Core::new(Election::new(node, voters, app))
+}
+
+impl<A: ElectionApp> Election<A> {
+    // `voters` is the whole cluster, this node included
+    pub fn new(node: NodeId, voters: &[NodeId], app: A) -> Self {
+        let state = ElectionState {
+            elector: Elector::new(node, voters),
+            tick_interval: Duration::from_millis(100),
+            transport: Transport::new(node),
+            reported: None,
+        };
+        Election { app, state }
+    }
+
+    // Leaders send heartbeats every tick
+    pub fn tick_interval(mut self, interval: Duration) -> Self {
+        self.state.tick_interval = interval;
+        self
+    }
+
+    pub fn election_ticks(mut self, ticks: u32) -> Self {
+        self.state.elector = self.state.elector.election_ticks(ticks);
+        self
+    }
+
+    fn wrap_context<'a>(&'a mut self, ctx: &'a Context) -> (&'a mut A, ElectionContext<'a>) {
+        let ctx = ElectionContext {
+            ctx,
+            state: &mut self.state,
+        };
+        (&mut self.app, ctx)
+    }
+
+    // Send what the elector produced and tell the app about a new leader
+    fn settle(&mut self, ctx: &Context) {
+        self.state.flush(ctx);
+        let leader = self.state.elector.leader();
+        if leader != self.state.reported {
+            self.state.reported = leader;
+            let (app, mut ctx) = self.wrap_context(ctx);
+            app.handle_leader_change(&mut ctx, leader);
+        }
+    }
+}
+
+impl<A: ElectionApp> App for Election<A> {
+    fn handle_init(&mut self, ctx: &Context) {
+        ctx.set_timeout(timer_token(TIMER_TICK, 0), self.state.tick_interval);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_init(&mut ctx);
+    }
+    fn handle_connect(&mut self, ctx: &Context, id: usize) {
+        self.state.transport.hello(ctx, id);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_connect(&mut ctx, id);
+    }
+    fn handle_accept(&mut self, ctx: &Context, listen_socket: usize, id: usize) {
+        self.state.transport.hello(ctx, id);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_accept(&mut ctx, listen_socket, id);
+    }
+    fn handle_close(&mut self, ctx: &Context, id: usize) {
+        self.state.transport.closed(id);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_close(&mut ctx, id);
+    }
+    fn handle_frames(&mut self, ctx: &Context, id: usize, frames: Vec<Bytes>) {
+        let (messages, delivered) = self.state.transport.receive(id, frames);
+        for (node, message) in messages {
+            self.state.elector.step(node, message);
+        }
+        self.settle(ctx);
+        if !delivered.is_empty() {
+            let (app, mut ctx) = self.wrap_context(ctx);
+            app.handle_frames(&mut ctx, id, delivered);
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &Context, token: usize) {
+        match timer_kind(token) {
+            (TIMER_TICK, _) => {
+                self.state.elector.tick();
+                self.settle(ctx);
+                ctx.set_timeout(timer_token(TIMER_TICK, 0), self.state.tick_interval);
+            }
+            (_, token) => {
+                let (app, mut ctx) = self.wrap_context(ctx);
+                app.handle_timeout(&mut ctx, token);
+            }
+        }
+    }
+    fn handle_shutdown(&mut self) {
+        self.app.handle_shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, HashMap, VecDeque};
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    // Delivers every message right away unless the link is cut
+    struct Network {
+        nodes: BTreeMap<NodeId, Elector>,
+        cut: HashSet<(NodeId, NodeId)>,
+        // Every (term, leader) ever seen, for the one-leader-per-term check
+        leaders: HashMap<u64, NodeId>,
+    }
+
+    impl Network {
+        fn new(size: u64) -> Self {
+            let voters: Vec<NodeId> = (1..=size).map(NodeId).collect();
+            let nodes = voters
+                .iter()
+                .map(|node| (*node, Elector::new(*node, &voters).election_ticks(5)))
+                .collect();
+            Network {
+                nodes,
+                cut: HashSet::new(),
+                leaders: HashMap::new(),
+            }
+        }
+
+        fn isolate(&mut self, node: NodeId) {
+            for other in self.nodes.keys() {
+                self.cut.insert((node, *other));
+                self.cut.insert((*other, node));
+            }
+        }
+
+        fn run(&mut self, ticks: usize) {
+            for _ in 0..ticks {
+                let mut queue = VecDeque::new();
+                for (node, elector) in self.nodes.iter_mut() {
+                    elector.tick();
+                    queue.extend(elector.take_messages().into_iter().map(|m| (*node, m)));
+                }
+                while let Some((from, (to, message))) = queue.pop_front() {
+                    if self.cut.contains(&(from, to)) {
+                        continue;
+                    }
+                    let elector = self.nodes.get_mut(&to).unwrap();
+                    elector.step(from, message);
+                    queue.extend(elector.take_messages().into_iter().map(|m| (to, m)));
+                }
+                for elector in self.nodes.values().filter(|elector| elector.is_leader()) {
+                    let leader = self
+                        .leaders
+                        .entry(elector.term())
+                        .or_insert(elector.node_id());
+                    assert_eq!(*leader, elector.node_id(), "two leaders in one term");
+                }
+            }
+        }
+
+        fn leaders(&self) -> Vec<NodeId> {
+            let leaders = self.nodes.values().filter(|elector| elector.is_leader());
+            leaders.map(Elector::node_id).collect()
+        }
+
+        fn agreed_leader(&self) -> Option<NodeId> {
+            let leader = self.nodes.values().next()?.leader();
+            let agreed = self
+                .nodes
+                .values()
+                .all(|elector| elector.leader() == leader);
+            leader.filter(|_| agreed)
+        }
+    }
+
+    #[test]
+    fn elects_one_leader_across_partitions() {
+        let mut network = Network::new(5);
+        network.run(30);
+        let first = network.agreed_leader().expect("no leader agreed on");
+        assert_eq!(network.leaders(), vec![first]);
+        let first_term = network.nodes[&first].term();
+
+        // The majority side moves on, the old leader notices it lost its quorum
+        network.isolate(first);
+        network.run(30);
+        let second = network.leaders();
+        assert_eq!(second.len(), 1);
+        assert_ne!(second[0], first);
+        assert!(network.nodes[&second[0]].term() > first_term);
+        assert_eq!(network.nodes[&first].leader(), None);
+
+        network.cut.clear();
+        network.run(30);
+        let healed = network.agreed_leader().expect("no leader after healing");
+        assert_eq!(network.leaders(), vec![healed]);
+    }
+
+    // Same again, but a two node minority can never elect anyone
+    #[test]
+    fn minority_has_no_leader() {
+        let mut network = Network::new(5);
+        for (a, b) in [(1, 3), (1, 4), (1, 5), (2, 3), (2, 4), (2, 5)].iter() {
+            network.cut.insert((NodeId(*a), NodeId(*b)));
+            network.cut.insert((NodeId(*b), NodeId(*a)));
+        }
+        network.run(60);
+        let leaders = network.leaders();
+        assert_eq!(leaders.len(), 1);
+        assert!(leaders[0].0 >= 3);
+        assert_eq!(network.nodes[&NodeId(1)].leader(), None);
+        assert_eq!(network.nodes[&NodeId(2)].leader(), None);
+    }
+
+    type Seen = Rc<RefCell<Vec<Option<NodeId>>>>;
+
+    struct Watcher {
+        seen: Seen,
+    }
+
+    impl ElectionApp for Watcher {
+        fn handle_leader_change(&mut self, ctx: &mut ElectionContext, leader: Option<NodeId>) {
+            assert_eq!(ctx.is_leader(), leader == Some(ctx.node_id()));
+            self.seen.borrow_mut().push(leader);
+        }
+    }
+
+    #[test]
+    fn elects_over_connections() {
+        let (addr_a, addr_b) = ("127.0.0.1:13319", "127.0.0.1:13320");
+        let voters = [NodeId(1), NodeId(2), NodeId(3)];
+        let mut cores = vec![];
+        let mut seen = vec![];
+        for node in voters.iter() {
+            let watcher = Watcher {
+                seen: Rc::new(RefCell::new(vec![])),
+            };
+            seen.push(watcher.seen.clone());
+            let election = Election::new(*node, &voters, watcher)
+                .tick_interval(Duration::from_millis(10))
+                .election_ticks(5);
+            cores.push(Core::new(election));
+        }
+        cores[0].listen(addr_a).unwrap();
+        cores[1].listen(addr_b).unwrap();
+        cores[1].connect(addr_a).unwrap();
+        cores[2].connect(addr_a).unwrap();
+        cores[2].connect(addr_b).unwrap();
+
+        let current = |seen: &Seen| seen.borrow().last().copied().flatten();
+        let agreed = || {
+            let leader = current(&seen[0]);
+            leader.is_some() && seen.iter().all(|seen| current(seen) == leader)
+        };
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !agreed() && Instant::now() < deadline {
+            for core in cores.iter_mut() {
+                core.turn(tick).unwrap();
+            }
+        }
+        assert!(agreed());
+    }
+}
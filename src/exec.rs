@@ -0,0 +1,692 @@
+use bytes::Bytes;
+use failure::{bail, format_err, Error};
+use mio_framed::{Ready, UnixReady};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::service::{Service, ServiceContext};
+
+const CHUNK_SIZE: usize = 32 * 1024;
+const TIMER_REAP: usize = 0;
+
+// Fd tokens are the process slot with the stream in the low bits
+const STREAM_BITS: usize = 2;
+const STDOUT: usize = 0;
+const STDERR: usize = 1;
+const STDIN: usize = 2;
+
+fn fd_token(slot: usize, stream: usize) -> usize {
+    slot << STREAM_BITS | stream
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Signal {
+    Interrupt,
+    Terminate,
+    Kill,
+}
+
+impl Signal {
+    fn number(self) -> libc::c_int {
+        match self {
+            Signal::Interrupt => libc::SIGINT,
+            Signal::Terminate => libc::SIGTERM,
+            Signal::Kill => libc::SIGKILL,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ExecRequest {
+    // `program` must be on the service's allowlist, it is looked up in PATH
+    Spawn {
+        id: u64,
+        program: String,
+        args: Vec<String>,
+        timeout_ms: Option<u64>,
+    },
+    Stdin {
+        id: u64,
+        data: Vec<u8>,
+    },
+    // Closes stdin once everything queued before it was written
+    CloseStdin {
+        id: u64,
+    },
+    Signal {
+        id: u64,
+        signal: Signal,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExitStatus {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    // Killed for running past its timeout
+    pub timed_out: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ExecResponse {
+    Started { id: u64, pid: u32 },
+    Stdout { id: u64, data: Vec<u8> },
+    Stderr { id: u64, data: Vec<u8> },
+    // Always the last response for a process, after all of its output
+    Exited { id: u64, status: ExitStatus },
+    Error { id: u64, message: String },
+}
+
+impl ExecResponse {
+    pub fn id(&self) -> u64 {
+        match self {
+            ExecResponse::Started { id, .. }
+            | ExecResponse::Stdout { id, .. }
+            | ExecResponse::Stderr { id, .. }
+            | ExecResponse::Exited { id, .. }
+            | ExecResponse::Error { id, .. } => *id,
+        }
+    }
+}
+
+fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    bincode::serialize(message).expect("Exec message serialization can't fail")
+}
+
+pub(crate) fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+struct Process {
+    conn: usize,
+    id: u64,
+    child: Child,
+    stdout: Option<ChildStdout>,
+    stderr: Option<ChildStderr>,
+    stdin: Option<ChildStdin>,
+    stdin_queue: Vec<u8>,
+    close_stdin: bool,
+    deadline: Option<Instant>,
+    timed_out: bool,
+    status: Option<std::process::ExitStatus>,
+}
+
+impl Process {
+    // Core closes each pipe once it's no longer watched
+    fn close_pipes(&mut self, ctx: &ServiceContext, slot: usize) {
+        if let Some(stdout) = self.stdout.take() {
+            ctx.close_fd(fd_token(slot, STDOUT), stdout);
+        }
+        if let Some(stderr) = self.stderr.take() {
+            ctx.close_fd(fd_token(slot, STDERR), stderr);
+        }
+        if let Some(stdin) = self.stdin.take() {
+            ctx.close_fd(fd_token(slot, STDIN), stdin);
+        }
+    }
+
+    fn kill(&mut self) {
+        let _ = self.child.kill();
+        // Reaped right away so nothing is left as a zombie
+        let _ = self.child.wait();
+    }
+}
+
+// Runs allowlisted programs for peers, streaming their stdio as frames.
+// Pipes are non-blocking and watched by the Core's Poll, exits are noticed
+// by polling on a timer.
+pub struct ExecService {
+    allowed: HashSet<String>,
+    max_runtime: Option<Duration>,
+    poll_interval: Duration,
+    next_slot: usize,
+    processes: HashMap<usize, Process>,
+    by_request: HashMap<(usize, u64), usize>,
+    reaping: bool,
+}
+
+impl Default for ExecService {
+    fn default() -> Self {
+        ExecService {
+            allowed: HashSet::new(),
+            max_runtime: None,
+            poll_interval: Duration::from_millis(50),
+            next_slot: 0,
+            processes: HashMap::new(),
+            by_request: HashMap::new(),
+            reaping: false,
+        }
+    }
+}
+
+impl ExecService {
+    pub fn new() -> Self {
+        ExecService::default()
+    }
+
+    pub fn allow(mut self, program: &str) -> Self {
+        self.allowed.insert(program.into());
+        self
+    }
+
+    // Upper bound on how long any process may run, requests can only shorten it
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.max_runtime = Some(timeout);
+        self
+    }
+
+    // How often running processes are checked for exits and timeouts
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    fn send(ctx: &ServiceContext, conn: usize, response: &ExecResponse) {
+        // The peer may not run an exec client, then there's nobody to tell
+        let _ = ctx.send(conn, encode(response));
+    }
+
+    fn handle_request(&mut self, ctx: &ServiceContext, conn: usize, request: ExecRequest) {
+        let id = match &request {
+            ExecRequest::Spawn { id, .. }
+            | ExecRequest::Stdin { id, .. }
+            | ExecRequest::CloseStdin { id }
+            | ExecRequest::Signal { id, .. } => *id,
+        };
+        if let Err(e) = self.run(ctx, conn, request) {
+            let message = e.to_string();
+            Self::send(ctx, conn, &ExecResponse::Error { id, message });
+        }
+    }
+
+    fn run(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        request: ExecRequest,
+    ) -> Result<(), Error> {
+        match request {
+            ExecRequest::Spawn {
+                id,
+                program,
+                args,
+                timeout_ms,
+            } => self.spawn(ctx, conn, id, program, args, timeout_ms),
+            ExecRequest::Stdin { id, data } => {
+                let slot = self.slot(conn, id)?;
+                let process = self.processes.get_mut(&slot).expect("Slot without process");
+                if process.stdin.is_none() || process.close_stdin {
+                    bail!("Stdin of {} is closed", id);
+                }
+                process.stdin_queue.extend_from_slice(&data);
+                self.flush_stdin(ctx, slot);
+                Ok(())
+            }
+            ExecRequest::CloseStdin { id } => {
+                let slot = self.slot(conn, id)?;
+                if let Some(process) = self.processes.get_mut(&slot) {
+                    process.close_stdin = true;
+                }
+                self.flush_stdin(ctx, slot);
+                Ok(())
+            }
+            ExecRequest::Signal { id, signal } => {
+                let slot = self.slot(conn, id)?;
+                let process = &self.processes[&slot];
+                if process.status.is_none() {
+                    let pid = process.child.id() as libc::pid_t;
+                    if unsafe { libc::kill(pid, signal.number()) } < 0 {
+                        return Err(io::Error::last_os_error().into());
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn slot(&self, conn: usize, id: u64) -> Result<usize, Error> {
+        self.by_request
+            .get(&(conn, id))
+            .copied()
+            .ok_or_else(|| format_err!("No running process {}", id))
+    }
+
+    fn spawn(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        id: u64,
+        program: String,
+        args: Vec<String>,
+        timeout_ms: Option<u64>,
+    ) -> Result<(), Error> {
+        if !self.allowed.contains(&program) {
+            bail!("{} is not allowed", program);
+        }
+        if self.by_request.contains_key(&(conn, id)) {
+            bail!("Process {} already exists", id);
+        }
+        let mut child = Command::new(&program)
+            .args(&args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let (stdin, stdout, stderr) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take());
+        let fds = [
+            stdin.as_ref().map(AsRawFd::as_raw_fd),
+            stdout.as_ref().map(AsRawFd::as_raw_fd),
+            stderr.as_ref().map(AsRawFd::as_raw_fd),
+        ];
+        for fd in fds.iter().flatten() {
+            if let Err(e) = set_nonblocking(*fd) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e.into());
+            }
+        }
+
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        let interest = Ready::readable() | UnixReady::hup();
+        if let Some(stdout) = &stdout {
+            ctx.watch_fd(fd_token(slot, STDOUT), stdout.as_raw_fd(), interest);
+        }
+        if let Some(stderr) = &stderr {
+            ctx.watch_fd(fd_token(slot, STDERR), stderr.as_raw_fd(), interest);
+        }
+        let timeout = timeout_ms.map(Duration::from_millis);
+        let runtime = match (timeout, self.max_runtime) {
+            (Some(timeout), Some(max)) => Some(timeout.min(max)),
+            (timeout, max) => timeout.or(max),
+        };
+        let pid = child.id();
+        let process = Process {
+            conn,
+            id,
+            child,
+            stdout,
+            stderr,
+            stdin,
+            stdin_queue: vec![],
+            close_stdin: false,
+            deadline: runtime.map(|runtime| Instant::now() + runtime),
+            timed_out: false,
+            status: None,
+        };
+        self.processes.insert(slot, process);
+        self.by_request.insert((conn, id), slot);
+        Self::send(ctx, conn, &ExecResponse::Started { id, pid });
+        if !self.reaping {
+            self.reaping = true;
+            ctx.set_timeout(TIMER_REAP, self.poll_interval);
+        }
+        Ok(())
+    }
+
+    // Read a pipe until it would block, forwarding everything
+    fn drain(&mut self, ctx: &ServiceContext, slot: usize, stream: usize) {
+        let process = match self.processes.get_mut(&slot) {
+            Some(process) => process,
+            None => return,
+        };
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let read = match stream {
+                STDOUT => process.stdout.as_mut().map(|pipe| pipe.read(&mut buf)),
+                _ => process.stderr.as_mut().map(|pipe| pipe.read(&mut buf)),
+            };
+            match read {
+                Some(Ok(n)) if n > 0 => {
+                    let (id, data) = (process.id, buf[..n].to_vec());
+                    let response = match stream {
+                        STDOUT => ExecResponse::Stdout { id, data },
+                        _ => ExecResponse::Stderr { id, data },
+                    };
+                    Self::send(ctx, process.conn, &response);
+                }
+                Some(Err(ref e)) if e.kind() == ErrorKind::WouldBlock => break,
+                Some(Err(ref e)) if e.kind() == ErrorKind::Interrupted => continue,
+                None => break,
+                // End of file or a broken pipe
+                Some(_) => {
+                    let token = fd_token(slot, stream);
+                    if stream == STDOUT {
+                        if let Some(stdout) = process.stdout.take() {
+                            ctx.close_fd(token, stdout);
+                        }
+                    } else if let Some(stderr) = process.stderr.take() {
+                        ctx.close_fd(token, stderr);
+                    }
+                    break;
+                }
+            }
+        }
+        self.check_finished(ctx, slot);
+    }
+
+    fn flush_stdin(&mut self, ctx: &ServiceContext, slot: usize) {
+        let process = match self.processes.get_mut(&slot) {
+            Some(process) => process,
+            None => return,
+        };
+        let stdin = match process.stdin.as_mut() {
+            Some(stdin) => stdin,
+            None => return,
+        };
+        let mut broken = false;
+        while !process.stdin_queue.is_empty() {
+            match stdin.write(&process.stdin_queue) {
+                Ok(n) => {
+                    process.stdin_queue.drain(..n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    broken = true;
+                    break;
+                }
+            }
+        }
+        let token = fd_token(slot, STDIN);
+        if broken || (process.stdin_queue.is_empty() && process.close_stdin) {
+            if let Some(stdin) = process.stdin.take() {
+                ctx.close_fd(token, stdin);
+            }
+            process.stdin_queue.clear();
+        } else if process.stdin_queue.is_empty() {
+            ctx.unwatch_fd(token);
+        } else {
+            ctx.watch_fd(token, stdin.as_raw_fd(), Ready::writable());
+        }
+    }
+
+    // Exited is only sent once the output is drained and the process reaped
+    fn check_finished(&mut self, ctx: &ServiceContext, slot: usize) {
+        let process = match self.processes.get_mut(&slot) {
+            Some(process) => process,
+            None => return,
+        };
+        if process.stdout.is_some() || process.stderr.is_some() {
+            // XXX A grandchild holding the pipes open keeps us waiting here
+            return;
+        }
+        if process.status.is_none() {
+            process.status = process.child.try_wait().ok().and_then(|status| status);
+        }
+        let status = match process.status {
+            Some(status) => status,
+            None => return,
+        };
+        let status = ExitStatus {
+            code: status.code(),
+            signal: status.signal(),
+            timed_out: process.timed_out,
+        };
+        let (conn, id) = (process.conn, process.id);
+        process.close_pipes(ctx, slot);
+        self.processes.remove(&slot);
+        self.by_request.remove(&(conn, id));
+        Self::send(ctx, conn, &ExecResponse::Exited { id, status });
+    }
+
+    fn reap(&mut self, ctx: &ServiceContext) {
+        let now = Instant::now();
+        let slots: Vec<usize> = self.processes.keys().copied().collect();
+        for slot in slots {
+            if let Some(process) = self.processes.get_mut(&slot) {
+                if process.deadline.is_some_and(|deadline| now >= deadline) && !process.timed_out {
+                    process.timed_out = true;
+                    let _ = process.child.kill();
+                }
+                if process.status.is_none() {
+                    process.status = process.child.try_wait().ok().and_then(|status| status);
+                }
+            }
+            self.check_finished(ctx, slot);
+        }
+        self.reaping = !self.processes.is_empty();
+        if self.reaping {
+            ctx.set_timeout(TIMER_REAP, self.poll_interval);
+        }
+    }
+}
+
+impl Service for ExecService {
+    fn handle_close(&mut self, ctx: &mut ServiceContext, conn: usize) {
+        let slots: Vec<usize> = self
+            .processes
+            .iter()
+            .filter(|(_, process)| process.conn == conn)
+            .map(|(slot, _)| *slot)
+            .collect();
+        for slot in slots {
+            if let Some(mut process) = self.processes.remove(&slot) {
+                self.by_request.remove(&(conn, process.id));
+                process.close_pipes(ctx, slot);
+                process.kill();
+            }
+        }
+    }
+    fn handle_frames(&mut self, ctx: &mut ServiceContext, conn: usize, frames: Vec<Bytes>) {
+        for frame in frames {
+            match bincode::deserialize(&frame) {
+                Ok(request) => self.handle_request(ctx, conn, request),
+                // XXX app.handle_decode_error?
+                Err(_e) => continue,
+            }
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &mut ServiceContext, _token: usize) {
+        self.reap(ctx);
+    }
+    fn handle_ready(&mut self, ctx: &mut ServiceContext, token: usize, _readiness: Ready) {
+        let slot = token >> STREAM_BITS;
+        match token & ((1 << STREAM_BITS) - 1) {
+            STDIN => self.flush_stdin(ctx, slot),
+            stream => self.drain(ctx, slot, stream),
+        }
+    }
+    fn handle_shutdown(&mut self) {
+        for process in self.processes.values_mut() {
+            process.kill();
+        }
+    }
+}
+
+// Builds requests for the "exec" service, for use in a client Service
+#[derive(Default)]
+pub struct ExecClient {
+    next_id: u64,
+}
+
+impl ExecClient {
+    pub fn new() -> Self {
+        ExecClient::default()
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<ExecResponse, Error> {
+        Ok(bincode::deserialize(frame)?)
+    }
+
+    fn send(ctx: &ServiceContext, conn: usize, request: &ExecRequest) -> Result<(), Error> {
+        ctx.send_to(conn, "exec", encode(request))
+    }
+
+    pub fn spawn(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        program: &str,
+        args: &[&str],
+        timeout: Option<Duration>,
+    ) -> Result<u64, Error> {
+        self.next_id += 1;
+        let request = ExecRequest::Spawn {
+            id: self.next_id,
+            program: program.into(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            timeout_ms: timeout.map(|timeout| timeout.as_millis() as u64),
+        };
+        Self::send(ctx, conn, &request)?;
+        Ok(self.next_id)
+    }
+    pub fn stdin(
+        &self,
+        ctx: &ServiceContext,
+        conn: usize,
+        id: u64,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let data = data.to_vec();
+        Self::send(ctx, conn, &ExecRequest::Stdin { id, data })
+    }
+    pub fn close_stdin(&self, ctx: &ServiceContext, conn: usize, id: u64) -> Result<(), Error> {
+        Self::send(ctx, conn, &ExecRequest::CloseStdin { id })
+    }
+    pub fn signal(
+        &self,
+        ctx: &ServiceContext,
+        conn: usize,
+        id: u64,
+        signal: Signal,
+    ) -> Result<(), Error> {
+        Self::send(ctx, conn, &ExecRequest::Signal { id, signal })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ServiceHost;
+    use mio_framed::Core;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Responses = Rc<RefCell<Vec<(&'static str, ExecResponse)>>>;
+
+    struct Script {
+        exec: ExecClient,
+        names: HashMap<u64, &'static str>,
+        responses: Responses,
+    }
+
+    impl Service for Script {
+        fn handle_connect(&mut self, ctx: &mut ServiceContext, conn: usize) {
+            let exec = &mut self.exec;
+            let cat = exec.spawn(ctx, conn, "cat", &[], None).unwrap();
+            exec.stdin(ctx, conn, cat, b"hello ").unwrap();
+            exec.stdin(ctx, conn, cat, b"world").unwrap();
+            exec.close_stdin(ctx, conn, cat).unwrap();
+            let slow = Some(Duration::from_millis(50));
+            let spawned = [
+                ("cat", cat),
+                (
+                    "timeout",
+                    exec.spawn(ctx, conn, "sleep", &["5"], slow).unwrap(),
+                ),
+                (
+                    "signal",
+                    exec.spawn(ctx, conn, "sleep", &["5"], None).unwrap(),
+                ),
+                (
+                    "denied",
+                    exec.spawn(ctx, conn, "rm", &["-rf", "/"], None).unwrap(),
+                ),
+            ];
+            self.names
+                .extend(spawned.iter().map(|(name, id)| (*id, *name)));
+        }
+        fn handle_frames(&mut self, ctx: &mut ServiceContext, conn: usize, frames: Vec<Bytes>) {
+            for frame in frames {
+                let response = ExecClient::decode(&frame).unwrap();
+                let name = self.names[&response.id()];
+                if let ("signal", ExecResponse::Started { id, .. }) = (name, &response) {
+                    self.exec.signal(ctx, conn, *id, Signal::Terminate).unwrap();
+                }
+                self.responses.borrow_mut().push((name, response));
+            }
+        }
+    }
+
+    fn last(responses: &Responses, name: &str) -> Option<ExecResponse> {
+        let responses = responses.borrow();
+        let found = responses.iter().rev().find(|(n, _)| *n == name);
+        found.map(|(_, response)| response.clone())
+    }
+
+    #[test]
+    fn runs_allowed_programs() {
+        let addr = "127.0.0.1:13312";
+        let responses = Rc::new(RefCell::new(vec![]));
+        let service = ExecService::new()
+            .allow("cat")
+            .allow("sleep")
+            .timeout(Duration::from_secs(10))
+            .poll_interval(Duration::from_millis(5));
+        let mut server = Core::new(ServiceHost::new().offer("exec", service));
+        server.listen(addr).unwrap();
+        let script = Script {
+            exec: ExecClient::new(),
+            names: HashMap::new(),
+            responses: responses.clone(),
+        };
+        let mut client = Core::new(ServiceHost::new().client("exec", script));
+        client.connect(addr).unwrap();
+
+        let done = |responses: &Responses| {
+            let finished = |response: Option<ExecResponse>| {
+                matches!(
+                    response,
+                    Some(ExecResponse::Exited { .. }) | Some(ExecResponse::Error { .. })
+                )
+            };
+            ["cat", "timeout", "signal", "denied"]
+                .iter()
+                .all(|name| finished(last(responses, name)))
+        };
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(&responses) && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+
+        let stdout: Vec<u8> = responses
+            .borrow()
+            .iter()
+            .flat_map(|(_, response)| match response {
+                ExecResponse::Stdout { data, .. } => data.clone(),
+                _ => vec![],
+            })
+            .collect();
+        assert_eq!(stdout, b"hello world");
+        let exited = |name| match last(&responses, name) {
+            Some(ExecResponse::Exited { status, .. }) => status,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(exited("cat").code, Some(0));
+        assert!(exited("timeout").timed_out);
+        assert_eq!(exited("timeout").signal, Some(libc::SIGKILL));
+        assert_eq!(exited("signal").signal, Some(libc::SIGTERM));
+        assert!(!exited("signal").timed_out);
+        assert!(matches!(
+            last(&responses, "denied"),
+            Some(ExecResponse::Error { .. })
+        ));
+    }
+}
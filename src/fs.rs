@@ -0,0 +1,836 @@
+use bytes::Bytes;
+use failure::{bail, format_err, Error};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::service::{Service, ServiceContext};
+
+// Leaves room for the headers in a u16::MAX frame
+pub const CHUNK_SIZE: usize = 32 * 1024;
+// Bytes of a read sent ahead of the reader's Credit
+pub const READ_WINDOW: u64 = 2 * CHUNK_SIZE as u64;
+// Directory listings and watch changes go out in frames of roughly this many bytes
+const BATCH_SIZE: usize = 32 * 1024;
+// Rough bincode overhead of an entry or change besides its name
+const ENTRY_OVERHEAD: usize = 40;
+// Watches stop descending after this many entries
+const WATCH_LIMIT: usize = 10_000;
+const TIMER_WATCH: usize = 0;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileMeta {
+    pub kind: FileKind,
+    pub len: u64,
+    pub modified_ms: u64,
+    pub readonly: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DirEntry {
+    pub name: String,
+    pub meta: FileMeta,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Change {
+    Created(String),
+    Modified(String),
+    Removed(String),
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Created(path) | Change::Modified(path) | Change::Removed(path) => path,
+        }
+    }
+}
+
+// Paths are relative to the served root, "/" separated
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum FsRequest {
+    Stat {
+        id: u64,
+        path: String,
+    },
+    List {
+        id: u64,
+        path: String,
+    },
+    // Answered with Chunks then Done, `len` None reads to the end.
+    // Only READ_WINDOW bytes go out until Credit makes room for more.
+    Read {
+        id: u64,
+        path: String,
+        offset: u64,
+        len: Option<u64>,
+    },
+    // The reader of `id` took `len` bytes, see FsClient::received
+    Credit {
+        id: u64,
+        len: u64,
+    },
+    // Big writes arrive as several requests sharing an id, only the last is answered
+    Write {
+        id: u64,
+        path: String,
+        offset: u64,
+        data: Vec<u8>,
+        truncate: bool,
+        last: bool,
+    },
+    Rename {
+        id: u64,
+        from: String,
+        to: String,
+    },
+    // Files and empty directories only
+    Delete {
+        id: u64,
+        path: String,
+    },
+    // Changes under `path` arrive as Changed until Unwatch
+    Watch {
+        id: u64,
+        path: String,
+    },
+    Unwatch {
+        id: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FsResponse {
+    Stat { id: u64, meta: FileMeta },
+    Entries { id: u64, entries: Vec<DirEntry> },
+    Chunk { id: u64, offset: u64, data: Vec<u8> },
+    Changed { id: u64, changes: Vec<Change> },
+    Done { id: u64 },
+    Error { id: u64, message: String },
+}
+
+impl FsResponse {
+    pub fn id(&self) -> u64 {
+        match self {
+            FsResponse::Stat { id, .. }
+            | FsResponse::Entries { id, .. }
+            | FsResponse::Chunk { id, .. }
+            | FsResponse::Changed { id, .. }
+            | FsResponse::Done { id }
+            | FsResponse::Error { id, .. } => *id,
+        }
+    }
+}
+
+fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    bincode::serialize(message).expect("Fs message serialization can't fail")
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+// Splits `items` so each group's names add up to about BATCH_SIZE
+fn batches<T, F: Fn(&T) -> usize>(items: Vec<T>, name_len: F) -> Vec<Vec<T>> {
+    let (mut batches, mut batch, mut batch_size) = (vec![], vec![], 0);
+    for item in items {
+        let item_size = name_len(&item) + ENTRY_OVERHEAD;
+        if !batch.is_empty() && batch_size + item_size > BATCH_SIZE {
+            batches.push(std::mem::take(&mut batch));
+            batch_size = 0;
+        }
+        batch_size += item_size;
+        batch.push(item);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+fn file_meta(meta: &fs::Metadata) -> FileMeta {
+    let kind = if meta.file_type().is_symlink() {
+        FileKind::Symlink
+    } else if meta.is_dir() {
+        FileKind::Dir
+    } else if meta.is_file() {
+        FileKind::File
+    } else {
+        FileKind::Other
+    };
+    FileMeta {
+        kind,
+        len: meta.len(),
+        modified_ms: meta.modified().map_or(0, unix_ms),
+        readonly: meta.permissions().readonly(),
+    }
+}
+
+struct Reading {
+    file: File,
+    offset: u64,
+    remaining: u64,
+    credit: u64,
+}
+
+impl Reading {
+    // Sends what the credit allows, true once the range is done
+    fn pump<F: FnMut(FsResponse)>(&mut self, id: u64, send: &mut F) -> Result<bool, Error> {
+        while self.remaining > 0 && self.credit > 0 {
+            let len = self.remaining.min(self.credit).min(CHUNK_SIZE as u64);
+            let mut data = vec![0; len as usize];
+            let n = self.file.read(&mut data)?;
+            if n == 0 {
+                return Ok(true);
+            }
+            data.truncate(n);
+            let offset = self.offset;
+            send(FsResponse::Chunk { id, offset, data });
+            self.offset += n as u64;
+            self.remaining -= n as u64;
+            self.credit -= n as u64;
+        }
+        Ok(self.remaining == 0)
+    }
+}
+
+struct Watch {
+    conn: usize,
+    path: PathBuf,
+    seen: HashMap<String, (u64, u64)>,
+}
+
+// Serves the tree under `root` to peers. Nothing outside it can be named,
+// neither through ".." nor through symlinks.
+pub struct FsService {
+    root: PathBuf,
+    watch_interval: Duration,
+    // Keyed by (conn, request id)
+    reads: HashMap<(usize, u64), Reading>,
+    watches: HashMap<(usize, u64), Watch>,
+}
+
+impl FsService {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self, Error> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            bail!("{} is not a directory", root.display());
+        }
+        Ok(FsService {
+            root,
+            watch_interval: Duration::from_secs(1),
+            reads: HashMap::new(),
+            watches: HashMap::new(),
+        })
+    }
+
+    // How often watched trees are rescanned
+    pub fn watch_interval(mut self, interval: Duration) -> Self {
+        self.watch_interval = interval;
+        self
+    }
+
+    // The path under the root, with any symlinks along it left in place
+    fn resolve(&self, path: &str) -> Result<PathBuf, Error> {
+        let outside = || format_err!("{} is outside the served root", path);
+        let mut resolved = self.root.clone();
+        for component in Path::new(path.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => resolved.push(part),
+                Component::CurDir => continue,
+                _ => return Err(outside()),
+            }
+            // Every symlink on the way has to lead somewhere inside. A dangling
+            // one counts as outside, creating a file through it could escape.
+            let is_symlink =
+                fs::symlink_metadata(&resolved).is_ok_and(|meta| meta.file_type().is_symlink());
+            if is_symlink {
+                match resolved.canonicalize() {
+                    Ok(target) if target.starts_with(&self.root) => {}
+                    _ => return Err(outside()),
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    // Opens what `path` names, following a final symlink that resolve checked.
+    // O_NOFOLLOW refuses a symlink swapped in since then.
+    fn open(&self, path: &Path, options: &mut OpenOptions) -> Result<File, Error> {
+        let target = match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_symlink() => path.canonicalize()?,
+            _ => path.to_path_buf(),
+        };
+        if !target.starts_with(&self.root) {
+            bail!("{} is outside the served root", self.relative(path));
+        }
+        Ok(options.custom_flags(libc::O_NOFOLLOW).open(target)?)
+    }
+
+    fn relative(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        relative.to_string_lossy().replace('\\', "/")
+    }
+
+    fn handle_request(&mut self, ctx: &ServiceContext, conn: usize, request: FsRequest) {
+        let id = match &request {
+            FsRequest::Stat { id, .. }
+            | FsRequest::List { id, .. }
+            | FsRequest::Read { id, .. }
+            | FsRequest::Credit { id, .. }
+            | FsRequest::Write { id, .. }
+            | FsRequest::Rename { id, .. }
+            | FsRequest::Delete { id, .. }
+            | FsRequest::Watch { id, .. }
+            | FsRequest::Unwatch { id } => *id,
+        };
+        let mut send = |response: FsResponse| {
+            // The peer may not run an fs client, then there's nobody to tell
+            let _ = ctx.send(conn, encode(&response));
+        };
+        if let Err(e) = self.run(conn, request, &mut send) {
+            send(FsResponse::Error {
+                id,
+                message: e.to_string(),
+            });
+        }
+    }
+
+    fn run<F: FnMut(FsResponse)>(
+        &mut self,
+        conn: usize,
+        request: FsRequest,
+        send: &mut F,
+    ) -> Result<(), Error> {
+        match request {
+            FsRequest::Stat { id, path } => {
+                let meta = file_meta(&fs::symlink_metadata(self.resolve(&path)?)?);
+                send(FsResponse::Stat { id, meta });
+            }
+            FsRequest::List { id, path } => {
+                let mut entries = vec![];
+                for entry in fs::read_dir(self.resolve(&path)?)? {
+                    let entry = entry?;
+                    entries.push(DirEntry {
+                        name: entry.file_name().to_string_lossy().into_owned(),
+                        meta: file_meta(&entry.metadata()?),
+                    });
+                }
+                entries.sort_by(|a, b| a.name.cmp(&b.name));
+                for entries in batches(entries, |entry| entry.name.len()) {
+                    send(FsResponse::Entries { id, entries });
+                }
+                send(FsResponse::Done { id });
+            }
+            FsRequest::Read {
+                id,
+                path,
+                offset,
+                len,
+            } => {
+                let path = self.resolve(&path)?;
+                let mut file = self.open(&path, OpenOptions::new().read(true))?;
+                file.seek(SeekFrom::Start(offset))?;
+                let reading = Reading {
+                    file,
+                    offset,
+                    remaining: len.unwrap_or(u64::MAX),
+                    credit: READ_WINDOW,
+                };
+                self.reads.insert((conn, id), reading);
+                self.pump(conn, id, send)?;
+            }
+            FsRequest::Credit { id, len } => {
+                if let Some(reading) = self.reads.get_mut(&(conn, id)) {
+                    reading.credit = reading.credit.saturating_add(len);
+                    self.pump(conn, id, send)?;
+                }
+            }
+            FsRequest::Write {
+                id,
+                path,
+                offset,
+                data,
+                truncate,
+                last,
+            } => {
+                let path = self.resolve(&path)?;
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut options = OpenOptions::new();
+                options.write(true).create(true).truncate(truncate);
+                let mut file = self.open(&path, &mut options)?;
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&data)?;
+                if last {
+                    send(FsResponse::Done { id });
+                }
+            }
+            FsRequest::Rename { id, from, to } => {
+                fs::rename(self.resolve(&from)?, self.resolve(&to)?)?;
+                send(FsResponse::Done { id });
+            }
+            FsRequest::Delete { id, path } => {
+                let path = self.resolve(&path)?;
+                if path == self.root {
+                    bail!("Can't delete the served root");
+                }
+                if fs::symlink_metadata(&path)?.is_dir() {
+                    fs::remove_dir(path)?;
+                } else {
+                    fs::remove_file(path)?;
+                }
+                send(FsResponse::Done { id });
+            }
+            FsRequest::Watch { id, path } => {
+                let path = self.resolve(&path)?;
+                let seen = self.scan(&path);
+                self.watches.insert((conn, id), Watch { conn, path, seen });
+                send(FsResponse::Done { id });
+            }
+            FsRequest::Unwatch { id } => {
+                self.watches.remove(&(conn, id));
+                send(FsResponse::Done { id });
+            }
+        }
+        Ok(())
+    }
+
+    fn pump<F: FnMut(FsResponse)>(
+        &mut self,
+        conn: usize,
+        id: u64,
+        send: &mut F,
+    ) -> Result<(), Error> {
+        let reading = match self.reads.get_mut(&(conn, id)) {
+            Some(reading) => reading,
+            None => return Ok(()),
+        };
+        let done = reading.pump(id, send);
+        if !matches!(done, Ok(false)) {
+            self.reads.remove(&(conn, id));
+        }
+        if done? {
+            send(FsResponse::Done { id });
+        }
+        Ok(())
+    }
+
+    // (len, mtime) of everything under `path`, keyed by path relative to the root.
+    // Directories are walked in name order, so when WATCH_LIMIT cuts the scan
+    // short every poll keeps the same entries.
+    fn scan(&self, path: &Path) -> HashMap<String, (u64, u64)> {
+        let mut seen = HashMap::new();
+        let mut pending = vec![path.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let mut entries: Vec<_> = match fs::read_dir(&dir) {
+                Ok(entries) => entries.flatten().collect(),
+                Err(_) => continue,
+            };
+            entries.sort_by_key(|entry| entry.file_name());
+            let mut dirs = vec![];
+            for entry in entries {
+                if seen.len() >= WATCH_LIMIT {
+                    return seen;
+                }
+                let meta = match entry.metadata() {
+                    Ok(meta) => meta,
+                    Err(_) => continue,
+                };
+                if meta.is_dir() {
+                    dirs.push(entry.path());
+                }
+                let modified = meta.modified().map_or(0, unix_ms);
+                seen.insert(self.relative(&entry.path()), (meta.len(), modified));
+            }
+            pending.extend(dirs.into_iter().rev());
+        }
+        seen
+    }
+
+    fn poll_watches(&mut self, ctx: &ServiceContext) {
+        let mut watches = std::mem::take(&mut self.watches);
+        for ((_, id), watch) in watches.iter_mut() {
+            let seen = self.scan(&watch.path);
+            let mut changes = vec![];
+            for (path, stamp) in seen.iter() {
+                match watch.seen.get(path) {
+                    None => changes.push(Change::Created(path.clone())),
+                    Some(old) if old != stamp => changes.push(Change::Modified(path.clone())),
+                    _ => {}
+                }
+            }
+            for path in watch.seen.keys() {
+                if !seen.contains_key(path) {
+                    changes.push(Change::Removed(path.clone()));
+                }
+            }
+            watch.seen = seen;
+            for changes in batches(changes, |change| change.path().len()) {
+                let changed = FsResponse::Changed { id: *id, changes };
+                let _ = ctx.send(watch.conn, encode(&changed));
+            }
+        }
+        self.watches = watches;
+    }
+}
+
+impl Service for FsService {
+    fn handle_init(&mut self, ctx: &mut ServiceContext) {
+        ctx.set_timeout(TIMER_WATCH, self.watch_interval);
+    }
+    fn handle_close(&mut self, _ctx: &mut ServiceContext, conn: usize) {
+        self.reads.retain(|(reader, _), _| *reader != conn);
+        self.watches.retain(|(watcher, _), _| *watcher != conn);
+    }
+    fn handle_frames(&mut self, ctx: &mut ServiceContext, conn: usize, frames: Vec<Bytes>) {
+        for frame in frames {
+            match bincode::deserialize(&frame) {
+                Ok(request) => self.handle_request(ctx, conn, request),
+                // XXX app.handle_decode_error?
+                Err(_e) => continue,
+            }
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &mut ServiceContext, _token: usize) {
+        self.poll_watches(ctx);
+        ctx.set_timeout(TIMER_WATCH, self.watch_interval);
+    }
+}
+
+// Builds requests for the "fs" service, for use in a client Service
+#[derive(Default)]
+pub struct FsClient {
+    next_id: u64,
+    // Bytes of each read taken since Credit was last sent
+    unacked: HashMap<u64, u64>,
+}
+
+impl FsClient {
+    pub fn new() -> Self {
+        FsClient::default()
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<FsResponse, Error> {
+        Ok(bincode::deserialize(frame)?)
+    }
+
+    // Call with each response once it's been dealt with,
+    // reads stall after READ_WINDOW bytes otherwise
+    pub fn received(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        response: &FsResponse,
+    ) -> Result<(), Error> {
+        match response {
+            FsResponse::Chunk { id, data, .. } => {
+                let unacked = self.unacked.entry(*id).or_insert(0);
+                *unacked += data.len() as u64;
+                if *unacked >= READ_WINDOW / 2 {
+                    let request = FsRequest::Credit {
+                        id: *id,
+                        len: *unacked,
+                    };
+                    *unacked = 0;
+                    ctx.send_to(conn, "fs", encode(&request))?;
+                }
+            }
+            other => {
+                self.unacked.remove(&other.id());
+            }
+        }
+        Ok(())
+    }
+
+    fn request<F>(&mut self, ctx: &ServiceContext, conn: usize, build: F) -> Result<u64, Error>
+    where
+        F: FnOnce(u64) -> FsRequest,
+    {
+        self.next_id += 1;
+        let id = self.next_id;
+        ctx.send_to(conn, "fs", encode(&build(id)))?;
+        Ok(id)
+    }
+
+    pub fn stat(&mut self, ctx: &ServiceContext, conn: usize, path: &str) -> Result<u64, Error> {
+        let path = path.into();
+        self.request(ctx, conn, |id| FsRequest::Stat { id, path })
+    }
+    pub fn list(&mut self, ctx: &ServiceContext, conn: usize, path: &str) -> Result<u64, Error> {
+        let path = path.into();
+        self.request(ctx, conn, |id| FsRequest::List { id, path })
+    }
+    pub fn read(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<u64, Error> {
+        let path = path.into();
+        self.request(ctx, conn, |id| FsRequest::Read {
+            id,
+            path,
+            offset,
+            len,
+        })
+    }
+    // Replaces the file, sending it in CHUNK_SIZE pieces
+    pub fn write(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        path: &str,
+        data: &[u8],
+    ) -> Result<u64, Error> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let mut chunks: Vec<&[u8]> = data.chunks(CHUNK_SIZE).collect();
+        if chunks.is_empty() {
+            // Still create or truncate the file
+            chunks.push(&[]);
+        }
+        let mut offset = 0;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let request = FsRequest::Write {
+                id,
+                path: path.into(),
+                offset,
+                data: chunk.to_vec(),
+                truncate: i == 0,
+                last: i == chunks.len() - 1,
+            };
+            ctx.send_to(conn, "fs", encode(&request))?;
+            offset += chunk.len() as u64;
+        }
+        Ok(id)
+    }
+    pub fn rename(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        from: &str,
+        to: &str,
+    ) -> Result<u64, Error> {
+        let (from, to) = (from.into(), to.into());
+        self.request(ctx, conn, |id| FsRequest::Rename { id, from, to })
+    }
+    pub fn delete(&mut self, ctx: &ServiceContext, conn: usize, path: &str) -> Result<u64, Error> {
+        let path = path.into();
+        self.request(ctx, conn, |id| FsRequest::Delete { id, path })
+    }
+    pub fn watch(&mut self, ctx: &ServiceContext, conn: usize, path: &str) -> Result<u64, Error> {
+        let path = path.into();
+        self.request(ctx, conn, |id| FsRequest::Watch { id, path })
+    }
+    pub fn unwatch(&mut self, ctx: &ServiceContext, conn: usize, watch: u64) -> Result<(), Error> {
+        let request = FsRequest::Unwatch { id: watch };
+        ctx.send_to(conn, "fs", encode(&request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ServiceHost;
+    use mio_framed::Core;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    type Responses = Rc<RefCell<Vec<(&'static str, FsResponse)>>>;
+
+    struct Script {
+        fs: FsClient,
+        names: HashMap<u64, &'static str>,
+        responses: Responses,
+    }
+
+    impl Service for Script {
+        fn handle_connect(&mut self, ctx: &mut ServiceContext, conn: usize) {
+            let big: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+            let fs = &mut self.fs;
+            let requests = [
+                ("watch", fs.watch(ctx, conn, "logs")),
+                ("write", fs.write(ctx, conn, "big.bin", &big)),
+                ("read", fs.read(ctx, conn, "big.bin", 40_000, Some(50_000))),
+                ("whole", fs.read(ctx, conn, "big.bin", 0, None)),
+                ("stat", fs.stat(ctx, conn, "/big.bin")),
+                ("log", fs.write(ctx, conn, "logs/app.log", b"started")),
+                ("rename", fs.rename(ctx, conn, "big.bin", "moved.bin")),
+                ("list", fs.list(ctx, conn, "")),
+                ("many", fs.list(ctx, conn, "many")),
+                ("delete", fs.delete(ctx, conn, "moved.bin")),
+                ("escape", fs.stat(ctx, conn, "logs/../../outside")),
+                ("dangling", fs.write(ctx, conn, "dangling/x", b"escaped")),
+                ("dangling", fs.write(ctx, conn, "dangling", b"escaped")),
+            ];
+            for (name, id) in requests.iter() {
+                self.names.insert(*id.as_ref().unwrap(), name);
+            }
+        }
+        fn handle_frames(&mut self, ctx: &mut ServiceContext, conn: usize, frames: Vec<Bytes>) {
+            for frame in frames {
+                let response = FsClient::decode(&frame).unwrap();
+                self.fs.received(ctx, conn, &response).unwrap();
+                let name = self.names[&response.id()];
+                self.responses.borrow_mut().push((name, response));
+            }
+        }
+    }
+
+    fn responses_for(responses: &Responses, name: &str) -> Vec<FsResponse> {
+        let responses = responses.borrow();
+        let found = responses.iter().filter(|(n, _)| *n == name);
+        found.map(|(_, response)| response.clone()).collect()
+    }
+
+    #[test]
+    fn serves_a_sandboxed_tree() {
+        let root = std::env::temp_dir().join(format!("mesh-msg-fs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("logs")).unwrap();
+        // Too many long names for one frame
+        fs::create_dir_all(root.join("many")).unwrap();
+        for i in 0..300 {
+            let name = format!("{:03}{}", i, "x".repeat(240));
+            File::create(root.join("many").join(name)).unwrap();
+        }
+        // Points outside the root at something that doesn't exist yet
+        let outside = root.with_extension("outside");
+        let _ = fs::remove_file(&outside);
+        std::os::unix::fs::symlink(&outside, root.join("dangling")).unwrap();
+
+        let addr = "127.0.0.1:13311";
+        let responses = Rc::new(RefCell::new(vec![]));
+        let service = FsService::new(&root)
+            .unwrap()
+            .watch_interval(Duration::from_millis(10));
+        let mut server = Core::new(ServiceHost::new().offer("fs", service));
+        server.listen(addr).unwrap();
+        let script = Script {
+            fs: FsClient::new(),
+            names: HashMap::new(),
+            responses: responses.clone(),
+        };
+        let mut client = Core::new(ServiceHost::new().client("fs", script));
+        client.connect(addr).unwrap();
+
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let done = |name| {
+            let responses = responses_for(&responses, name);
+            matches!(responses.last(), Some(FsResponse::Done { .. }))
+        };
+        while (responses_for(&responses, "watch").len() < 2 || !done("whole"))
+            && Instant::now() < deadline
+        {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+
+        let read = |name| -> Vec<u8> {
+            responses_for(&responses, name)
+                .into_iter()
+                .flat_map(|response| match response {
+                    FsResponse::Chunk { data, .. } => data,
+                    _ => vec![],
+                })
+                .collect()
+        };
+        let expected: Vec<u8> = (40_000..90_000).map(|i| i as u8).collect();
+        assert_eq!(read("read"), expected);
+        // More than READ_WINDOW, so it only finished thanks to Credit
+        let expected: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        assert_eq!(read("whole"), expected);
+        match &responses_for(&responses, "stat")[0] {
+            FsResponse::Stat { meta, .. } => {
+                assert_eq!(meta.kind, FileKind::File);
+                assert_eq!(meta.len, 100_000);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let listed: Vec<String> = responses_for(&responses, "list")
+            .into_iter()
+            .flat_map(|response| match response {
+                FsResponse::Entries { entries, .. } => entries,
+                _ => vec![],
+            })
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(listed, vec!["dangling", "logs", "many", "moved.bin"]);
+        let many = responses_for(&responses, "many");
+        let batches: Vec<usize> = many
+            .iter()
+            .filter_map(|response| match response {
+                FsResponse::Entries { entries, .. } => Some(entries.len()),
+                _ => None,
+            })
+            .collect();
+        assert!(batches.len() > 1);
+        assert_eq!(batches.iter().sum::<usize>(), 300);
+        assert!(matches!(many.last(), Some(FsResponse::Done { .. })));
+        assert!(matches!(
+            responses_for(&responses, "delete")[..],
+            [FsResponse::Done { .. }]
+        ));
+        assert!(matches!(
+            responses_for(&responses, "escape")[..],
+            [FsResponse::Error { .. }]
+        ));
+        assert!(matches!(
+            responses_for(&responses, "dangling")[..],
+            [FsResponse::Error { .. }, FsResponse::Error { .. }]
+        ));
+        assert!(!outside.exists());
+        assert!(!root.join("moved.bin").exists());
+        match &responses_for(&responses, "watch")[1] {
+            FsResponse::Changed { changes, .. } => {
+                assert_eq!(*changes, vec![Change::Created("logs/app.log".into())])
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn large_watches_stay_stable_and_fit_frames() {
+        let root = std::env::temp_dir().join(format!("mesh-msg-watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["a", "b"].iter() {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            for i in 0..WATCH_LIMIT / 2 + 100 {
+                File::create(root.join(dir).join(format!("{:05}{}", i, "x".repeat(40)))).unwrap();
+            }
+        }
+        let service = FsService::new(&root).unwrap();
+        let first = service.scan(&service.root);
+        assert_eq!(first.len(), WATCH_LIMIT);
+        assert_eq!(first, service.scan(&service.root));
+        let changes = first.keys().cloned().map(Change::Created).collect();
+        for changes in batches(changes, |change| change.path().len()) {
+            let changed = encode(&FsResponse::Changed { id: 1, changes });
+            assert!(changed.len() <= u16::MAX as usize);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
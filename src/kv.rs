@@ -0,0 +1,595 @@
+use bytes::Bytes;
+use failure::{bail, Error};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::packet::NodeId;
+use crate::service::{Service, ServiceContext};
+
+// Frames are capped at 64KiB, values and sync batches stay well below
+pub const MAX_KEY: usize = 1024;
+pub const MAX_VALUE: usize = 48 * 1024;
+const BATCH_SIZE: usize = 32 * 1024;
+// Rough bincode overhead of a record besides its key and value
+const RECORD_OVERHEAD: usize = 48;
+
+// Hybrid logical clock stamp, the writer's node breaks ties.
+// The derived ordering is the last-writer-wins order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Version {
+    pub time_ms: u64,
+    pub counter: u32,
+    pub node: NodeId,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub key: String,
+    // None is a tombstone, kept so deletes replicate like writes
+    pub value: Option<Vec<u8>>,
+    pub version: Version,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum KvRequest {
+    Get {
+        id: u64,
+        key: String,
+    },
+    Put {
+        id: u64,
+        key: String,
+        value: Vec<u8>,
+    },
+    Delete {
+        id: u64,
+        key: String,
+    },
+    // Changes to keys under `prefix` arrive as Changed tagged with this id
+    Watch {
+        id: u64,
+        prefix: String,
+    },
+    Unwatch {
+        id: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum KvResponse {
+    Value {
+        id: u64,
+        value: Option<Vec<u8>>,
+        version: Option<Version>,
+    },
+    Done {
+        id: u64,
+        version: Option<Version>,
+    },
+    Changed {
+        id: u64,
+        record: Record,
+    },
+    Error {
+        id: u64,
+        message: String,
+    },
+}
+
+impl KvResponse {
+    pub fn id(&self) -> u64 {
+        match self {
+            KvResponse::Value { id, .. }
+            | KvResponse::Done { id, .. }
+            | KvResponse::Changed { id, .. }
+            | KvResponse::Error { id, .. } => *id,
+        }
+    }
+}
+
+// Clients and replicas share the "kv" name, so everything goes in one enum
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum KvFrame {
+    Request(KvRequest),
+    Response(KvResponse),
+    // What the sender has, so the receiver can send back what is newer.
+    // Large stores take several frames, the receiver answers after `last`.
+    Digest {
+        entries: Vec<(String, Version)>,
+        last: bool,
+    },
+    Update(Vec<Record>),
+}
+
+fn encode(frame: &KvFrame) -> Vec<u8> {
+    bincode::serialize(frame).expect("Kv message serialization can't fail")
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+// Split into frames of roughly BATCH_SIZE
+fn batches<T, F>(items: Vec<T>, size: F) -> Vec<Vec<T>>
+where
+    F: Fn(&T) -> usize,
+{
+    let mut batches = vec![];
+    let (mut batch, mut batch_size) = (vec![], 0);
+    for item in items {
+        let item_size = size(&item);
+        if !batch.is_empty() && batch_size + item_size > BATCH_SIZE {
+            batches.push(std::mem::take(&mut batch));
+            batch_size = 0;
+        }
+        batch_size += item_size;
+        batch.push(item);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+// A replica of the key-value store. Replicas are the peers offering "kv":
+// writes are flooded to them as they happen and a digest exchange on every
+// new connection catches up on anything missed. Conflicts resolve to the
+// highest Version.
+pub struct KvService {
+    node: NodeId,
+    clock: (u64, u32),
+    entries: BTreeMap<String, Record>,
+    // (conn, id) -> prefix
+    watches: HashMap<(usize, u64), String>,
+    // Digests still arriving from each replica
+    digests: HashMap<usize, HashMap<String, Version>>,
+}
+
+impl KvService {
+    pub fn new(node: NodeId) -> Self {
+        KvService {
+            node,
+            clock: (0, 0),
+            entries: BTreeMap::new(),
+            watches: HashMap::new(),
+            digests: HashMap::new(),
+        }
+    }
+
+    fn tick(&mut self) -> Version {
+        let now = now_ms();
+        self.clock = if now > self.clock.0 {
+            (now, 0)
+        } else {
+            (self.clock.0, self.clock.1 + 1)
+        };
+        Version {
+            time_ms: self.clock.0,
+            counter: self.clock.1,
+            node: self.node,
+        }
+    }
+
+    // Keep local writes ordered after anything already seen
+    fn observe(&mut self, version: Version) {
+        if (version.time_ms, version.counter) > self.clock {
+            self.clock = (version.time_ms, version.counter);
+        }
+    }
+
+    fn send(ctx: &ServiceContext, conn: usize, frame: &KvFrame) {
+        // The peer may not run kv, then there's nobody to tell
+        let _ = ctx.send(conn, encode(frame));
+    }
+
+    fn replicas<'a>(ctx: &'a ServiceContext) -> impl Iterator<Item = usize> + 'a {
+        ctx.peers()
+            .filter(move |conn| ctx.peer_offers(*conn, ctx.name()))
+    }
+
+    fn send_updates(ctx: &ServiceContext, conn: usize, records: Vec<Record>) {
+        let size = |record: &Record| {
+            record.key.len() + record.value.as_ref().map_or(0, Vec::len) + RECORD_OVERHEAD
+        };
+        for batch in batches(records, size) {
+            Self::send(ctx, conn, &KvFrame::Update(batch));
+        }
+    }
+
+    // Store records newer than ours, returning those that were
+    fn merge(&mut self, ctx: &ServiceContext, records: Vec<Record>) -> Vec<Record> {
+        let mut applied = vec![];
+        for record in records {
+            self.observe(record.version);
+            let newer = self
+                .entries
+                .get(&record.key)
+                .is_none_or(|current| record.version > current.version);
+            if !newer {
+                continue;
+            }
+            for ((conn, id), prefix) in self.watches.iter() {
+                if record.key.starts_with(prefix.as_str()) {
+                    let record = record.clone();
+                    let changed = KvResponse::Changed { id: *id, record };
+                    Self::send(ctx, *conn, &KvFrame::Response(changed));
+                }
+            }
+            self.entries.insert(record.key.clone(), record.clone());
+            applied.push(record);
+        }
+        applied
+    }
+
+    // Merge and pass on to the other replicas
+    fn apply(&mut self, ctx: &ServiceContext, from: Option<usize>, records: Vec<Record>) {
+        let applied = self.merge(ctx, records);
+        if applied.is_empty() {
+            return;
+        }
+        for conn in Self::replicas(ctx).filter(|conn| Some(*conn) != from) {
+            Self::send_updates(ctx, conn, applied.clone());
+        }
+    }
+
+    fn write(&mut self, ctx: &ServiceContext, key: String, value: Option<Vec<u8>>) -> Version {
+        let version = self.tick();
+        let record = Record {
+            key,
+            value,
+            version,
+        };
+        self.apply(ctx, None, vec![record]);
+        version
+    }
+
+    fn handle_request(&mut self, ctx: &ServiceContext, conn: usize, request: KvRequest) {
+        let id = match &request {
+            KvRequest::Get { id, .. }
+            | KvRequest::Put { id, .. }
+            | KvRequest::Delete { id, .. }
+            | KvRequest::Watch { id, .. }
+            | KvRequest::Unwatch { id } => *id,
+        };
+        let response = match self.run(ctx, conn, request) {
+            Ok(response) => response,
+            Err(e) => KvResponse::Error {
+                id,
+                message: e.to_string(),
+            },
+        };
+        Self::send(ctx, conn, &KvFrame::Response(response));
+    }
+
+    fn run(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        request: KvRequest,
+    ) -> Result<KvResponse, Error> {
+        match request {
+            KvRequest::Get { id, key } => {
+                let record = self.entries.get(&key);
+                Ok(KvResponse::Value {
+                    id,
+                    value: record.and_then(|record| record.value.clone()),
+                    version: record.map(|record| record.version),
+                })
+            }
+            KvRequest::Put { id, key, value } => {
+                if key.len() > MAX_KEY || value.len() > MAX_VALUE {
+                    bail!("Entry for {} is too large", key);
+                }
+                let version = Some(self.write(ctx, key, Some(value)));
+                Ok(KvResponse::Done { id, version })
+            }
+            KvRequest::Delete { id, key } => {
+                let version = match self.entries.get(&key) {
+                    Some(record) if record.value.is_some() => Some(self.write(ctx, key, None)),
+                    _ => None,
+                };
+                Ok(KvResponse::Done { id, version })
+            }
+            KvRequest::Watch { id, prefix } => {
+                self.watches.insert((conn, id), prefix);
+                Ok(KvResponse::Done { id, version: None })
+            }
+            KvRequest::Unwatch { id } => {
+                self.watches.remove(&(conn, id));
+                Ok(KvResponse::Done { id, version: None })
+            }
+        }
+    }
+
+    // Anti-entropy: each side sends a digest and answers the other's
+    fn send_digest(&self, ctx: &ServiceContext, conn: usize) {
+        let digest: Vec<(String, Version)> = self
+            .entries
+            .values()
+            .map(|record| (record.key.clone(), record.version))
+            .collect();
+        let size = |(key, _): &(String, Version)| key.len() + RECORD_OVERHEAD;
+        let mut batches = batches(digest, size);
+        // An empty store still asks for everything the other side has
+        let last = batches.pop().unwrap_or_default();
+        for entries in batches {
+            Self::send(
+                ctx,
+                conn,
+                &KvFrame::Digest {
+                    entries,
+                    last: false,
+                },
+            );
+        }
+        Self::send(
+            ctx,
+            conn,
+            &KvFrame::Digest {
+                entries: last,
+                last: true,
+            },
+        );
+    }
+
+    fn handle_digest(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        entries: Vec<(String, Version)>,
+        last: bool,
+    ) {
+        let theirs = self.digests.entry(conn).or_default();
+        theirs.extend(entries);
+        if !last {
+            return;
+        }
+        let theirs = self.digests.remove(&conn).unwrap_or_default();
+        let newer: Vec<Record> = self
+            .entries
+            .values()
+            .filter(|record| {
+                theirs
+                    .get(&record.key)
+                    .is_none_or(|version| record.version > *version)
+            })
+            .cloned()
+            .collect();
+        Self::send_updates(ctx, conn, newer);
+    }
+}
+
+impl Service for KvService {
+    fn handle_connect(&mut self, ctx: &mut ServiceContext, conn: usize) {
+        if ctx.peer_offers(conn, ctx.name()) {
+            self.send_digest(ctx, conn);
+        }
+    }
+    fn handle_close(&mut self, _ctx: &mut ServiceContext, conn: usize) {
+        self.watches.retain(|(watcher, _), _| *watcher != conn);
+        self.digests.remove(&conn);
+    }
+    fn handle_frames(&mut self, ctx: &mut ServiceContext, conn: usize, frames: Vec<Bytes>) {
+        for frame in frames {
+            match bincode::deserialize(&frame) {
+                Ok(KvFrame::Request(request)) => self.handle_request(ctx, conn, request),
+                Ok(KvFrame::Digest { entries, last }) => {
+                    self.handle_digest(ctx, conn, entries, last)
+                }
+                Ok(KvFrame::Update(records)) => self.apply(ctx, Some(conn), records),
+                // Replicas don't make requests
+                Ok(KvFrame::Response(_)) => continue,
+                // XXX app.handle_decode_error?
+                Err(_e) => continue,
+            }
+        }
+    }
+}
+
+// Builds requests for the "kv" service, for use in a client Service
+// registered under the same name
+#[derive(Default)]
+pub struct KvClient {
+    next_id: u64,
+}
+
+impl KvClient {
+    pub fn new() -> Self {
+        KvClient::default()
+    }
+
+    // Ok(None) for replication traffic, which clients can ignore
+    pub fn decode(frame: &[u8]) -> Result<Option<KvResponse>, Error> {
+        match bincode::deserialize(frame)? {
+            KvFrame::Response(response) => Ok(Some(response)),
+            _ => Ok(None),
+        }
+    }
+
+    fn request<F>(&mut self, ctx: &ServiceContext, conn: usize, build: F) -> Result<u64, Error>
+    where
+        F: FnOnce(u64) -> KvRequest,
+    {
+        self.next_id += 1;
+        let id = self.next_id;
+        ctx.send_to(conn, "kv", encode(&KvFrame::Request(build(id))))?;
+        Ok(id)
+    }
+
+    pub fn get(&mut self, ctx: &ServiceContext, conn: usize, key: &str) -> Result<u64, Error> {
+        let key = key.into();
+        self.request(ctx, conn, |id| KvRequest::Get { id, key })
+    }
+    pub fn put(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        key: &str,
+        value: &[u8],
+    ) -> Result<u64, Error> {
+        let (key, value) = (key.into(), value.to_vec());
+        self.request(ctx, conn, |id| KvRequest::Put { id, key, value })
+    }
+    pub fn delete(&mut self, ctx: &ServiceContext, conn: usize, key: &str) -> Result<u64, Error> {
+        let key = key.into();
+        self.request(ctx, conn, |id| KvRequest::Delete { id, key })
+    }
+    pub fn watch(&mut self, ctx: &ServiceContext, conn: usize, prefix: &str) -> Result<u64, Error> {
+        let prefix = prefix.into();
+        self.request(ctx, conn, |id| KvRequest::Watch { id, prefix })
+    }
+    pub fn unwatch(&self, ctx: &ServiceContext, conn: usize, id: u64) -> Result<(), Error> {
+        let request = KvFrame::Request(KvRequest::Unwatch { id });
+        ctx.send_to(conn, "kv", encode(&request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ServiceHost;
+    use mio_framed::Core;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    type Responses = Rc<RefCell<Vec<KvResponse>>>;
+    type Steps = Box<dyn FnMut(&mut KvClient, &ServiceContext, usize)>;
+
+    // Runs `script` once connected, then records what comes back
+    struct Script {
+        kv: KvClient,
+        script: Steps,
+        responses: Responses,
+    }
+
+    impl Service for Script {
+        fn handle_connect(&mut self, ctx: &mut ServiceContext, conn: usize) {
+            (self.script)(&mut self.kv, ctx, conn);
+        }
+        fn handle_frames(&mut self, _ctx: &mut ServiceContext, _conn: usize, frames: Vec<Bytes>) {
+            let mut responses = self.responses.borrow_mut();
+            for frame in frames {
+                responses.extend(KvClient::decode(&frame).unwrap());
+            }
+        }
+    }
+
+    fn client<F>(addr: &str, script: F) -> (Core<ServiceHost>, Responses)
+    where
+        F: FnMut(&mut KvClient, &ServiceContext, usize) + 'static,
+    {
+        let responses = Rc::new(RefCell::new(vec![]));
+        let script = Script {
+            kv: KvClient::new(),
+            script: Box::new(script),
+            responses: responses.clone(),
+        };
+        let mut core = Core::new(ServiceHost::new().client("kv", script));
+        core.connect(addr).unwrap();
+        (core, responses)
+    }
+
+    fn run_until<F: Fn() -> bool>(cores: &mut [&mut Core<ServiceHost>], done: F) {
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() && Instant::now() < deadline {
+            for core in cores.iter_mut() {
+                core.turn(tick).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn replicates_between_nodes() {
+        let (addr_a, addr_b) = ("127.0.0.1:13315", "127.0.0.1:13316");
+        let mut a = Core::new(ServiceHost::new().offer("kv", KvService::new(NodeId(1))));
+        a.listen(addr_a).unwrap();
+
+        // Written before the other replica exists, so only anti-entropy gets it there
+        let (mut writer, written) = client(addr_a, |kv, ctx, conn| {
+            kv.put(ctx, conn, "config/a", b"1").unwrap();
+            kv.put(ctx, conn, "config/b", b"2").unwrap();
+            kv.delete(ctx, conn, "config/b").unwrap();
+        });
+        run_until(&mut [&mut a, &mut writer], || written.borrow().len() == 3);
+
+        let mut b = Core::new(ServiceHost::new().offer("kv", KvService::new(NodeId(2))));
+        b.listen(addr_b).unwrap();
+        b.connect(addr_a).unwrap();
+        let (mut reader, read) = client(addr_b, |kv, ctx, conn| {
+            kv.watch(ctx, conn, "config/").unwrap();
+        });
+        let watching = |read: &Responses| !read.borrow().is_empty();
+        run_until(&mut [&mut a, &mut b, &mut writer, &mut reader], || {
+            watching(&read)
+        });
+
+        // A live write on one replica shows up in a watch on the other
+        let (mut late, _) = client(addr_a, |kv, ctx, conn| {
+            kv.put(ctx, conn, "config/c", b"3").unwrap();
+            kv.put(ctx, conn, "other", b"4").unwrap();
+        });
+        let changed = |read: &Responses| {
+            let read = read.borrow();
+            read.iter()
+                .any(|response| matches!(response, KvResponse::Changed { record, .. } if record.key == "config/c"))
+        };
+        run_until(
+            &mut [&mut a, &mut b, &mut writer, &mut reader, &mut late],
+            || changed(&read),
+        );
+
+        let (mut check, checked) = client(addr_b, |kv, ctx, conn| {
+            for key in ["config/a", "config/b", "config/c", "other"].iter() {
+                kv.get(ctx, conn, key).unwrap();
+            }
+        });
+        run_until(&mut [&mut a, &mut b, &mut late, &mut check], || {
+            checked.borrow().len() == 4
+        });
+
+        let values: Vec<Option<Vec<u8>>> = checked
+            .borrow()
+            .iter()
+            .map(|response| match response {
+                KvResponse::Value { value, .. } => value.clone(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        let expected = vec![
+            Some(b"1".to_vec()),
+            None,
+            Some(b"3".to_vec()),
+            Some(b"4".to_vec()),
+        ];
+        assert_eq!(values, expected);
+
+        let versions: Vec<Version> = written
+            .borrow()
+            .iter()
+            .filter_map(|response| match response {
+                KvResponse::Done { version, .. } => *version,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(versions.len(), 3);
+        assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+        // The watch only saw its prefix, it may also have seen the sync from A
+        let read = read.borrow();
+        let keys: Vec<&str> = read
+            .iter()
+            .filter_map(|response| match response {
+                KvResponse::Changed { record, .. } => Some(record.key.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert!(keys.contains(&"config/c"));
+        assert!(keys.iter().all(|key| key.starts_with("config/")));
+    }
+}
@@ -0,0 +1,40 @@
+mod election;
+mod exec;
+mod fs;
+mod kv;
+mod mesh;
+mod nodemap;
+mod packet;
+mod pty;
+mod pubsub;
+mod raft;
+mod routing;
+mod service;
+mod supervise;
+mod swim;
+mod transport;
+
+pub use crate::election::{
+    new_election, Election, ElectionApp, ElectionContext, ElectionMessage, Elector, Role,
+};
+pub use crate::exec::{ExecClient, ExecRequest, ExecResponse, ExecService, ExitStatus, Signal};
+pub use crate::fs::{
+    Change, DirEntry, FileKind, FileMeta, FsClient, FsRequest, FsResponse, FsService,
+};
+pub use crate::kv::{KvClient, KvRequest, KvResponse, KvService, Record, Version};
+pub use crate::mesh::{new_mesh, Mesh, MeshApp, MeshContext};
+pub use crate::nodemap::{Link, NodeInfo, NodeMap, NodeMapSnapshot, NodeSnapshot};
+pub use crate::packet::NodeId;
+pub use crate::pty::{PtyClient, PtyRequest, PtyResponse, PtyService, WindowSize};
+pub use crate::pubsub::topic_matches;
+pub use crate::raft::{
+    new_raft, Committed, Entry, Payload, Persisted, Raft, RaftApp, RaftContext, RaftMessage,
+    Replica, Snapshot, Storage,
+};
+pub use crate::routing::Route;
+pub use crate::service::{Service, ServiceContext, ServiceHost, ServiceInfo};
+pub use crate::supervise::{
+    ExitEvent, ProcessNode, ProcessState, RestartPolicy, SuperviseRequest, SuperviseResponse,
+    SupervisedInfo, SupervisorClient, SupervisorService,
+};
+pub use crate::swim::{new_swim, MemberState, Swim, SwimApp, SwimContext};
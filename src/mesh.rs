@@ -0,0 +1,936 @@
+use bytes::{Bytes, IntoBuf};
+use failure::{format_err, Error};
+use mio_framed::{App, Context, Core};
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::nodemap::{Link, NodeMap};
+use crate::packet::{Body, Envelope, Hello, Packet, Publication, DEFAULT_TTL};
+use crate::pubsub::{SeenCache, SubscriptionTable};
+use crate::routing::{Route, RoutingTable};
+use crate::NodeId;
+
+// Timer tokens are tagged in the low bits so the mesh and the app can't collide
+const TIMER_BITS: usize = 4;
+const TIMER_APP: usize = 0;
+const TIMER_GOSSIP: usize = 1;
+const TIMER_PUBLISH: usize = 2;
+
+// How many publication ids to remember for duplicate suppression
+const SEEN_CAPACITY: usize = 4096;
+// Acknowledged publications are sent at most this many times
+const MAX_PUBLISH_ATTEMPTS: u8 = 3;
+
+fn timer_token(kind: usize, token: usize) -> usize {
+    token << TIMER_BITS | kind
+}
+
+pub trait MeshApp {
+    fn handle_init(&mut self, _ctx: &mut MeshContext) {}
+    // A node became addressable, either directly or through relays
+    fn handle_node_up(&mut self, _ctx: &mut MeshContext, _node: NodeId) {}
+    fn handle_node_down(&mut self, _ctx: &mut MeshContext, _node: NodeId) {}
+    fn handle_frames(&mut self, _ctx: &mut MeshContext, _src: NodeId, _frames: Vec<Bytes>) {}
+    fn handle_publish(
+        &mut self,
+        _ctx: &mut MeshContext,
+        _src: NodeId,
+        _topic: &str,
+        _payload: Bytes,
+    ) {
+    }
+    // An acknowledged publication timed out, `acked_by` lists the subscribers that got it
+    fn handle_publish_acks(&mut self, _ctx: &mut MeshContext, _id: u64, _acked_by: Vec<NodeId>) {}
+    // A relay dropped something we sent to `dst`, it had no route or the ttl ran out
+    fn handle_unreachable(&mut self, _ctx: &mut MeshContext, _dst: NodeId) {}
+    fn handle_timeout(&mut self, _ctx: &mut MeshContext, _token: usize) {}
+    fn handle_shutdown(&mut self) {}
+}
+
+pub struct MeshContext<'a> {
+    ctx: &'a Context,
+    state: &'a mut MeshState,
+}
+
+impl<'a> MeshContext<'a> {
+    pub fn node_id(&self) -> NodeId {
+        self.state.node
+    }
+    pub fn neighbors<'b>(&'b self) -> impl Iterator<Item = NodeId> + 'b {
+        self.state.table.links().map(|(node, _conn)| node)
+    }
+    pub fn is_reachable(&self, node: NodeId) -> bool {
+        self.state.reachable.contains(&node)
+    }
+    pub fn routes<'b>(&'b self) -> impl Iterator<Item = (NodeId, &'b Route)> + 'b {
+        self.state
+            .table
+            .routes()
+            .iter()
+            .map(|(node, route)| (*node, route))
+    }
+    pub fn send_to_node<B: Into<Bytes>>(&mut self, dst: NodeId, frame: B) -> Result<(), Error> {
+        let frame: Bytes = frame.into();
+        let envelope = Envelope::new(self.state.node, dst, Body::Data(frame.to_vec()));
+        let conn = self
+            .state
+            .route(dst)
+            .ok_or_else(|| format_err!("No route to node {}", dst))?;
+        send_packet(self.ctx, conn, &Packet::Envelope(envelope))
+    }
+    // Ask the mesh for everything published on topics matching `pattern`
+    pub fn subscribe(&mut self, pattern: &str) {
+        if self.state.subscriptions.subscribe_local(pattern.into()) {
+            self.state.advertise_interest(self.ctx);
+        }
+    }
+    pub fn unsubscribe(&mut self, pattern: &str) {
+        if self.state.subscriptions.unsubscribe_local(pattern) {
+            self.state.advertise_interest(self.ctx);
+        }
+    }
+    // Fire and forget, each subscriber sees the message at most once.
+    // Returns how many connections the message went out on.
+    pub fn publish<B: Into<Bytes>>(&mut self, topic: &str, payload: B) -> Result<usize, Error> {
+        let publication = self.state.publication(topic, payload.into(), false);
+        self.state.send_publication(self.ctx, &publication, None)
+    }
+    // Like publish, but subscribers acknowledge receipt and the message is
+    // retried while nobody has. MeshApp::handle_publish_acks reports who got
+    // it once `timeout` passes after the last attempt.
+    pub fn publish_acked<B: Into<Bytes>>(
+        &mut self,
+        topic: &str,
+        payload: B,
+        timeout: Duration,
+    ) -> Result<u64, Error> {
+        let publication = self.state.publication(topic, payload.into(), true);
+        let id = publication.seq;
+        self.state.send_publication(self.ctx, &publication, None)?;
+        let pending = PendingPublish {
+            publication,
+            timeout,
+            acked_by: vec![],
+        };
+        self.state.pending.insert(id, pending);
+        self.ctx
+            .set_timeout(timer_token(TIMER_PUBLISH, id as usize), timeout);
+        Ok(id)
+    }
+    pub fn nodemap(&self) -> &NodeMap {
+        &self.state.nodemap
+    }
+    pub fn set_timeout(&self, token: usize, delay: Duration) {
+        self.ctx.set_timeout(timer_token(TIMER_APP, token), delay);
+    }
+    pub fn cancel_timeout(&self, token: usize) {
+        self.ctx.cancel_timeout(timer_token(TIMER_APP, token));
+    }
+}
+
+struct MeshState {
+    node: NodeId,
+    relay: bool,
+    roles: Vec<String>,
+    table: RoutingTable,
+    reachable: HashSet<NodeId>,
+    nodemap: NodeMap,
+    gossip_interval: Duration,
+    started: Instant,
+    rtts: HashMap<usize, u64>,
+    subscriptions: SubscriptionTable,
+    next_seq: u64,
+    // Keyed on attempt too, so retries make it past relays that saw the original
+    forwarded: SeenCache<(NodeId, u64, u8)>,
+    delivered: SeenCache<(NodeId, u64)>,
+    pending: HashMap<u64, PendingPublish>,
+}
+
+struct PendingPublish {
+    publication: Publication,
+    timeout: Duration,
+    acked_by: Vec<NodeId>,
+}
+
+// Only app payloads can outgrow a frame, the mesh's own packets are
+// bounded and ignore the result
+fn send_packet(ctx: &Context, conn: usize, packet: &Packet) -> Result<(), Error> {
+    ctx.write_frame(conn, packet.encode()?.into_buf());
+    Ok(())
+}
+
+impl MeshState {
+    fn new(node: NodeId) -> Self {
+        MeshState {
+            node,
+            relay: false,
+            roles: vec![],
+            table: RoutingTable::new(node),
+            reachable: HashSet::new(),
+            nodemap: NodeMap::new(node),
+            gossip_interval: Duration::from_secs(5),
+            started: Instant::now(),
+            rtts: HashMap::new(),
+            subscriptions: SubscriptionTable::default(),
+            next_seq: 0,
+            forwarded: SeenCache::new(SEEN_CAPACITY),
+            delivered: SeenCache::new(SEEN_CAPACITY),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn route(&self, dst: NodeId) -> Option<usize> {
+        self.table.route(dst)
+    }
+
+    fn hello(&mut self, ctx: &Context, conn: usize, hello: Hello) {
+        if hello.node == self.node {
+            // XXX Connected to ourselves, or a node id collision
+            return;
+        }
+        for batch in self.nodemap.gossip() {
+            let _ = send_packet(ctx, conn, &Packet::NodeMap(batch));
+        }
+        if self.table.link_up(conn, hello.node) {
+            self.announce(ctx);
+        } else if self.relay {
+            // Nothing changed for anyone else, but the new neighbor needs our routes
+            let packet = Packet::Routes(self.table.advertisement_for(conn));
+            let _ = send_packet(ctx, conn, &packet);
+        }
+        self.advertise_interest(ctx);
+    }
+
+    fn closed(&mut self, ctx: &Context, conn: usize) {
+        self.rtts.remove(&conn);
+        self.subscriptions.remove_conn(conn);
+        if self.table.link_down(conn) {
+            self.announce(ctx);
+        }
+        self.advertise_interest(ctx);
+    }
+
+    fn interest(&mut self, ctx: &Context, conn: usize, patterns: Vec<(String, u8)>) {
+        if self.subscriptions.interest(conn, patterns) {
+            self.advertise_interest(ctx);
+        }
+    }
+
+    // Send each neighbor our interest set if it changed since last time
+    fn advertise_interest(&mut self, ctx: &Context) {
+        let conns: Vec<usize> = self.table.neighbor_conns().collect();
+        for conn in conns {
+            let patterns = self.subscriptions.advertisement_for(conn, self.relay);
+            if self.subscriptions.mark_sent(conn, &patterns) {
+                let _ = send_packet(ctx, conn, &Packet::Interest(patterns));
+            }
+        }
+    }
+
+    fn publication(&mut self, topic: &str, payload: Bytes, ack: bool) -> Publication {
+        self.next_seq += 1;
+        Publication {
+            src: self.node,
+            seq: self.next_seq,
+            attempt: 0,
+            ttl: DEFAULT_TTL,
+            ack,
+            topic: topic.into(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn send_publication(
+        &self,
+        ctx: &Context,
+        publication: &Publication,
+        from: Option<usize>,
+    ) -> Result<usize, Error> {
+        let frame = Packet::Publish(publication.clone()).encode()?;
+        let mut sent = 0;
+        for conn in self.subscriptions.matching_conns(&publication.topic) {
+            if Some(conn) != from {
+                ctx.write_frame(conn, frame.clone().into_buf());
+                sent += 1;
+            }
+        }
+        Ok(sent)
+    }
+
+    // Forwards a publication heard on `conn` and returns it if it's for us.
+    // Meshes with cycles see the same message over several paths, the caches
+    // drop all but the first copy.
+    fn published(
+        &mut self,
+        ctx: &Context,
+        conn: usize,
+        mut publication: Publication,
+    ) -> Option<Publication> {
+        let id = (publication.src, publication.seq, publication.attempt);
+        if publication.src == self.node || !self.forwarded.insert(id) {
+            return None;
+        }
+        if self.relay && publication.ttl > 0 {
+            publication.ttl -= 1;
+            let _ = self.send_publication(ctx, &publication, Some(conn));
+        }
+        // Neighbors only send what we asked for, but might be out of date
+        if !self.subscriptions.matches_local(&publication.topic) {
+            return None;
+        }
+        if publication.ack {
+            // Acknowledge every copy, an earlier ack may have been lost
+            let ack = Body::PublishAck(publication.seq);
+            let envelope = Envelope::new(self.node, publication.src, ack);
+            if let Some(conn) = self.route(publication.src) {
+                let _ = send_packet(ctx, conn, &Packet::Envelope(envelope));
+            }
+        }
+        if self.delivered.insert((publication.src, publication.seq)) {
+            Some(publication)
+        } else {
+            None
+        }
+    }
+
+    fn publish_ack(&mut self, src: NodeId, id: u64) {
+        if let Some(pending) = self.pending.get_mut(&id) {
+            if !pending.acked_by.contains(&src) {
+                pending.acked_by.push(src);
+            }
+        }
+    }
+
+    // Retries while nobody has acknowledged, otherwise returns who did
+    fn publish_timeout(&mut self, ctx: &Context, id: u64) -> Option<Vec<NodeId>> {
+        let pending = self.pending.get_mut(&id)?;
+        if pending.acked_by.is_empty() && pending.publication.attempt + 1 < MAX_PUBLISH_ATTEMPTS {
+            pending.publication.attempt += 1;
+            ctx.set_timeout(timer_token(TIMER_PUBLISH, id as usize), pending.timeout);
+            let publication = pending.publication.clone();
+            let _ = self.send_publication(ctx, &publication, None);
+            return None;
+        }
+        self.pending.remove(&id).map(|pending| pending.acked_by)
+    }
+
+    fn routes(&mut self, ctx: &Context, conn: usize, routes: Vec<(NodeId, u8)>) {
+        if self.table.advertisement(conn, routes) {
+            self.announce(ctx);
+        }
+    }
+
+    // Only relays offer paths through themselves
+    fn announce(&self, ctx: &Context) {
+        if !self.relay {
+            return;
+        }
+        for conn in self.table.neighbor_conns() {
+            let packet = Packet::Routes(self.table.advertisement_for(conn));
+            let _ = send_packet(ctx, conn, &packet);
+        }
+    }
+
+    fn forward(&mut self, ctx: &Context, mut envelope: Envelope) {
+        let next = if self.relay && envelope.ttl > 0 {
+            self.table.route(envelope.dst)
+        } else {
+            None
+        };
+        match next {
+            Some(conn) => {
+                envelope.ttl -= 1;
+                envelope.hops += 1;
+                let _ = send_packet(ctx, conn, &Packet::Envelope(envelope));
+            }
+            None => self.unreachable(ctx, envelope),
+        }
+    }
+
+    // Tell the sender its envelope went nowhere, unless that is itself a
+    // notice, those could bounce around forever
+    fn unreachable(&self, ctx: &Context, envelope: Envelope) {
+        if let Body::Unreachable(_) = envelope.body {
+            return;
+        }
+        let notice = Envelope::new(self.node, envelope.src, Body::Unreachable(envelope.dst));
+        if let Some(conn) = self.route(envelope.src) {
+            let _ = send_packet(ctx, conn, &Packet::Envelope(notice));
+        }
+    }
+
+    fn now_us(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    fn pong(&mut self, conn: usize, sent_us: u64) {
+        let rtt = self.now_us().saturating_sub(sent_us);
+        self.rtts.insert(conn, rtt);
+    }
+
+    fn gossip(&mut self, ctx: &Context) {
+        let ping = Packet::Ping(self.now_us());
+        for conn in self.table.neighbor_conns() {
+            let _ = send_packet(ctx, conn, &ping);
+        }
+
+        let links = self
+            .table
+            .links()
+            .map(|(node, conn)| Link {
+                node,
+                rtt_us: self.rtts.get(&conn).copied(),
+            })
+            .collect();
+        let mut roles = self.roles.clone();
+        if self.relay {
+            roles.push("relay".into());
+        }
+        self.nodemap
+            .update_local(ctx.listen_addrs().collect(), roles, links);
+        self.nodemap.expire(self.gossip_interval * 10);
+
+        for batch in self.nodemap.gossip() {
+            let packet = Packet::NodeMap(batch);
+            for conn in self.table.neighbor_conns() {
+                let _ = send_packet(ctx, conn, &packet);
+            }
+        }
+        ctx.set_timeout(timer_token(TIMER_GOSSIP, 0), self.gossip_interval);
+    }
+
+    fn update_reachable(&mut self) -> (Vec<NodeId>, Vec<NodeId>) {
+        let reachable: HashSet<NodeId> = self.table.routes().keys().copied().collect();
+        let up = reachable.difference(&self.reachable).copied().collect();
+        let down = self.reachable.difference(&reachable).copied().collect();
+        self.reachable = reachable;
+        (up, down)
+    }
+}
+
+pub struct Mesh<A: MeshApp> {
+    app: A,
+    state: MeshState,
+}
+
+pub fn new_mesh<A: MeshApp>(node: NodeId, app: A) -> Core<Mesh<A>> {
+    Core::new(Mesh::new(node, app))
+}
+
+impl<A: MeshApp> Mesh<A> {
+    pub fn new(node: NodeId, app: A) -> Self {
+        let state = MeshState::new(node);
+        Mesh { app, state }
+    }
+
+    // Advertise routes through this node and forward frames for others
+    pub fn relay(mut self, relay: bool) -> Self {
+        self.state.relay = relay;
+        self
+    }
+
+    // Free-form role advertised in the nodemap
+    pub fn role(mut self, role: &str) -> Self {
+        self.state.roles.push(role.into());
+        self
+    }
+
+    pub fn gossip_interval(mut self, interval: Duration) -> Self {
+        self.state.gossip_interval = interval;
+        self
+    }
+
+    fn hello(&self, ctx: &Context, conn: usize) {
+        let hello = Hello {
+            node: self.state.node,
+            relay: self.state.relay,
+        };
+        let _ = send_packet(ctx, conn, &Packet::Hello(hello));
+    }
+
+    fn refresh(&mut self, ctx: &Context) {
+        let (up, down) = self.state.update_reachable();
+        let mut ctx = MeshContext {
+            ctx,
+            state: &mut self.state,
+        };
+        for node in down {
+            self.app.handle_node_down(&mut ctx, node);
+        }
+        for node in up {
+            self.app.handle_node_up(&mut ctx, node);
+        }
+    }
+}
+
+impl<A: MeshApp> App for Mesh<A> {
+    fn handle_init(&mut self, ctx: &Context) {
+        self.state.gossip(ctx);
+        let mut ctx = MeshContext {
+            ctx,
+            state: &mut self.state,
+        };
+        self.app.handle_init(&mut ctx)
+    }
+    fn handle_connect(&mut self, ctx: &Context, id: usize) {
+        self.hello(ctx, id);
+    }
+    fn handle_accept(&mut self, ctx: &Context, _listen_socket: usize, id: usize) {
+        self.hello(ctx, id);
+    }
+    fn handle_close(&mut self, ctx: &Context, id: usize) {
+        self.state.closed(ctx, id);
+        self.refresh(ctx);
+    }
+    fn handle_frames(&mut self, ctx: &Context, id: usize, frames: Vec<Bytes>) {
+        let mut delivered: Vec<(NodeId, Bytes)> = vec![];
+        let mut published: Vec<Publication> = vec![];
+        let mut unreachable: Vec<NodeId> = vec![];
+        for frame in frames {
+            let packet = match Packet::decode(&frame) {
+                Ok(packet) => packet,
+                Err(_e) => {
+                    // XXX app.handle_decode_error?
+                    continue;
+                }
+            };
+            match packet {
+                Packet::Hello(hello) => self.state.hello(ctx, id, hello),
+                Packet::Routes(routes) => self.state.routes(ctx, id, routes),
+                Packet::Ping(sent_us) => {
+                    let _ = send_packet(ctx, id, &Packet::Pong(sent_us));
+                }
+                Packet::Pong(sent_us) => self.state.pong(id, sent_us),
+                Packet::NodeMap(infos) => self.state.nodemap.merge(infos),
+                Packet::Interest(patterns) => self.state.interest(ctx, id, patterns),
+                Packet::Publish(publication) => {
+                    if let Some(publication) = self.state.published(ctx, id, publication) {
+                        published.push(publication);
+                    }
+                }
+                Packet::Envelope(envelope) => {
+                    if envelope.dst != self.state.node {
+                        self.state.forward(ctx, envelope);
+                        continue;
+                    }
+                    match envelope.body {
+                        Body::Data(data) => delivered.push((envelope.src, data.into())),
+                        Body::PublishAck(seq) => self.state.publish_ack(envelope.src, seq),
+                        Body::Unreachable(dst) => unreachable.push(dst),
+                    }
+                }
+            }
+        }
+        self.refresh(ctx);
+
+        let mut ctx = MeshContext {
+            ctx,
+            state: &mut self.state,
+        };
+        let mut delivered = delivered.into_iter().peekable();
+        while let Some((src, frame)) = delivered.next() {
+            let mut frames = vec![frame];
+            while let Some((_, frame)) = delivered.next_if(|(next, _)| *next == src) {
+                frames.push(frame);
+            }
+            self.app.handle_frames(&mut ctx, src, frames);
+        }
+        for publication in published {
+            let payload = publication.payload.into();
+            self.app
+                .handle_publish(&mut ctx, publication.src, &publication.topic, payload);
+        }
+        for dst in unreachable {
+            self.app.handle_unreachable(&mut ctx, dst);
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &Context, token: usize) {
+        match token & ((1 << TIMER_BITS) - 1) {
+            TIMER_GOSSIP => self.state.gossip(ctx),
+            TIMER_PUBLISH => {
+                let id = (token >> TIMER_BITS) as u64;
+                if let Some(acked_by) = self.state.publish_timeout(ctx, id) {
+                    let mut ctx = MeshContext {
+                        ctx,
+                        state: &mut self.state,
+                    };
+                    self.app.handle_publish_acks(&mut ctx, id, acked_by);
+                }
+            }
+            _ => {
+                let mut ctx = MeshContext {
+                    ctx,
+                    state: &mut self.state,
+                };
+                self.app.handle_timeout(&mut ctx, token >> TIMER_BITS);
+            }
+        }
+    }
+    fn handle_shutdown(&mut self) {
+        self.app.handle_shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NodeMapSnapshot;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    struct Sender {
+        dst: NodeId,
+    }
+
+    impl MeshApp for Sender {
+        fn handle_node_up(&mut self, ctx: &mut MeshContext, node: NodeId) {
+            if node == self.dst {
+                ctx.send_to_node(node, "through the relay").unwrap();
+            }
+        }
+    }
+
+    struct Receiver {
+        received: Rc<RefCell<Vec<(NodeId, Bytes)>>>,
+    }
+
+    impl MeshApp for Receiver {
+        fn handle_frames(&mut self, _ctx: &mut MeshContext, src: NodeId, frames: Vec<Bytes>) {
+            let mut received = self.received.borrow_mut();
+            received.extend(frames.into_iter().map(|frame| (src, frame)));
+        }
+    }
+
+    #[test]
+    fn relays_one_hop() {
+        let (a, b, c) = (NodeId(1), NodeId(2), NodeId(3));
+        let addr = "127.0.0.1:13301";
+        let received = Rc::new(RefCell::new(vec![]));
+
+        let mut relay = Core::new(
+            Mesh::new(
+                b,
+                Receiver {
+                    received: Rc::new(RefCell::new(vec![])),
+                },
+            )
+            .relay(true),
+        );
+        relay.listen(addr).unwrap();
+        let mut sender = new_mesh(a, Sender { dst: c });
+        sender.connect(addr).unwrap();
+        let mut receiver = new_mesh(
+            c,
+            Receiver {
+                received: received.clone(),
+            },
+        );
+        receiver.connect(addr).unwrap();
+
+        let tick = Some(Duration::from_millis(10));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.borrow().is_empty() && Instant::now() < deadline {
+            relay.turn(tick).unwrap();
+            sender.turn(tick).unwrap();
+            receiver.turn(tick).unwrap();
+        }
+        assert_eq!(
+            *received.borrow(),
+            vec![(a, Bytes::from("through the relay"))]
+        );
+    }
+
+    struct Stray {
+        relay: NodeId,
+        unreachable: Rc<RefCell<Vec<NodeId>>>,
+    }
+
+    impl MeshApp for Stray {
+        fn handle_node_up(&mut self, ctx: &mut MeshContext, node: NodeId) {
+            if node != self.relay {
+                return;
+            }
+            assert!(ctx.send_to_node(node, vec![0; 70_000]).is_err());
+            assert!(ctx.publish("chat", vec![0; 70_000]).is_err());
+            // send_to_node won't address a node without a route, the relay
+            // has to see it for itself
+            let conn = ctx.state.route(node).unwrap();
+            let envelope = Envelope::new(ctx.node_id(), NodeId(99), Body::Data(vec![]));
+            send_packet(ctx.ctx, conn, &Packet::Envelope(envelope)).unwrap();
+        }
+        fn handle_unreachable(&mut self, _ctx: &mut MeshContext, dst: NodeId) {
+            self.unreachable.borrow_mut().push(dst);
+        }
+    }
+
+    #[test]
+    fn relay_reports_unroutable_envelopes() {
+        let (a, b) = (NodeId(1), NodeId(2));
+        let addr = "127.0.0.1:13330";
+        let unreachable = Rc::new(RefCell::new(vec![]));
+
+        let mut relay = Core::new(
+            Mesh::new(
+                b,
+                Receiver {
+                    received: Rc::new(RefCell::new(vec![])),
+                },
+            )
+            .relay(true),
+        );
+        relay.listen(addr).unwrap();
+        let mut sender = new_mesh(
+            a,
+            Stray {
+                relay: b,
+                unreachable: unreachable.clone(),
+            },
+        );
+        sender.connect(addr).unwrap();
+
+        let tick = Some(Duration::from_millis(10));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while unreachable.borrow().is_empty() && Instant::now() < deadline {
+            relay.turn(tick).unwrap();
+            sender.turn(tick).unwrap();
+        }
+        assert_eq!(*unreachable.borrow(), vec![NodeId(99)]);
+    }
+
+    #[test]
+    fn routes_multiple_hops() {
+        let (a, b, c, d) = (NodeId(1), NodeId(2), NodeId(3), NodeId(4));
+        let (addr_b, addr_c) = ("127.0.0.1:13302", "127.0.0.1:13303");
+        let received = Rc::new(RefCell::new(vec![]));
+        let idle = || Receiver {
+            received: Rc::new(RefCell::new(vec![])),
+        };
+
+        let mut relay_b = Core::new(Mesh::new(b, idle()).relay(true));
+        relay_b.listen(addr_b).unwrap();
+        let mut relay_c = Core::new(Mesh::new(c, idle()).relay(true));
+        relay_c.listen(addr_c).unwrap();
+        relay_c.connect(addr_b).unwrap();
+        let mut sender = new_mesh(a, Sender { dst: d });
+        sender.connect(addr_b).unwrap();
+        let mut receiver = new_mesh(
+            d,
+            Receiver {
+                received: received.clone(),
+            },
+        );
+        receiver.connect(addr_c).unwrap();
+
+        let tick = Some(Duration::from_millis(10));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.borrow().is_empty() && Instant::now() < deadline {
+            relay_b.turn(tick).unwrap();
+            relay_c.turn(tick).unwrap();
+            sender.turn(tick).unwrap();
+            receiver.turn(tick).unwrap();
+        }
+        assert_eq!(
+            *received.borrow(),
+            vec![(a, Bytes::from("through the relay"))]
+        );
+    }
+
+    struct Watcher {
+        snapshot: Rc<RefCell<Option<NodeMapSnapshot>>>,
+    }
+
+    impl MeshApp for Watcher {
+        fn handle_init(&mut self, ctx: &mut MeshContext) {
+            ctx.set_timeout(0, Duration::from_millis(10));
+        }
+        fn handle_timeout(&mut self, ctx: &mut MeshContext, _token: usize) {
+            *self.snapshot.borrow_mut() = Some(ctx.nodemap().snapshot());
+            ctx.set_timeout(0, Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn gossips_nodemap() {
+        let (a, b, c) = (NodeId(1), NodeId(2), NodeId(3));
+        let addr = "127.0.0.1:13304";
+        let snapshot = Rc::new(RefCell::new(None));
+        let interval = Duration::from_millis(20);
+        let idle = || Receiver {
+            received: Rc::new(RefCell::new(vec![])),
+        };
+
+        let mut relay = Core::new(Mesh::new(b, idle()).relay(true).gossip_interval(interval));
+        relay.listen(addr).unwrap();
+        let mut watcher = Core::new(
+            Mesh::new(
+                a,
+                Watcher {
+                    snapshot: snapshot.clone(),
+                },
+            )
+            .gossip_interval(interval),
+        );
+        watcher.connect(addr).unwrap();
+        let mut far = Core::new(Mesh::new(c, idle()).role("far").gossip_interval(interval));
+        far.connect(addr).unwrap();
+
+        let converged = |snapshot: &Option<NodeMapSnapshot>| {
+            snapshot.as_ref().is_some_and(|snapshot| {
+                let far = snapshot.nodes.iter().find(|n| n.info.node == c);
+                let relay = snapshot.nodes.iter().find(|n| n.info.node == b);
+                far.is_some_and(|far| !far.direct && far.info.roles == vec!["far"])
+                    && relay.is_some_and(|relay| {
+                        relay.direct && relay.info.links.iter().all(|l| l.rtt_us.is_some())
+                    })
+            })
+        };
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !converged(&snapshot.borrow()) && Instant::now() < deadline {
+            relay.turn(tick).unwrap();
+            watcher.turn(tick).unwrap();
+            far.turn(tick).unwrap();
+        }
+        assert!(converged(&snapshot.borrow()));
+    }
+
+    struct Subscriber {
+        pattern: &'static str,
+        received: Rc<RefCell<Vec<(NodeId, String)>>>,
+    }
+
+    impl MeshApp for Subscriber {
+        fn handle_init(&mut self, ctx: &mut MeshContext) {
+            ctx.subscribe(self.pattern);
+        }
+        fn handle_publish(
+            &mut self,
+            _ctx: &mut MeshContext,
+            src: NodeId,
+            topic: &str,
+            _payload: Bytes,
+        ) {
+            self.received.borrow_mut().push((src, topic.into()));
+        }
+    }
+
+    struct Publisher;
+
+    impl MeshApp for Publisher {
+        fn handle_init(&mut self, ctx: &mut MeshContext) {
+            ctx.set_timeout(0, Duration::from_millis(10));
+        }
+        fn handle_timeout(&mut self, ctx: &mut MeshContext, _token: usize) {
+            ctx.publish("chat.general", "hi").unwrap();
+            ctx.set_timeout(0, Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn publishes_to_subscribers_only() {
+        let addr = "127.0.0.1:13305";
+        let chat = Rc::new(RefCell::new(vec![]));
+        let news = Rc::new(RefCell::new(vec![]));
+
+        let mut publisher = new_mesh(NodeId(1), Publisher);
+        publisher.listen(addr).unwrap();
+        let mut chat_node = new_mesh(
+            NodeId(2),
+            Subscriber {
+                pattern: "chat.>",
+                received: chat.clone(),
+            },
+        );
+        chat_node.connect(addr).unwrap();
+        let mut news_node = new_mesh(
+            NodeId(3),
+            Subscriber {
+                pattern: "news",
+                received: news.clone(),
+            },
+        );
+        news_node.connect(addr).unwrap();
+
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while chat.borrow().len() < 3 && Instant::now() < deadline {
+            publisher.turn(tick).unwrap();
+            chat_node.turn(tick).unwrap();
+            news_node.turn(tick).unwrap();
+        }
+        assert!(chat.borrow().len() >= 3);
+        assert!(chat
+            .borrow()
+            .iter()
+            .all(|(src, topic)| *src == NodeId(1) && topic == "chat.general"));
+        assert!(news.borrow().is_empty());
+    }
+
+    struct AckedPublisher {
+        dst: NodeId,
+        acks: Rc<RefCell<Option<Vec<NodeId>>>>,
+    }
+
+    impl MeshApp for AckedPublisher {
+        fn handle_node_up(&mut self, ctx: &mut MeshContext, node: NodeId) {
+            if node == self.dst {
+                // Give the subscription a moment to catch up with the route
+                ctx.set_timeout(0, Duration::from_millis(50));
+            }
+        }
+        fn handle_timeout(&mut self, ctx: &mut MeshContext, _token: usize) {
+            ctx.publish_acked("chat.general", "hi", Duration::from_millis(100))
+                .unwrap();
+        }
+        fn handle_publish_acks(&mut self, _ctx: &mut MeshContext, _id: u64, acked_by: Vec<NodeId>) {
+            *self.acks.borrow_mut() = Some(acked_by);
+        }
+    }
+
+    #[test]
+    fn publishes_across_cycles_once() {
+        // a and d are both connected to relays b and c, so every message has two paths
+        let (a, b, c, d) = (NodeId(1), NodeId(2), NodeId(3), NodeId(4));
+        let (addr_b, addr_c) = ("127.0.0.1:13306", "127.0.0.1:13307");
+        let received = Rc::new(RefCell::new(vec![]));
+        let acks = Rc::new(RefCell::new(None));
+        let idle = || Receiver {
+            received: Rc::new(RefCell::new(vec![])),
+        };
+
+        let mut relay_b = Core::new(Mesh::new(b, idle()).relay(true));
+        relay_b.listen(addr_b).unwrap();
+        let mut relay_c = Core::new(Mesh::new(c, idle()).relay(true));
+        relay_c.listen(addr_c).unwrap();
+        let mut publisher = new_mesh(
+            a,
+            AckedPublisher {
+                dst: d,
+                acks: acks.clone(),
+            },
+        );
+        publisher.connect(addr_b).unwrap();
+        publisher.connect(addr_c).unwrap();
+        let mut subscriber = new_mesh(
+            d,
+            Subscriber {
+                pattern: "chat.>",
+                received: received.clone(),
+            },
+        );
+        subscriber.connect(addr_b).unwrap();
+        subscriber.connect(addr_c).unwrap();
+
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while acks.borrow().is_none() && Instant::now() < deadline {
+            relay_b.turn(tick).unwrap();
+            relay_c.turn(tick).unwrap();
+            publisher.turn(tick).unwrap();
+            subscriber.turn(tick).unwrap();
+        }
+        assert_eq!(*acks.borrow(), Some(vec![d]));
+        assert_eq!(*received.borrow(), vec![(a, "chat.general".to_string())]);
+    }
+}
@@ -0,0 +1,257 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::NodeId;
+
+// Well under a frame, so a batch never has to be split again
+const GOSSIP_BATCH_SIZE: u64 = 32 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    pub node: NodeId,
+    pub rtt_us: Option<u64>,
+}
+
+// What a node says about itself, gossiped across the mesh
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeInfo {
+    pub node: NodeId,
+    // Bumped by the owner on every gossip round, the highest version wins.
+    // Never below the owner's wall clock in ms, so it survives restarts.
+    pub version: u64,
+    pub addrs: Vec<SocketAddr>,
+    pub roles: Vec<String>,
+    pub links: Vec<Link>,
+}
+
+struct Entry {
+    info: NodeInfo,
+    last_seen: SystemTime,
+}
+
+pub struct NodeMap {
+    node: NodeId,
+    entries: HashMap<NodeId, Entry>,
+}
+
+impl NodeMap {
+    pub(crate) fn new(node: NodeId) -> Self {
+        NodeMap {
+            node,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, node: NodeId) -> Option<&NodeInfo> {
+        self.entries.get(&node).map(|entry| &entry.info)
+    }
+    pub fn last_seen(&self, node: NodeId) -> Option<SystemTime> {
+        self.entries.get(&node).map(|entry| entry.last_seen)
+    }
+    pub fn nodes<'a>(&'a self) -> impl Iterator<Item = &'a NodeInfo> + 'a {
+        self.entries.values().map(|entry| &entry.info)
+    }
+    pub fn with_role<'a>(&'a self, role: &'a str) -> impl Iterator<Item = &'a NodeInfo> + 'a {
+        self.nodes()
+            .filter(move |info| info.roles.iter().any(|r| r == role))
+    }
+    pub fn is_direct(&self, node: NodeId) -> bool {
+        self.get(self.node)
+            .is_some_and(|info| info.links.iter().any(|link| link.node == node))
+    }
+
+    pub fn snapshot(&self) -> NodeMapSnapshot {
+        let mut nodes: Vec<NodeSnapshot> = self
+            .entries
+            .values()
+            .map(|entry| NodeSnapshot {
+                info: entry.info.clone(),
+                direct: self.is_direct(entry.info.node),
+                last_seen_ms: unix_ms(entry.last_seen),
+            })
+            .collect();
+        nodes.sort_by_key(|snapshot| snapshot.info.node);
+        NodeMapSnapshot {
+            node: self.node,
+            taken_at_ms: unix_ms(SystemTime::now()),
+            nodes,
+        }
+    }
+
+    pub(crate) fn update_local(
+        &mut self,
+        addrs: Vec<SocketAddr>,
+        roles: Vec<String>,
+        links: Vec<Link>,
+    ) {
+        let last_seen = SystemTime::now();
+        let previous = self.get(self.node).map_or(0, |info| info.version + 1);
+        let version = previous.max(unix_ms(last_seen));
+        let info = NodeInfo {
+            node: self.node,
+            version,
+            addrs,
+            roles,
+            links,
+        };
+        self.entries.insert(self.node, Entry { info, last_seen });
+    }
+
+    pub(crate) fn merge(&mut self, infos: Vec<NodeInfo>) {
+        let now = SystemTime::now();
+        for info in infos {
+            if info.node == self.node {
+                continue;
+            }
+            let newer = self
+                .get(info.node)
+                .is_none_or(|known| info.version > known.version);
+            if newer {
+                let entry = Entry {
+                    info,
+                    last_seen: now,
+                };
+                self.entries.insert(entry.info.node, entry);
+            }
+        }
+    }
+
+    pub(crate) fn expire(&mut self, max_age: Duration) {
+        let node = self.node;
+        let now = SystemTime::now();
+        self.entries.retain(|id, entry| {
+            *id == node
+                || now
+                    .duration_since(entry.last_seen)
+                    .map_or(true, |age| age < max_age)
+        });
+    }
+
+    // The map split into batches that each fit in a frame, merge is per
+    // entry so they can be applied in any order
+    pub(crate) fn gossip(&self) -> Vec<Vec<NodeInfo>> {
+        let mut batches = vec![];
+        let mut batch = vec![];
+        let mut size = 0;
+        for info in self.nodes() {
+            let len = bincode::serialized_size(info).unwrap_or(0);
+            if !batch.is_empty() && size + len > GOSSIP_BATCH_SIZE {
+                batches.push(std::mem::take(&mut batch));
+                size = 0;
+            }
+            batch.push(info.clone());
+            size += len;
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+        batches
+    }
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeSnapshot {
+    #[serde(flatten)]
+    pub info: NodeInfo,
+    pub direct: bool,
+    pub last_seen_ms: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeMapSnapshot {
+    pub node: NodeId,
+    pub taken_at_ms: u64,
+    pub nodes: Vec<NodeSnapshot>,
+}
+
+impl NodeMapSnapshot {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(node: u64, version: u64, links: &[u64]) -> NodeInfo {
+        NodeInfo {
+            node: NodeId(node),
+            version,
+            addrs: vec!["127.0.0.1:13265".parse().unwrap()],
+            roles: vec!["relay".into()],
+            links: links
+                .iter()
+                .map(|n| Link {
+                    node: NodeId(*n),
+                    rtt_us: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn merge_keeps_newest_version() {
+        let mut map = NodeMap::new(NodeId(1));
+        map.merge(vec![info(2, 5, &[3]), info(1, 100, &[])]);
+        map.merge(vec![info(2, 4, &[])]);
+        assert_eq!(map.get(NodeId(2)).unwrap().version, 5);
+        assert!(map.get(NodeId(1)).is_none());
+        map.merge(vec![info(2, 6, &[])]);
+        assert!(map.get(NodeId(2)).unwrap().links.is_empty());
+        assert_eq!(map.with_role("relay").count(), 1);
+    }
+
+    #[test]
+    fn versions_keep_rising_across_restarts() {
+        let mut before = NodeMap::new(NodeId(1));
+        for _ in 0..3 {
+            before.update_local(vec![], vec![], vec![]);
+        }
+        let old = before.get(NodeId(1)).unwrap().version;
+        std::thread::sleep(Duration::from_millis(10));
+        let mut restarted = NodeMap::new(NodeId(1));
+        restarted.update_local(vec![], vec![], vec![]);
+        assert!(restarted.get(NodeId(1)).unwrap().version > old);
+    }
+
+    #[test]
+    fn gossip_splits_into_frame_sized_batches() {
+        let mut map = NodeMap::new(NodeId(1));
+        map.merge((2..2002).map(|node| info(node, 1, &[1])).collect());
+        let batches = map.gossip();
+        assert!(batches.len() > 1);
+        for batch in &batches {
+            assert!(bincode::serialized_size(batch).unwrap() <= GOSSIP_BATCH_SIZE + 8);
+        }
+        assert_eq!(batches.iter().map(Vec::len).sum::<usize>(), 2000);
+    }
+
+    #[test]
+    fn snapshot_serializes_to_json() {
+        let mut map = NodeMap::new(NodeId(1));
+        map.update_local(
+            vec![],
+            vec![],
+            vec![Link {
+                node: NodeId(2),
+                rtt_us: Some(250),
+            }],
+        );
+        map.merge(vec![info(2, 1, &[1])]);
+        let snapshot = map.snapshot();
+        assert!(snapshot.nodes[1].direct);
+        let json = snapshot.to_json().unwrap();
+        let parsed: NodeMapSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.nodes[1].info, info(2, 1, &[1]));
+        assert!(json.contains("\"rtt_us\": 250"));
+    }
+}
@@ -0,0 +1,98 @@
+use bytes::Bytes;
+use failure::{bail, Error};
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+use crate::nodemap::NodeInfo;
+
+pub const DEFAULT_TTL: u8 = crate::routing::MAX_METRIC;
+// Packets travel as single frames
+pub const MAX_PACKET: usize = u16::MAX as usize;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct NodeId(pub u64);
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x}", self.0)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Packet {
+    Hello(Hello),
+    // Full distance-vector table of a relay, (destination, metric)
+    Routes(Vec<(NodeId, u8)>),
+    Envelope(Envelope),
+    // Sender's clock in microseconds, echoed back to measure round trips
+    Ping(u64),
+    Pong(u64),
+    NodeMap(Vec<NodeInfo>),
+    // Every pattern the sender wants to hear about, for itself or for nodes
+    // behind it, (pattern, hops to the nearest subscriber)
+    Interest(Vec<(String, u8)>),
+    Publish(Publication),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Hello {
+    pub node: NodeId,
+    pub relay: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    pub src: NodeId,
+    pub dst: NodeId,
+    pub ttl: u8,
+    pub hops: u8,
+    pub body: Body,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Body {
+    Data(Vec<u8>),
+    // A subscriber received the publication with this sequence number
+    PublishAck(u64),
+    // A relay had nowhere to send the sender's envelope for this node
+    Unreachable(NodeId),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Publication {
+    pub src: NodeId,
+    // (src, seq) identifies the message, attempt is bumped on every retry
+    pub seq: u64,
+    pub attempt: u8,
+    pub ttl: u8,
+    // Subscribers should answer with Body::PublishAck
+    pub ack: bool,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn new(src: NodeId, dst: NodeId, body: Body) -> Self {
+        Envelope {
+            src,
+            dst,
+            ttl: DEFAULT_TTL,
+            hops: 0,
+            body,
+        }
+    }
+}
+
+impl Packet {
+    pub fn encode(&self) -> Result<Bytes, Error> {
+        let frame = bincode::serialize(self).expect("Packet serialization can't fail");
+        if frame.len() > MAX_PACKET {
+            bail!("Packet of {} bytes doesn't fit in a frame", frame.len());
+        }
+        Ok(frame.into())
+    }
+    pub fn decode(frame: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::deserialize(frame)
+    }
+}
@@ -0,0 +1,608 @@
+use bytes::Bytes;
+use failure::{bail, format_err, Error};
+use mio_framed::{Ready, UnixReady};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use crate::exec::{set_nonblocking, ExitStatus};
+use crate::service::{Service, ServiceContext};
+
+const CHUNK_SIZE: usize = 32 * 1024;
+const TIMER_REAP: usize = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WindowSize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl WindowSize {
+    fn winsize(self) -> libc::winsize {
+        libc::winsize {
+            ws_row: self.rows,
+            ws_col: self.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PtyRequest {
+    // `program` must be on the service's allowlist, it runs as a session
+    // leader with the pty as its controlling terminal
+    Open {
+        id: u64,
+        program: String,
+        args: Vec<String>,
+        term: Option<String>,
+        size: WindowSize,
+    },
+    Input {
+        id: u64,
+        data: Vec<u8>,
+    },
+    Resize {
+        id: u64,
+        size: WindowSize,
+    },
+    // Hangs up the terminal, the session ends with Exited as usual
+    Close {
+        id: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PtyResponse {
+    Opened { id: u64, pid: u32 },
+    Output { id: u64, data: Vec<u8> },
+    // Always the last response for a session, after all of its output
+    Exited { id: u64, status: ExitStatus },
+    Error { id: u64, message: String },
+}
+
+impl PtyResponse {
+    pub fn id(&self) -> u64 {
+        match self {
+            PtyResponse::Opened { id, .. }
+            | PtyResponse::Output { id, .. }
+            | PtyResponse::Exited { id, .. }
+            | PtyResponse::Error { id, .. } => *id,
+        }
+    }
+}
+
+fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    bincode::serialize(message).expect("Pty message serialization can't fail")
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Returns the master and slave ends
+fn open_pty(size: WindowSize) -> io::Result<(File, File)> {
+    let (mut master, mut slave) = (0, 0);
+    let winsize = size.winsize();
+    let opened = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &winsize,
+        )
+    };
+    if opened < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    // Neither end should leak into other children
+    set_cloexec(master.as_raw_fd())?;
+    set_cloexec(slave.as_raw_fd())?;
+    Ok((master, slave))
+}
+
+struct Session {
+    conn: usize,
+    id: u64,
+    child: Child,
+    // None once the slave side is gone
+    master: Option<File>,
+    input_queue: Vec<u8>,
+    status: Option<std::process::ExitStatus>,
+}
+
+impl Session {
+    fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Interactive sessions for peers: each runs an allowlisted program on its own
+// pseudo-terminal, with the master side watched by the Core's Poll.
+pub struct PtyService {
+    allowed: HashSet<String>,
+    poll_interval: Duration,
+    next_slot: usize,
+    sessions: HashMap<usize, Session>,
+    by_request: HashMap<(usize, u64), usize>,
+    reaping: bool,
+}
+
+impl Default for PtyService {
+    fn default() -> Self {
+        PtyService {
+            allowed: HashSet::new(),
+            poll_interval: Duration::from_millis(50),
+            next_slot: 0,
+            sessions: HashMap::new(),
+            by_request: HashMap::new(),
+            reaping: false,
+        }
+    }
+}
+
+impl PtyService {
+    pub fn new() -> Self {
+        PtyService::default()
+    }
+
+    pub fn allow(mut self, program: &str) -> Self {
+        self.allowed.insert(program.into());
+        self
+    }
+
+    // How often sessions are checked for exits
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    fn send(ctx: &ServiceContext, conn: usize, response: &PtyResponse) {
+        // The peer may not run a pty client, then there's nobody to tell
+        let _ = ctx.send(conn, encode(response));
+    }
+
+    fn handle_request(&mut self, ctx: &ServiceContext, conn: usize, request: PtyRequest) {
+        let id = match &request {
+            PtyRequest::Open { id, .. }
+            | PtyRequest::Input { id, .. }
+            | PtyRequest::Resize { id, .. }
+            | PtyRequest::Close { id } => *id,
+        };
+        if let Err(e) = self.run(ctx, conn, request) {
+            let message = e.to_string();
+            Self::send(ctx, conn, &PtyResponse::Error { id, message });
+        }
+    }
+
+    fn run(&mut self, ctx: &ServiceContext, conn: usize, request: PtyRequest) -> Result<(), Error> {
+        match request {
+            PtyRequest::Open {
+                id,
+                program,
+                args,
+                term,
+                size,
+            } => self.open(ctx, conn, id, program, args, term, size),
+            PtyRequest::Input { id, data } => {
+                let slot = self.slot(conn, id)?;
+                let session = self.sessions.get_mut(&slot).expect("Slot without session");
+                if session.master.is_none() {
+                    bail!("Terminal of {} is closed", id);
+                }
+                session.input_queue.extend_from_slice(&data);
+                self.flush(ctx, slot);
+                Ok(())
+            }
+            PtyRequest::Resize { id, size } => {
+                let slot = self.slot(conn, id)?;
+                if let Some(master) = &self.sessions[&slot].master {
+                    // The kernel sends SIGWINCH to the foreground process group
+                    let winsize = size.winsize();
+                    let fd = master.as_raw_fd();
+                    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &winsize) } < 0 {
+                        return Err(io::Error::last_os_error().into());
+                    }
+                }
+                Ok(())
+            }
+            PtyRequest::Close { id } => {
+                let slot = self.slot(conn, id)?;
+                let session = &self.sessions[&slot];
+                if session.status.is_none() {
+                    let pid = session.child.id() as libc::pid_t;
+                    unsafe { libc::kill(pid, libc::SIGHUP) };
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn slot(&self, conn: usize, id: u64) -> Result<usize, Error> {
+        self.by_request
+            .get(&(conn, id))
+            .copied()
+            .ok_or_else(|| format_err!("No open session {}", id))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn open(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        id: u64,
+        program: String,
+        args: Vec<String>,
+        term: Option<String>,
+        size: WindowSize,
+    ) -> Result<(), Error> {
+        if !self.allowed.contains(&program) {
+            bail!("{} is not allowed", program);
+        }
+        if self.by_request.contains_key(&(conn, id)) {
+            bail!("Session {} already exists", id);
+        }
+        let (master, slave) = open_pty(size)?;
+        let mut command = Command::new(&program);
+        command
+            .args(&args)
+            .env("TERM", term.as_deref().unwrap_or("xterm"))
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+        unsafe {
+            command.pre_exec(|| {
+                // A new session, so the pty can become its controlling terminal
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = command.spawn()?;
+        // Only the child holds the slave now, so its exit shows up on the master
+        drop(command);
+        if let Err(e) = set_nonblocking(master.as_raw_fd()) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e.into());
+        }
+
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        let interest = Ready::readable() | UnixReady::hup();
+        ctx.watch_fd(slot, master.as_raw_fd(), interest);
+        let pid = child.id();
+        let session = Session {
+            conn,
+            id,
+            child,
+            master: Some(master),
+            input_queue: vec![],
+            status: None,
+        };
+        self.sessions.insert(slot, session);
+        self.by_request.insert((conn, id), slot);
+        Self::send(ctx, conn, &PtyResponse::Opened { id, pid });
+        if !self.reaping {
+            self.reaping = true;
+            ctx.set_timeout(TIMER_REAP, self.poll_interval);
+        }
+        Ok(())
+    }
+
+    // Read the master until it would block, forwarding everything
+    fn drain(&mut self, ctx: &ServiceContext, slot: usize) {
+        let session = match self.sessions.get_mut(&slot) {
+            Some(session) => session,
+            None => return,
+        };
+        let mut buf = vec![0; CHUNK_SIZE];
+        while let Some(master) = session.master.as_mut() {
+            match master.read(&mut buf) {
+                Ok(n) if n > 0 => {
+                    let (id, data) = (session.id, buf[..n].to_vec());
+                    Self::send(ctx, session.conn, &PtyResponse::Output { id, data });
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                // Linux reports EIO rather than end of file once the slave is closed
+                _ => {
+                    if let Some(master) = session.master.take() {
+                        ctx.close_fd(slot, master);
+                    }
+                    session.input_queue.clear();
+                    break;
+                }
+            }
+        }
+        self.check_finished(ctx, slot);
+    }
+
+    fn flush(&mut self, ctx: &ServiceContext, slot: usize) {
+        let session = match self.sessions.get_mut(&slot) {
+            Some(session) => session,
+            None => return,
+        };
+        let master = match session.master.as_mut() {
+            Some(master) => master,
+            None => return,
+        };
+        while !session.input_queue.is_empty() {
+            match master.write(&session.input_queue) {
+                Ok(n) => {
+                    session.input_queue.drain(..n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                // Anything else turns up as a read error too
+                Err(_) => break,
+            }
+        }
+        // One fd for both directions, so writable interest comes and goes
+        let mut interest = Ready::readable() | UnixReady::hup();
+        if !session.input_queue.is_empty() {
+            interest |= Ready::writable();
+        }
+        ctx.watch_fd(slot, master.as_raw_fd(), interest);
+    }
+
+    // Exited is only sent once the output is drained and the process reaped
+    fn check_finished(&mut self, ctx: &ServiceContext, slot: usize) {
+        let session = match self.sessions.get_mut(&slot) {
+            Some(session) => session,
+            None => return,
+        };
+        if session.master.is_some() {
+            // XXX A background job holding the terminal open keeps us waiting here
+            return;
+        }
+        if session.status.is_none() {
+            session.status = session.child.try_wait().ok().and_then(|status| status);
+        }
+        let status = match session.status {
+            Some(status) => status,
+            None => return,
+        };
+        let status = ExitStatus {
+            code: status.code(),
+            signal: status.signal(),
+            timed_out: false,
+        };
+        let (conn, id) = (session.conn, session.id);
+        self.sessions.remove(&slot);
+        self.by_request.remove(&(conn, id));
+        Self::send(ctx, conn, &PtyResponse::Exited { id, status });
+    }
+
+    fn reap(&mut self, ctx: &ServiceContext) {
+        let slots: Vec<usize> = self.sessions.keys().copied().collect();
+        for slot in slots {
+            if let Some(session) = self.sessions.get_mut(&slot) {
+                if session.status.is_none() {
+                    session.status = session.child.try_wait().ok().and_then(|status| status);
+                }
+            }
+            self.check_finished(ctx, slot);
+        }
+        self.reaping = !self.sessions.is_empty();
+        if self.reaping {
+            ctx.set_timeout(TIMER_REAP, self.poll_interval);
+        }
+    }
+}
+
+impl Service for PtyService {
+    fn handle_close(&mut self, ctx: &mut ServiceContext, conn: usize) {
+        let slots: Vec<usize> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.conn == conn)
+            .map(|(slot, _)| *slot)
+            .collect();
+        for slot in slots {
+            if let Some(mut session) = self.sessions.remove(&slot) {
+                self.by_request.remove(&(conn, session.id));
+                if let Some(master) = session.master.take() {
+                    ctx.close_fd(slot, master);
+                }
+                session.kill();
+            }
+        }
+    }
+    fn handle_frames(&mut self, ctx: &mut ServiceContext, conn: usize, frames: Vec<Bytes>) {
+        for frame in frames {
+            match bincode::deserialize(&frame) {
+                Ok(request) => self.handle_request(ctx, conn, request),
+                // XXX app.handle_decode_error?
+                Err(_e) => continue,
+            }
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &mut ServiceContext, _token: usize) {
+        self.reap(ctx);
+    }
+    fn handle_ready(&mut self, ctx: &mut ServiceContext, slot: usize, readiness: Ready) {
+        if readiness.is_writable() {
+            self.flush(ctx, slot);
+        }
+        if readiness.is_readable() || UnixReady::from(readiness).is_hup() {
+            self.drain(ctx, slot);
+        }
+    }
+    fn handle_shutdown(&mut self) {
+        for session in self.sessions.values_mut() {
+            session.kill();
+        }
+    }
+}
+
+// Builds requests for the "pty" service, for use in a client Service
+#[derive(Default)]
+pub struct PtyClient {
+    next_id: u64,
+}
+
+impl PtyClient {
+    pub fn new() -> Self {
+        PtyClient::default()
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<PtyResponse, Error> {
+        Ok(bincode::deserialize(frame)?)
+    }
+
+    fn send(ctx: &ServiceContext, conn: usize, request: &PtyRequest) -> Result<(), Error> {
+        ctx.send_to(conn, "pty", encode(request))
+    }
+
+    pub fn open(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        program: &str,
+        args: &[&str],
+        term: Option<&str>,
+        size: WindowSize,
+    ) -> Result<u64, Error> {
+        self.next_id += 1;
+        let request = PtyRequest::Open {
+            id: self.next_id,
+            program: program.into(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            term: term.map(String::from),
+            size,
+        };
+        Self::send(ctx, conn, &request)?;
+        Ok(self.next_id)
+    }
+    pub fn input(
+        &self,
+        ctx: &ServiceContext,
+        conn: usize,
+        id: u64,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let data = data.to_vec();
+        Self::send(ctx, conn, &PtyRequest::Input { id, data })
+    }
+    pub fn resize(
+        &self,
+        ctx: &ServiceContext,
+        conn: usize,
+        id: u64,
+        size: WindowSize,
+    ) -> Result<(), Error> {
+        Self::send(ctx, conn, &PtyRequest::Resize { id, size })
+    }
+    pub fn close(&self, ctx: &ServiceContext, conn: usize, id: u64) -> Result<(), Error> {
+        Self::send(ctx, conn, &PtyRequest::Close { id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ServiceHost;
+    use mio_framed::Core;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    type Responses = Rc<RefCell<Vec<PtyResponse>>>;
+
+    struct Terminal {
+        pty: PtyClient,
+        responses: Responses,
+    }
+
+    impl Service for Terminal {
+        fn handle_connect(&mut self, ctx: &mut ServiceContext, conn: usize) {
+            let pty = &mut self.pty;
+            let size = WindowSize { rows: 24, cols: 80 };
+            let id = pty.open(ctx, conn, "sh", &[], None, size).unwrap();
+            let size = WindowSize {
+                rows: 30,
+                cols: 100,
+            };
+            pty.resize(ctx, conn, id, size).unwrap();
+            let input = b"echo size=$(stty size); tty -s && exit 3\n";
+            pty.input(ctx, conn, id, input).unwrap();
+            pty.open(ctx, conn, "rm", &[], None, size).unwrap();
+        }
+        fn handle_frames(&mut self, _ctx: &mut ServiceContext, _conn: usize, frames: Vec<Bytes>) {
+            let mut responses = self.responses.borrow_mut();
+            for frame in frames {
+                responses.push(PtyClient::decode(&frame).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn runs_a_shell_on_a_pty() {
+        let addr = "127.0.0.1:13314";
+        let responses = Rc::new(RefCell::new(vec![]));
+        let service = PtyService::new()
+            .allow("sh")
+            .poll_interval(Duration::from_millis(5));
+        let mut server = Core::new(ServiceHost::new().offer("pty", service));
+        server.listen(addr).unwrap();
+        let terminal = Terminal {
+            pty: PtyClient::new(),
+            responses: responses.clone(),
+        };
+        let mut client = Core::new(ServiceHost::new().client("pty", terminal));
+        client.connect(addr).unwrap();
+
+        let done = |responses: &Responses| {
+            let responses = responses.borrow();
+            let exited = responses
+                .iter()
+                .any(|response| matches!(response, PtyResponse::Exited { .. }));
+            exited && responses.iter().any(|response| response.id() == 2)
+        };
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(&responses) && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+
+        let responses = responses.borrow();
+        let output: Vec<u8> = responses
+            .iter()
+            .flat_map(|response| match response {
+                PtyResponse::Output { data, .. } => data.clone(),
+                _ => vec![],
+            })
+            .collect();
+        let output = String::from_utf8_lossy(&output);
+        // The terminal echoes input as well, only the expansion has the answer
+        assert!(output.contains("size=30 100"), "{}", output);
+        let exited = responses.iter().find_map(|response| match response {
+            PtyResponse::Exited { status, .. } => Some(status.clone()),
+            _ => None,
+        });
+        assert_eq!(exited.unwrap().code, Some(3));
+        assert!(matches!(
+            responses.iter().find(|response| response.id() == 2),
+            Some(PtyResponse::Error { .. })
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+use crate::routing::MAX_METRIC;
+
+// Topics are dot separated, like "logs.web.error".
+// In patterns "*" matches exactly one segment and a trailing ">" matches
+// one or more, so "logs.>" is every topic under "logs".
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut topic = topic.split('.');
+    for segment in pattern.split('.') {
+        match (segment, topic.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (segment, Some(name)) if segment == name => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+// Which patterns each connection wants, plus our own interest.
+// Like routes, a neighbor sends its whole interest set whenever it changes,
+// and relays pass on everything they heard except back where it came from.
+// Each pattern carries its distance to the nearest subscriber, so interest
+// withdrawn somewhere in a loop counts up to MAX_METRIC and is dropped.
+#[derive(Default)]
+pub(crate) struct SubscriptionTable {
+    // Pattern to hops, counting the hop to us
+    by_conn: HashMap<usize, HashMap<String, u8>>,
+    local: HashSet<String>,
+    // Last interest set sent on each connection, to skip repeats
+    sent: HashMap<usize, Vec<(String, u8)>>,
+}
+
+impl SubscriptionTable {
+    pub fn interest(&mut self, conn: usize, patterns: Vec<(String, u8)>) -> bool {
+        let patterns: HashMap<String, u8> = patterns
+            .into_iter()
+            .map(|(pattern, hops)| (pattern, hops.saturating_add(1)))
+            .filter(|(_, hops)| *hops < MAX_METRIC)
+            .collect();
+        let changed = self.by_conn.get(&conn) != Some(&patterns);
+        if patterns.is_empty() {
+            self.by_conn.remove(&conn);
+        } else {
+            self.by_conn.insert(conn, patterns);
+        }
+        changed
+    }
+
+    pub fn remove_conn(&mut self, conn: usize) {
+        self.by_conn.remove(&conn);
+        self.sent.remove(&conn);
+    }
+
+    pub fn subscribe_local(&mut self, pattern: String) -> bool {
+        self.local.insert(pattern)
+    }
+
+    pub fn unsubscribe_local(&mut self, pattern: &str) -> bool {
+        self.local.remove(pattern)
+    }
+
+    pub fn matches_local(&self, topic: &str) -> bool {
+        self.local
+            .iter()
+            .any(|pattern| topic_matches(pattern, topic))
+    }
+
+    pub fn matching_conns(&self, topic: &str) -> Vec<usize> {
+        self.by_conn
+            .iter()
+            .filter(|(_, patterns)| patterns.keys().any(|p| topic_matches(p, topic)))
+            .map(|(conn, _)| *conn)
+            .collect()
+    }
+
+    // Split horizon, same as routes: interest never goes back where it came from.
+    // Each pattern goes out with the fewest hops it was heard at.
+    pub fn advertisement_for(&self, conn: usize, relay: bool) -> Vec<(String, u8)> {
+        let mut patterns: HashMap<&String, u8> =
+            self.local.iter().map(|pattern| (pattern, 0)).collect();
+        if relay {
+            for (other, interest) in self.by_conn.iter() {
+                if *other == conn {
+                    continue;
+                }
+                for (pattern, hops) in interest.iter() {
+                    let best = patterns.entry(pattern).or_insert(*hops);
+                    *best = (*best).min(*hops);
+                }
+            }
+        }
+        let mut patterns: Vec<(String, u8)> = patterns
+            .into_iter()
+            .map(|(pattern, hops)| (pattern.clone(), hops))
+            .collect();
+        patterns.sort();
+        patterns
+    }
+
+    // Returns true if `patterns` differs from what was last sent on `conn`
+    pub fn mark_sent(&mut self, conn: usize, patterns: &[(String, u8)]) -> bool {
+        if self
+            .sent
+            .get(&conn)
+            .is_some_and(|sent| sent[..] == *patterns)
+        {
+            return false;
+        }
+        self.sent.insert(conn, patterns.to_vec());
+        true
+    }
+}
+
+// Bounded set of recently seen message ids, oldest forgotten first
+pub(crate) struct SeenCache<K> {
+    capacity: usize,
+    seen: HashSet<K>,
+    order: VecDeque<K>,
+}
+
+impl<K: Clone + Eq + Hash> SeenCache<K> {
+    pub fn new(capacity: usize) -> Self {
+        SeenCache {
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // Returns false if `key` was already seen
+    pub fn insert(&mut self, key: K) -> bool {
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(topic_matches("logs.web", "logs.web"));
+        assert!(!topic_matches("logs.web", "logs.web.error"));
+        assert!(topic_matches("logs.*.error", "logs.web.error"));
+        assert!(!topic_matches("logs.*", "logs.web.error"));
+        assert!(topic_matches("logs.>", "logs.web.error"));
+        assert!(!topic_matches("logs.>", "logs"));
+        assert!(topic_matches(">", "anything"));
+        assert!(!topic_matches("logs.web.error", "logs.web"));
+    }
+
+    fn patterns(patterns: &[(&str, u8)]) -> Vec<(String, u8)> {
+        let patterns = patterns.iter();
+        patterns
+            .map(|(pattern, hops)| (pattern.to_string(), *hops))
+            .collect()
+    }
+
+    #[test]
+    fn table_cleans_up() {
+        let mut table = SubscriptionTable::default();
+        table.interest(1, patterns(&[("chat.>", 0)]));
+        table.interest(2, patterns(&[("chat.general", 0), ("news", 0)]));
+        let mut conns = table.matching_conns("chat.general");
+        conns.sort();
+        assert_eq!(conns, vec![1, 2]);
+        table.remove_conn(1);
+        assert!(table.interest(2, patterns(&[("news", 0)])));
+        assert!(!table.interest(2, patterns(&[("news", 0)])));
+        assert!(table.matching_conns("chat.general").is_empty());
+        assert_eq!(table.matching_conns("news"), vec![2]);
+        // Too far away to count
+        table.interest(3, patterns(&[("far", MAX_METRIC - 1)]));
+        assert!(table.matching_conns("far").is_empty());
+    }
+
+    #[test]
+    fn interest_split_horizon() {
+        let mut table = SubscriptionTable::default();
+        table.subscribe_local("local".into());
+        table.interest(1, patterns(&[("a", 0)]));
+        table.interest(2, patterns(&[("b", 0), ("local", 4)]));
+        assert_eq!(
+            table.advertisement_for(1, true),
+            patterns(&[("b", 1), ("local", 0)])
+        );
+        assert_eq!(table.advertisement_for(1, false), patterns(&[("local", 0)]));
+        let ad = table.advertisement_for(2, true);
+        assert!(table.mark_sent(2, &ad));
+        assert!(!table.mark_sent(2, &ad));
+    }
+
+    #[test]
+    fn withdrawn_interest_dies_out_in_a_ring() {
+        // Relays 0, 1 and 2 in a ring, conn 0 leads to the next one
+        // and conn 1 to the previous one
+        let mut tables: Vec<SubscriptionTable> =
+            (0..3).map(|_| SubscriptionTable::default()).collect();
+        let exchange = |tables: &mut Vec<SubscriptionTable>| {
+            for _ in 0..100 {
+                let mut sent = vec![];
+                for (node, table) in tables.iter_mut().enumerate() {
+                    for conn in 0..2 {
+                        let ad = table.advertisement_for(conn, true);
+                        if table.mark_sent(conn, &ad) {
+                            let (to, to_conn) = match conn {
+                                0 => ((node + 1) % 3, 1),
+                                _ => ((node + 2) % 3, 0),
+                            };
+                            sent.push((to, to_conn, ad));
+                        }
+                    }
+                }
+                if sent.is_empty() {
+                    return;
+                }
+                for (to, conn, ad) in sent {
+                    tables[to].interest(conn, ad);
+                }
+            }
+            panic!("interest never settled");
+        };
+
+        tables[0].subscribe_local("news".into());
+        exchange(&mut tables);
+        assert!(tables[1].matching_conns("news").contains(&1));
+        assert!(tables[2].matching_conns("news").contains(&0));
+        tables[0].unsubscribe_local("news");
+        exchange(&mut tables);
+        for table in tables.iter() {
+            assert!(table.matching_conns("news").is_empty());
+        }
+    }
+
+    #[test]
+    fn seen_cache_forgets_oldest() {
+        let mut seen = SeenCache::new(2);
+        assert!(seen.insert(1));
+        assert!(!seen.insert(1));
+        seen.insert(2);
+        seen.insert(3);
+        assert!(seen.insert(1));
+        assert!(!seen.insert(3));
+    }
+}
@@ -0,0 +1,1547 @@
+use bytes::Bytes;
+use failure::{bail, Error};
+use mio_framed::{App, Context, Core};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::election::{Campaign, Role};
+use crate::transport::{timer_kind, timer_token, Transport, TIMER_APP};
+use crate::NodeId;
+
+const TIMER_TICK: usize = 1;
+
+// Everything has to fit in a u16 framed message, with room for the header
+const MAX_COMMAND: usize = 48 * 1024;
+const BATCH_SIZE: usize = 32 * 1024;
+const SNAPSHOT_CHUNK: usize = 32 * 1024;
+
+fn quorum(voters: usize) -> usize {
+    voters / 2 + 1
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Payload {
+    // Appended by every new leader, so entries from older terms can commit
+    Noop,
+    Command(Vec<u8>),
+    // The complete new set of voters, in effect as soon as it's appended
+    Membership(Vec<NodeId>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub payload: Payload,
+}
+
+impl Entry {
+    // Close enough for batching
+    fn size(&self) -> usize {
+        let payload = match &self.payload {
+            Payload::Noop => 0,
+            Payload::Command(command) => command.len(),
+            Payload::Membership(voters) => voters.len() * 8,
+        };
+        payload + 24
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        last_index: u64,
+        last_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    // On success `index` is the last entry matched, otherwise where the
+    // leader should back up to
+    AppendReply {
+        term: u64,
+        success: bool,
+        index: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        index: u64,
+        last_term: u64,
+        voters: Vec<NodeId>,
+        offset: usize,
+        data: Vec<u8>,
+        done: bool,
+    },
+    // How much of the snapshot at `index` arrived so far
+    SnapshotReply {
+        term: u64,
+        index: u64,
+        offset: usize,
+    },
+}
+
+impl RaftMessage {
+    fn term(&self) -> u64 {
+        match self {
+            RaftMessage::RequestVote { term, .. }
+            | RaftMessage::Vote { term, .. }
+            | RaftMessage::Append { term, .. }
+            | RaftMessage::AppendReply { term, .. }
+            | RaftMessage::InstallSnapshot { term, .. }
+            | RaftMessage::SnapshotReply { term, .. } => *term,
+        }
+    }
+}
+
+// What the state machine has to do next, in log order
+#[derive(Clone, Debug, PartialEq)]
+pub enum Committed {
+    Restore { index: u64, data: Vec<u8> },
+    Apply { index: u64, command: Vec<u8> },
+    Membership { index: u64, voters: Vec<NodeId> },
+}
+
+// The state machine as of `index`, and the voters at that point
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub voters: Vec<NodeId>,
+    pub data: Vec<u8>,
+}
+
+// Everything a replica needs back after a restart
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Persisted {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    pub snapshot: Snapshot,
+    // Whatever follows the snapshot, committed or not
+    pub entries: Vec<Entry>,
+}
+
+// Where a replica keeps its term, vote and log. Changes are saved before
+// any message that depends on them goes out, so a restarted node never
+// votes twice in a term or forgets entries it acknowledged.
+pub trait Storage {
+    // What was saved last time, None for a new node
+    fn load(&mut self) -> Result<Option<Persisted>, Error>;
+    fn save_state(&mut self, term: u64, voted_for: Option<NodeId>) -> Result<(), Error>;
+    // Replaces every entry from `from` on with `entries`
+    fn save_entries(&mut self, from: u64, entries: &[Entry]) -> Result<(), Error>;
+    // Entries the snapshot covers can be dropped
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Error>;
+}
+
+// Entries after the snapshot, entries[i] has index snapshot.index + 1 + i
+struct RaftLog {
+    snapshot: Snapshot,
+    entries: Vec<Entry>,
+}
+
+impl RaftLog {
+    fn last_index(&self) -> u64 {
+        self.snapshot.index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot.index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot.index - 1) as usize)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    // Drops `index` and everything after it
+    fn truncate(&mut self, index: u64) {
+        let keep = index.saturating_sub(self.snapshot.index + 1);
+        self.entries.truncate(keep as usize);
+    }
+
+    fn entries_from(&self, index: u64, max_bytes: usize) -> Vec<Entry> {
+        let start = (index - self.snapshot.index - 1) as usize;
+        let mut size = 0;
+        let mut batch = vec![];
+        for entry in self.entries.iter().skip(start) {
+            if !batch.is_empty() && size + entry.size() > max_bytes {
+                break;
+            }
+            size += entry.size();
+            batch.push(entry.clone());
+        }
+        batch
+    }
+
+    fn voters_at(&self, index: u64) -> &[NodeId] {
+        let entries = self.entries.iter().rev();
+        let membership =
+            entries
+                .filter(|entry| entry.index <= index)
+                .find_map(|entry| match &entry.payload {
+                    Payload::Membership(voters) => Some(voters),
+                    _ => None,
+                });
+        membership.unwrap_or(&self.snapshot.voters)
+    }
+}
+
+struct Progress {
+    // Next entry to send, moved past each batch as it goes out so
+    // proposals in a row don't resend everything still in flight
+    next: u64,
+    // Highest entry known to be replicated
+    matched: u64,
+    // How far into the snapshot the follower is, if next was compacted away
+    offset: usize,
+}
+
+// A Raft replica driven by ticks and messages only, like Elector, so it
+// runs the same over Core timers or in a simulation. Without a Storage
+// everything is kept in memory, so a node that restarts has to rejoin
+// under a new id.
+pub struct Replica {
+    campaign: Campaign,
+    log: RaftLog,
+    commit: u64,
+    applied: u64,
+    // A snapshot from the leader the state machine hasn't seen yet
+    restore: bool,
+    incoming: Option<Snapshot>,
+    progress: HashMap<NodeId, Progress>,
+    snapshot_threshold: u64,
+    outbox: Vec<(NodeId, RaftMessage)>,
+    storage: Option<Box<dyn Storage>>,
+    // Term and vote as last saved
+    saved: (u64, Option<NodeId>),
+    // The lowest index changed since the last save
+    unsaved: Option<u64>,
+    snapshot_unsaved: bool,
+}
+
+impl Replica {
+    // `voters` is the initial cluster. A node joining later starts with the
+    // cluster as it is and waits for the leader to add it.
+    pub fn new(node: NodeId, voters: &[NodeId]) -> Self {
+        let mut voters = voters.to_vec();
+        voters.sort();
+        voters.dedup();
+        let snapshot = Snapshot {
+            index: 0,
+            term: 0,
+            voters,
+            data: vec![],
+        };
+        Replica {
+            campaign: Campaign::new(node),
+            log: RaftLog {
+                snapshot,
+                entries: vec![],
+            },
+            commit: 0,
+            applied: 0,
+            restore: false,
+            incoming: None,
+            progress: HashMap::new(),
+            snapshot_threshold: 1000,
+            outbox: vec![],
+            storage: None,
+            saved: (0, None),
+            unsaved: None,
+            snapshot_unsaved: false,
+        }
+    }
+
+    // Like new, but picks up where `storage` left off. `voters` only
+    // matters the first time.
+    pub fn open(
+        node: NodeId,
+        voters: &[NodeId],
+        mut storage: Box<dyn Storage>,
+    ) -> Result<Self, Error> {
+        let persisted = storage.load()?;
+        let mut replica = Replica::new(node, voters);
+        match persisted {
+            Some(persisted) => {
+                replica.campaign.term = persisted.term;
+                replica.campaign.voted_for = persisted.voted_for;
+                replica.saved = (persisted.term, persisted.voted_for);
+                replica.commit = persisted.snapshot.index;
+                replica.restore = persisted.snapshot.index > 0;
+                replica.log = RaftLog {
+                    snapshot: persisted.snapshot,
+                    entries: persisted.entries,
+                };
+            }
+            // The voters have to survive too
+            None => replica.snapshot_unsaved = true,
+        }
+        replica.storage = Some(storage);
+        replica.persist()?;
+        Ok(replica)
+    }
+
+    // Followers wait between this and twice this many ticks before standing
+    pub fn election_ticks(mut self, ticks: u32) -> Self {
+        self.campaign.set_election_ticks(ticks);
+        self
+    }
+
+    // Applied entries kept before asking for a snapshot and compacting
+    pub fn snapshot_threshold(mut self, entries: u64) -> Self {
+        self.snapshot_threshold = entries.max(1);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.campaign.seed(seed);
+        self
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.campaign.node
+    }
+    pub fn term(&self) -> u64 {
+        self.campaign.term
+    }
+    pub fn role(&self) -> Role {
+        self.campaign.role
+    }
+    pub fn is_leader(&self) -> bool {
+        self.campaign.is_leader()
+    }
+    pub fn leader(&self) -> Option<NodeId> {
+        self.campaign.leader
+    }
+    pub fn commit_index(&self) -> u64 {
+        self.commit
+    }
+    pub fn applied_index(&self) -> u64 {
+        self.applied
+    }
+    pub fn last_index(&self) -> u64 {
+        self.log.last_index()
+    }
+    // The latest configuration in the log, committed or not
+    pub fn voters(&self) -> Vec<NodeId> {
+        self.log.voters_at(self.log.last_index()).to_vec()
+    }
+
+    // Messages to send, once what they depend on is saved. They stay
+    // queued if that fails.
+    pub fn take_messages(&mut self) -> Result<Vec<(NodeId, RaftMessage)>, Error> {
+        self.persist()?;
+        Ok(std::mem::take(&mut self.outbox))
+    }
+
+    fn persist(&mut self) -> Result<(), Error> {
+        let storage = match self.storage.as_mut() {
+            Some(storage) => storage,
+            None => return Ok(()),
+        };
+        if self.snapshot_unsaved {
+            storage.save_snapshot(&self.log.snapshot)?;
+            self.snapshot_unsaved = false;
+        }
+        if let Some(from) = self.unsaved {
+            let from = from.max(self.log.snapshot.index + 1);
+            let start = (from - self.log.snapshot.index - 1) as usize;
+            let entries = self.log.entries.get(start..).unwrap_or(&[]);
+            storage.save_entries(from, entries)?;
+            self.unsaved = None;
+        }
+        let state = (self.campaign.term, self.campaign.voted_for);
+        if state != self.saved {
+            storage.save_state(state.0, state.1)?;
+            self.saved = state;
+        }
+        Ok(())
+    }
+
+    fn unsaved_from(&mut self, index: u64) {
+        self.unsaved = Some(self.unsaved.map_or(index, |from| from.min(index)));
+    }
+
+    fn is_voter(&self) -> bool {
+        self.log
+            .voters_at(self.log.last_index())
+            .contains(&self.campaign.node)
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        let voters = self.log.voters_at(self.log.last_index()).iter();
+        voters
+            .copied()
+            .filter(|peer| *peer != self.campaign.node)
+            .collect()
+    }
+
+    // Only one membership change may be uncommitted at a time
+    fn membership_pending(&self) -> bool {
+        let mut uncommitted = self.log.entries.iter().filter(|e| e.index > self.commit);
+        uncommitted.any(|entry| matches!(entry.payload, Payload::Membership(_)))
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        self.campaign.become_follower(term, leader);
+        self.progress.clear();
+    }
+
+    fn follow(&mut self, leader: NodeId) {
+        if self.campaign.role != Role::Follower || self.campaign.leader != Some(leader) {
+            self.become_follower(self.campaign.term, Some(leader));
+        }
+        self.campaign.elapsed = 0;
+    }
+
+    fn become_leader(&mut self) {
+        self.campaign.become_leader();
+        self.progress.clear();
+        self.append(Payload::Noop);
+        self.replicate();
+    }
+
+    fn campaign(&mut self) {
+        let quorum = self.quorum_check();
+        if self.campaign.stand(quorum) {
+            self.become_leader();
+            return;
+        }
+        let message = RaftMessage::RequestVote {
+            term: self.campaign.term,
+            last_index: self.log.last_index(),
+            last_term: self.log.last_term(),
+        };
+        for peer in self.peers() {
+            self.outbox.push((peer, message.clone()));
+        }
+    }
+
+    // Only the latest voters count, removed nodes may still answer
+    fn quorum_check(&self) -> impl Fn(&HashSet<NodeId>) -> bool {
+        let voters = self.log.voters_at(self.log.last_index()).to_vec();
+        move |nodes| {
+            let count = voters.iter().filter(|v| nodes.contains(v)).count();
+            count >= quorum(voters.len())
+        }
+    }
+
+    fn append(&mut self, payload: Payload) -> u64 {
+        let index = self.log.last_index() + 1;
+        let term = self.campaign.term;
+        let entry = Entry {
+            term,
+            index,
+            payload,
+        };
+        self.log.entries.push(entry);
+        self.unsaved_from(index);
+        self.maybe_commit();
+        index
+    }
+
+    fn replicate(&mut self) {
+        for peer in self.peers() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: NodeId) {
+        let last_index = self.log.last_index();
+        let progress = self.progress.entry(peer).or_insert(Progress {
+            next: last_index + 1,
+            matched: 0,
+            offset: 0,
+        });
+        let (next, offset) = (progress.next, progress.offset);
+        let term = self.campaign.term;
+        let message = if next <= self.log.snapshot.index {
+            let snapshot = &self.log.snapshot;
+            let offset = offset.min(snapshot.data.len());
+            let end = (offset + SNAPSHOT_CHUNK).min(snapshot.data.len());
+            RaftMessage::InstallSnapshot {
+                term,
+                index: snapshot.index,
+                last_term: snapshot.term,
+                voters: snapshot.voters.clone(),
+                offset,
+                data: snapshot.data[offset..end].to_vec(),
+                done: end == snapshot.data.len(),
+            }
+        } else {
+            let prev_index = next - 1;
+            let prev_term = self.log.term_at(prev_index);
+            let entries = self.log.entries_from(next, BATCH_SIZE);
+            progress.next = next + entries.len() as u64;
+            RaftMessage::Append {
+                term,
+                prev_index,
+                prev_term: prev_term.expect("Entries after the snapshot are kept"),
+                entries,
+                commit: self.commit,
+            }
+        };
+        self.outbox.push((peer, message));
+    }
+
+    fn maybe_commit(&mut self) {
+        if !self.is_leader() {
+            return;
+        }
+        let voters = self.log.voters_at(self.log.last_index());
+        for index in (self.commit + 1..=self.log.last_index()).rev() {
+            // Entries from older terms only commit along with newer ones
+            if self.log.term_at(index) != Some(self.campaign.term) {
+                break;
+            }
+            let acks = voters.iter().filter(|voter| {
+                **voter == self.campaign.node
+                    || self
+                        .progress
+                        .get(voter)
+                        .is_some_and(|progress| progress.matched >= index)
+            });
+            if acks.count() >= quorum(voters.len()) {
+                self.commit = index;
+                break;
+            }
+        }
+        // A leader that removed itself hands over once that's committed
+        if !self.is_voter() && !self.membership_pending() {
+            self.become_follower(self.campaign.term, None);
+        }
+    }
+
+    // Appends a command to the log, returning its index if this is the leader
+    pub fn propose(&mut self, command: Vec<u8>) -> Result<u64, Error> {
+        if !self.is_leader() {
+            bail!("Not the leader, {:?} is", self.campaign.leader);
+        }
+        if command.len() > MAX_COMMAND {
+            bail!("Command of {} bytes is too large", command.len());
+        }
+        let index = self.append(Payload::Command(command));
+        self.replicate();
+        Ok(index)
+    }
+
+    // Changes the voters by adding or removing a single node
+    pub fn change_membership(&mut self, voters: &[NodeId]) -> Result<u64, Error> {
+        if !self.is_leader() {
+            bail!("Not the leader, {:?} is", self.campaign.leader);
+        }
+        if self.membership_pending() {
+            bail!("A membership change is already in progress");
+        }
+        let mut voters = voters.to_vec();
+        voters.sort();
+        voters.dedup();
+        let current = self.voters();
+        let added = voters.iter().filter(|v| !current.contains(v)).count();
+        let removed = current.iter().filter(|v| !voters.contains(v)).count();
+        if voters.is_empty() || added + removed > 1 {
+            bail!("Membership can only change one node at a time");
+        }
+        let index = self.append(Payload::Membership(voters));
+        self.replicate();
+        Ok(index)
+    }
+
+    // Call every heartbeat interval
+    pub fn tick(&mut self) {
+        if self.campaign.tick() {
+            if self.is_voter() {
+                self.campaign();
+            }
+            return;
+        }
+        if !self.is_leader() {
+            return;
+        }
+        self.replicate();
+        let quorum = self.quorum_check();
+        if !self.campaign.check_quorum(quorum) {
+            self.progress.clear();
+        }
+    }
+
+    pub fn step(&mut self, from: NodeId, message: RaftMessage) {
+        let term = message.term();
+        if term > self.campaign.term {
+            // While a leader is around, ignore removed or partitioned nodes
+            // trying to start elections
+            let in_lease = self.campaign.leader.is_some()
+                && self.campaign.elapsed < self.campaign.election_ticks;
+            if let RaftMessage::RequestVote { .. } = message {
+                if in_lease {
+                    return;
+                }
+            }
+            let leader = match message {
+                RaftMessage::Append { .. } | RaftMessage::InstallSnapshot { .. } => Some(from),
+                _ => None,
+            };
+            // Only a leader or a granted vote holds off our own election,
+            // a candidate that can't win shouldn't keep resetting everyone
+            let elapsed = self.campaign.elapsed;
+            self.become_follower(term, leader);
+            if leader.is_none() {
+                self.campaign.elapsed = elapsed;
+            }
+        }
+        if term < self.campaign.term {
+            // Let stale leaders and candidates know they are behind
+            let term = self.campaign.term;
+            match message {
+                RaftMessage::RequestVote { .. } => {
+                    let granted = false;
+                    self.outbox
+                        .push((from, RaftMessage::Vote { term, granted }));
+                }
+                RaftMessage::Append { .. } | RaftMessage::InstallSnapshot { .. } => {
+                    let (success, index) = (false, self.log.last_index());
+                    let reply = RaftMessage::AppendReply {
+                        term,
+                        success,
+                        index,
+                    };
+                    self.outbox.push((from, reply));
+                }
+                _ => {}
+            }
+            return;
+        }
+        match message {
+            RaftMessage::RequestVote {
+                last_index,
+                last_term,
+                ..
+            } => {
+                // Only candidates whose log is at least as far along as ours
+                let log = (self.log.last_term(), self.log.last_index());
+                let granted = self.campaign.grant(from, (last_term, last_index) >= log);
+                self.outbox
+                    .push((from, RaftMessage::Vote { term, granted }));
+            }
+            RaftMessage::Vote { granted, .. } => {
+                let quorum = self.quorum_check();
+                if self.campaign.count_vote(from, granted, quorum) {
+                    self.become_leader();
+                }
+            }
+            RaftMessage::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                ..
+            } => {
+                self.follow(from);
+                self.handle_append(from, prev_index, prev_term, entries, commit);
+            }
+            RaftMessage::AppendReply { success, index, .. } => {
+                if self.campaign.role == Role::Leader {
+                    self.campaign.heard_from.insert(from);
+                    self.handle_append_reply(from, success, index);
+                }
+            }
+            RaftMessage::InstallSnapshot {
+                index,
+                last_term,
+                voters,
+                offset,
+                data,
+                done,
+                ..
+            } => {
+                self.follow(from);
+                let snapshot = Snapshot {
+                    index,
+                    term: last_term,
+                    voters,
+                    data,
+                };
+                self.handle_snapshot(from, snapshot, offset, done);
+            }
+            RaftMessage::SnapshotReply { index, offset, .. } => {
+                if self.campaign.role == Role::Leader {
+                    self.campaign.heard_from.insert(from);
+                    self.handle_snapshot_reply(from, index, offset);
+                }
+            }
+        }
+    }
+
+    fn handle_append(
+        &mut self,
+        from: NodeId,
+        mut prev_index: u64,
+        mut prev_term: u64,
+        mut entries: Vec<Entry>,
+        commit: u64,
+    ) {
+        let term = self.campaign.term;
+        let snapshot_index = self.log.snapshot.index;
+        if prev_index < snapshot_index {
+            // Already in the snapshot, and so committed
+            entries.retain(|entry| entry.index > snapshot_index);
+            prev_index = snapshot_index;
+            prev_term = self.log.snapshot.term;
+        }
+        if self.log.term_at(prev_index) != Some(prev_term) {
+            let index = (prev_index - 1).min(self.log.last_index());
+            let success = false;
+            let reply = RaftMessage::AppendReply {
+                term,
+                success,
+                index,
+            };
+            self.outbox.push((from, reply));
+            return;
+        }
+        let index = prev_index + entries.len() as u64;
+        for entry in entries {
+            match self.log.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.log.truncate(entry.index),
+                None => {}
+            }
+            self.unsaved_from(entry.index);
+            self.log.entries.push(entry);
+        }
+        self.commit = self.commit.max(commit.min(index));
+        let success = true;
+        let reply = RaftMessage::AppendReply {
+            term,
+            success,
+            index,
+        };
+        self.outbox.push((from, reply));
+    }
+
+    fn handle_append_reply(&mut self, from: NodeId, success: bool, index: u64) {
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if success {
+            // Replies to duplicates don't send anything, so they die out
+            if index <= progress.matched {
+                return;
+            }
+            progress.matched = index;
+            progress.next = progress.next.max(index + 1);
+            progress.offset = 0;
+            let more = progress.next <= self.log.last_index();
+            self.maybe_commit();
+            if more && self.is_leader() {
+                self.send_append(from);
+            }
+        } else {
+            let next = (progress.next - 1).min(index + 1).max(progress.matched + 1);
+            if next < progress.next {
+                progress.next = next;
+                self.send_append(from);
+            }
+        }
+    }
+
+    fn handle_snapshot(&mut self, from: NodeId, chunk: Snapshot, offset: usize, done: bool) {
+        let term = self.campaign.term;
+        let index = chunk.index;
+        if index <= self.commit {
+            let success = true;
+            let reply = RaftMessage::AppendReply {
+                term,
+                success,
+                index,
+            };
+            self.outbox.push((from, reply));
+            return;
+        }
+        let mut incoming = match self.incoming.take() {
+            Some(incoming) if incoming.index == index => incoming,
+            _ if offset == 0 => Snapshot {
+                data: vec![],
+                ..chunk.clone()
+            },
+            _ => {
+                let offset = 0;
+                let reply = RaftMessage::SnapshotReply {
+                    term,
+                    index,
+                    offset,
+                };
+                self.outbox.push((from, reply));
+                return;
+            }
+        };
+        let expected = offset == incoming.data.len();
+        if expected {
+            incoming.data.extend_from_slice(&chunk.data);
+        }
+        if !expected || !done {
+            let offset = incoming.data.len();
+            let reply = RaftMessage::SnapshotReply {
+                term,
+                index,
+                offset,
+            };
+            self.outbox.push((from, reply));
+            self.incoming = Some(incoming);
+            return;
+        }
+        // Entries past the snapshot survive if the logs agree up to it
+        if self.log.term_at(index) == Some(incoming.term) {
+            let compacted = index - self.log.snapshot.index;
+            self.log.entries.drain(..compacted as usize);
+        } else {
+            self.log.entries.clear();
+            self.unsaved_from(index + 1);
+        }
+        self.log.snapshot = incoming;
+        self.snapshot_unsaved = true;
+        self.commit = index;
+        self.restore = true;
+        let success = true;
+        let reply = RaftMessage::AppendReply {
+            term,
+            success,
+            index,
+        };
+        self.outbox.push((from, reply));
+    }
+
+    fn handle_snapshot_reply(&mut self, from: NodeId, index: u64, offset: usize) {
+        let snapshot_index = self.log.snapshot.index;
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if index != snapshot_index {
+            // The leader compacted again since, start over
+            progress.offset = 0;
+        } else if offset > progress.offset {
+            progress.offset = offset;
+        } else {
+            progress.offset = offset;
+            return;
+        }
+        self.send_append(from);
+    }
+
+    // Committed entries for the state machine, each handed out once
+    pub fn take_committed(&mut self) -> Vec<Committed> {
+        let mut committed = vec![];
+        if self.restore {
+            self.restore = false;
+            let snapshot = &self.log.snapshot;
+            self.applied = snapshot.index;
+            let (index, data) = (snapshot.index, snapshot.data.clone());
+            committed.push(Committed::Restore { index, data });
+        }
+        while self.applied < self.commit {
+            self.applied += 1;
+            let index = self.applied;
+            let entry = self.log.entry(index);
+            match &entry.expect("Entries are kept until applied").payload {
+                Payload::Noop => {}
+                Payload::Command(command) => {
+                    let command = command.clone();
+                    committed.push(Committed::Apply { index, command });
+                }
+                Payload::Membership(voters) => {
+                    let voters = voters.clone();
+                    committed.push(Committed::Membership { index, voters });
+                }
+            }
+        }
+        committed
+    }
+
+    // Whether enough has been applied that the log should be compacted
+    pub fn wants_snapshot(&self) -> bool {
+        self.applied.saturating_sub(self.log.snapshot.index) >= self.snapshot_threshold
+    }
+
+    // Replaces everything applied so far with `data`, the state machine as
+    // of applied_index
+    pub fn compact(&mut self, data: Vec<u8>) {
+        let index = self.applied;
+        if index <= self.log.snapshot.index {
+            return;
+        }
+        let term = self.log.term_at(index).expect("Applied entries are kept");
+        let voters = self.log.voters_at(index).to_vec();
+        let compacted = index - self.log.snapshot.index;
+        self.log.entries.drain(..compacted as usize);
+        self.log.snapshot = Snapshot {
+            index,
+            term,
+            voters,
+            data,
+        };
+        self.snapshot_unsaved = true;
+    }
+}
+
+// The replicated state machine. Every node applies the same commands in the
+// same order.
+pub trait RaftApp {
+    fn apply(&mut self, ctx: &mut RaftContext, index: u64, command: &[u8]);
+    // The state as of the last command applied
+    fn snapshot(&mut self) -> Vec<u8>;
+    // Replace the state with a snapshot from the leader
+    fn restore(&mut self, ctx: &mut RaftContext, index: u64, snapshot: &[u8]);
+
+    fn handle_init(&mut self, _ctx: &mut RaftContext) {}
+    fn handle_connect(&mut self, _ctx: &mut RaftContext, _id: usize) {}
+    fn handle_accept(&mut self, _ctx: &mut RaftContext, _listen_socket: usize, _id: usize) {}
+    fn handle_close(&mut self, _ctx: &mut RaftContext, _id: usize) {}
+    fn handle_leader_change(&mut self, _ctx: &mut RaftContext, _leader: Option<NodeId>) {}
+    // A committed membership change
+    fn handle_membership(&mut self, _ctx: &mut RaftContext, _index: u64, _voters: &[NodeId]) {}
+    // Saving failed, nothing goes out or gets applied until a later save works
+    fn handle_storage_error(&mut self, _ctx: &mut RaftContext, _error: Error) {}
+    fn handle_frames(&mut self, _ctx: &mut RaftContext, _id: usize, _frames: Vec<Bytes>) {}
+    fn handle_timeout(&mut self, _ctx: &mut RaftContext, _token: usize) {}
+    fn handle_shutdown(&mut self) {}
+}
+
+pub struct RaftContext<'a> {
+    ctx: &'a Context,
+    state: &'a mut RaftState,
+}
+
+impl<'a> RaftContext<'a> {
+    pub fn context(&self) -> &Context {
+        self.ctx
+    }
+    pub fn node_id(&self) -> NodeId {
+        self.state.replica.node_id()
+    }
+    pub fn is_leader(&self) -> bool {
+        self.state.replica.is_leader()
+    }
+    pub fn leader(&self) -> Option<NodeId> {
+        self.state.replica.leader()
+    }
+    pub fn term(&self) -> u64 {
+        self.state.replica.term()
+    }
+    pub fn voters(&self) -> Vec<NodeId> {
+        self.state.replica.voters()
+    }
+    pub fn commit_index(&self) -> u64 {
+        self.state.replica.commit_index()
+    }
+    // The index the command will be applied at, once committed
+    pub fn propose<B: AsRef<[u8]>>(&mut self, command: B) -> Result<u64, Error> {
+        self.state.replica.propose(command.as_ref().to_vec())
+    }
+    pub fn change_membership(&mut self, voters: &[NodeId]) -> Result<u64, Error> {
+        self.state.replica.change_membership(voters)
+    }
+    // The node on the other end of a connection, once its Hello arrived
+    pub fn node_of(&self, id: usize) -> Option<NodeId> {
+        self.state.transport.node_of(id)
+    }
+    pub fn send<B: AsRef<[u8]>>(&self, id: usize, frame: B) {
+        self.state.transport.send_app(self.ctx, id, frame.as_ref());
+    }
+    pub fn set_timeout(&self, token: usize, delay: Duration) {
+        self.ctx.set_timeout(timer_token(TIMER_APP, token), delay);
+    }
+    pub fn cancel_timeout(&self, token: usize) {
+        self.ctx.cancel_timeout(timer_token(TIMER_APP, token));
+    }
+}
+
+struct RaftState {
+    replica: Replica,
+    tick_interval: Duration,
+    transport: Transport,
+    // What the app was last told
+    reported: Option<NodeId>,
+}
+
+impl RaftState {
+    // Messages to nodes without a connection are dropped, raft retries
+    fn flush(&mut self, ctx: &Context) -> Result<(), Error> {
+        for (to, message) in self.replica.take_messages()? {
+            self.transport.send_to(ctx, to, &message);
+        }
+        Ok(())
+    }
+}
+
+pub struct Raft<A: RaftApp> {
+    app: A,
+    state: RaftState,
+}
+
+pub fn new_raft<A: RaftApp>(node: NodeId, voters: &[NodeId], app: A) -> Core<Raft<A>> {
+    Core::new(Raft::new(node, voters, app))
+}
+
+impl<A: RaftApp> Raft<A> {
+    // `voters` is the initial cluster, see Replica::new
+    pub fn new(node: NodeId, voters: &[NodeId], app: A) -> Self {
+        Self::with_replica(Replica::new(node, voters), app)
+    }
+
+    // Keeps the replica's state in `storage`, see Replica::open
+    pub fn open(
+        node: NodeId,
+        voters: &[NodeId],
+        storage: Box<dyn Storage>,
+        app: A,
+    ) -> Result<Self, Error> {
+        let replica = Replica::open(node, voters, storage)?;
+        Ok(Self::with_replica(replica, app))
+    }
+
+    fn with_replica(replica: Replica, app: A) -> Self {
+        let transport = Transport::new(replica.node_id());
+        let state = RaftState {
+            replica,
+            tick_interval: Duration::from_millis(100),
+            transport,
+            reported: None,
+        };
+        Raft { app, state }
+    }
+
+    // Leaders send heartbeats every tick
+    pub fn tick_interval(mut self, interval: Duration) -> Self {
+        self.state.tick_interval = interval;
+        self
+    }
+
+    pub fn election_ticks(mut self, ticks: u32) -> Self {
+        self.state.replica = self.state.replica.election_ticks(ticks);
+        self
+    }
+
+    pub fn snapshot_threshold(mut self, entries: u64) -> Self {
+        self.state.replica = self.state.replica.snapshot_threshold(entries);
+        self
+    }
+
+    fn wrap_context<'a>(&'a mut self, ctx: &'a Context) -> (&'a mut A, RaftContext<'a>) {
+        let ctx = RaftContext {
+            ctx,
+            state: &mut self.state,
+        };
+        (&mut self.app, ctx)
+    }
+
+    // Send what the replica produced, then feed the app until nothing new
+    // is committed, since the app may propose from its callbacks
+    fn settle(&mut self, ctx: &Context) {
+        loop {
+            if let Err(e) = self.state.flush(ctx) {
+                let (app, mut ctx) = self.wrap_context(ctx);
+                app.handle_storage_error(&mut ctx, e);
+                return;
+            }
+            let leader = self.state.replica.leader();
+            if leader != self.state.reported {
+                self.state.reported = leader;
+                let (app, mut ctx) = self.wrap_context(ctx);
+                app.handle_leader_change(&mut ctx, leader);
+                continue;
+            }
+            let committed = self.state.replica.take_committed();
+            if committed.is_empty() {
+                break;
+            }
+            let (app, mut ctx) = self.wrap_context(ctx);
+            for committed in committed {
+                match committed {
+                    Committed::Restore { index, data } => app.restore(&mut ctx, index, &data),
+                    Committed::Apply { index, command } => app.apply(&mut ctx, index, &command),
+                    Committed::Membership { index, voters } => {
+                        app.handle_membership(&mut ctx, index, &voters)
+                    }
+                }
+            }
+        }
+        if self.state.replica.wants_snapshot() {
+            let data = self.app.snapshot();
+            self.state.replica.compact(data);
+        }
+    }
+}
+
+impl<A: RaftApp> App for Raft<A> {
+    fn handle_init(&mut self, ctx: &Context) {
+        ctx.set_timeout(timer_token(TIMER_TICK, 0), self.state.tick_interval);
+        let (app, mut rctx) = self.wrap_context(ctx);
+        app.handle_init(&mut rctx);
+        self.settle(ctx);
+    }
+    fn handle_connect(&mut self, ctx: &Context, id: usize) {
+        self.state.transport.hello(ctx, id);
+        let (app, mut rctx) = self.wrap_context(ctx);
+        app.handle_connect(&mut rctx, id);
+        self.settle(ctx);
+    }
+    fn handle_accept(&mut self, ctx: &Context, listen_socket: usize, id: usize) {
+        self.state.transport.hello(ctx, id);
+        let (app, mut rctx) = self.wrap_context(ctx);
+        app.handle_accept(&mut rctx, listen_socket, id);
+        self.settle(ctx);
+    }
+    fn handle_close(&mut self, ctx: &Context, id: usize) {
+        self.state.transport.closed(id);
+        let (app, mut rctx) = self.wrap_context(ctx);
+        app.handle_close(&mut rctx, id);
+        self.settle(ctx);
+    }
+    fn handle_frames(&mut self, ctx: &Context, id: usize, frames: Vec<Bytes>) {
+        let (messages, delivered) = self.state.transport.receive(id, frames);
+        for (node, message) in messages {
+            self.state.replica.step(node, message);
+        }
+        self.settle(ctx);
+        if !delivered.is_empty() {
+            let (app, mut rctx) = self.wrap_context(ctx);
+            app.handle_frames(&mut rctx, id, delivered);
+            self.settle(ctx);
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &Context, token: usize) {
+        match timer_kind(token) {
+            (TIMER_TICK, _) => {
+                self.state.replica.tick();
+                ctx.set_timeout(timer_token(TIMER_TICK, 0), self.state.tick_interval);
+            }
+            (_, token) => {
+                let (app, mut rctx) = self.wrap_context(ctx);
+                app.handle_timeout(&mut rctx, token);
+            }
+        }
+        self.settle(ctx);
+    }
+    fn handle_shutdown(&mut self) {
+        self.app.handle_shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, VecDeque};
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    // Applies commands by appending them, so any divergence shows
+    #[derive(Default)]
+    struct Machine {
+        state: Vec<u8>,
+        applied: u64,
+        restored: bool,
+    }
+
+    impl Machine {
+        fn apply(&mut self, committed: Committed) {
+            match committed {
+                Committed::Restore { index, data } => {
+                    self.state = data;
+                    self.applied = index;
+                    self.restored = true;
+                }
+                Committed::Apply { index, command } => {
+                    assert!(index > self.applied, "applied out of order");
+                    self.state.extend(command);
+                    self.applied = index;
+                }
+                Committed::Membership { index, .. } => self.applied = index,
+            }
+        }
+    }
+
+    // Delivers every message right away unless the link is cut
+    struct Network {
+        nodes: BTreeMap<NodeId, (Replica, Machine)>,
+        cut: HashSet<(NodeId, NodeId)>,
+        leaders: HashMap<u64, NodeId>,
+    }
+
+    impl Network {
+        fn new(size: u64) -> Self {
+            let mut network = Network {
+                nodes: BTreeMap::new(),
+                cut: HashSet::new(),
+                leaders: HashMap::new(),
+            };
+            let voters: Vec<NodeId> = (1..=size).map(NodeId).collect();
+            for node in voters.iter() {
+                network.add(*node, &voters);
+            }
+            network
+        }
+
+        fn add(&mut self, node: NodeId, voters: &[NodeId]) {
+            let disk = Box::new(Disk::default());
+            let replica = Replica::open(node, voters, disk)
+                .unwrap()
+                .election_ticks(5)
+                .snapshot_threshold(20);
+            self.nodes.insert(node, (replica, Machine::default()));
+        }
+
+        fn isolate(&mut self, node: NodeId) {
+            for other in self.nodes.keys() {
+                self.cut.insert((node, *other));
+                self.cut.insert((*other, node));
+            }
+        }
+
+        fn replica(&mut self, node: NodeId) -> &mut Replica {
+            &mut self.nodes.get_mut(&node).unwrap().0
+        }
+
+        fn deliver(&mut self, mut queue: VecDeque<(NodeId, (NodeId, RaftMessage))>) {
+            while let Some((from, (to, message))) = queue.pop_front() {
+                if self.cut.contains(&(from, to)) {
+                    continue;
+                }
+                let replica = match self.nodes.get_mut(&to) {
+                    Some((replica, _)) => replica,
+                    None => continue,
+                };
+                replica.step(from, message);
+                queue.extend(
+                    replica
+                        .take_messages()
+                        .unwrap()
+                        .into_iter()
+                        .map(|m| (to, m)),
+                );
+            }
+            for (replica, machine) in self.nodes.values_mut() {
+                for committed in replica.take_committed() {
+                    machine.apply(committed);
+                }
+                if replica.wants_snapshot() {
+                    replica.compact(machine.state.clone());
+                }
+            }
+        }
+
+        fn run(&mut self, ticks: usize) {
+            for _ in 0..ticks {
+                let mut queue = VecDeque::new();
+                for (node, (replica, _)) in self.nodes.iter_mut() {
+                    replica.tick();
+                    let messages = replica.take_messages().unwrap();
+                    queue.extend(messages.into_iter().map(|m| (*node, m)));
+                }
+                self.deliver(queue);
+                self.check();
+            }
+        }
+
+        // One leader per term, and every state machine a prefix of the others
+        fn check(&mut self) {
+            for (replica, _) in self.nodes.values().filter(|(r, _)| r.is_leader()) {
+                let leader = self
+                    .leaders
+                    .entry(replica.term())
+                    .or_insert(replica.node_id());
+                assert_eq!(*leader, replica.node_id(), "two leaders in one term");
+            }
+            let states = self.nodes.values().map(|(_, machine)| &machine.state);
+            let longest = states.max_by_key(|state| state.len()).unwrap();
+            for (_, machine) in self.nodes.values() {
+                assert!(
+                    longest.starts_with(&machine.state),
+                    "state machines diverged"
+                );
+            }
+        }
+
+        fn propose(&mut self, leader: NodeId, commands: std::ops::Range<u8>) {
+            for command in commands {
+                self.replica(leader).propose(vec![command]).unwrap();
+            }
+            let queue = self.replica(leader).take_messages().unwrap();
+            self.deliver(queue.into_iter().map(|m| (leader, m)).collect());
+        }
+
+        fn leader(&self) -> NodeId {
+            let leaders: Vec<NodeId> = self
+                .nodes
+                .values()
+                .filter(|(replica, _)| replica.is_leader())
+                .map(|(replica, _)| replica.node_id())
+                .collect();
+            assert_eq!(leaders.len(), 1, "no single leader");
+            leaders[0]
+        }
+
+        fn state(&self, node: NodeId) -> &[u8] {
+            &self.nodes[&node].1.state
+        }
+    }
+
+    #[test]
+    fn replicates_snapshots_and_changes_membership() {
+        let mut network = Network::new(3);
+        network.run(30);
+        let leader = network.leader();
+        network.propose(leader, 0..50);
+        network.run(5);
+        let expected: Vec<u8> = (0..50).collect();
+        for node in 1..=3 {
+            assert_eq!(network.state(NodeId(node)), &expected[..]);
+        }
+
+        // A follower that misses compacted entries catches up by snapshot
+        let lagging = *network.nodes.keys().find(|n| **n != leader).unwrap();
+        network.isolate(lagging);
+        network.propose(leader, 50..120);
+        network.run(5);
+        assert!(network.nodes[&leader].0.log.snapshot.index > 50);
+        network.cut.clear();
+        network.run(20);
+        let expected: Vec<u8> = (0..120).collect();
+        assert_eq!(network.state(lagging), &expected[..]);
+        assert!(network.nodes[&lagging].1.restored);
+
+        // Add a fourth node, it gets everything too
+        let voters = [NodeId(1), NodeId(2), NodeId(3)];
+        network.add(NodeId(4), &voters);
+        let leader = network.leader();
+        let added = [NodeId(1), NodeId(2), NodeId(3), NodeId(4)];
+        network.replica(leader).change_membership(&added).unwrap();
+        network.run(20);
+        assert_eq!(network.state(NodeId(4)), &expected[..]);
+        assert_eq!(network.replica(NodeId(4)).voters(), added.to_vec());
+
+        // Removing the leader hands leadership to someone else
+        let remaining: Vec<NodeId> = added.iter().copied().filter(|n| *n != leader).collect();
+        network
+            .replica(leader)
+            .change_membership(&remaining)
+            .unwrap();
+        network.run(60);
+        let next = network.leader();
+        assert_ne!(next, leader);
+        network.propose(next, 120..130);
+        network.run(5);
+        let expected: Vec<u8> = (0..130).collect();
+        for node in remaining {
+            assert_eq!(network.state(node), &expected[..]);
+        }
+    }
+
+    #[test]
+    fn sends_each_proposal_once() {
+        let mut network = Network::new(3);
+        network.run(30);
+        let leader = network.leader();
+        for command in 0..50 {
+            network.replica(leader).propose(vec![command]).unwrap();
+        }
+        let sent: usize = network
+            .replica(leader)
+            .take_messages()
+            .unwrap()
+            .iter()
+            .map(|(_, message)| match message {
+                RaftMessage::Append { entries, .. } => entries.len(),
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(sent, 2 * 50);
+    }
+
+    #[test]
+    fn rejects_proposals_off_leader() {
+        let mut network = Network::new(3);
+        network.run(30);
+        let leader = network.leader();
+        let follower = *network.nodes.keys().find(|n| **n != leader).unwrap();
+        assert!(network.replica(follower).propose(vec![1]).is_err());
+        let two_at_once = [NodeId(1), NodeId(5), NodeId(6)];
+        assert!(network
+            .replica(leader)
+            .change_membership(&two_at_once)
+            .is_err());
+    }
+
+    // Survives the replica, like a disk would
+    #[derive(Clone, Default)]
+    struct Disk(Rc<RefCell<Option<Persisted>>>);
+
+    impl Storage for Disk {
+        fn load(&mut self) -> Result<Option<Persisted>, Error> {
+            Ok(self.0.borrow().clone())
+        }
+        fn save_state(&mut self, term: u64, voted_for: Option<NodeId>) -> Result<(), Error> {
+            let mut disk = self.0.borrow_mut();
+            let persisted = disk.as_mut().expect("The snapshot is saved first");
+            persisted.term = term;
+            persisted.voted_for = voted_for;
+            Ok(())
+        }
+        fn save_entries(&mut self, from: u64, entries: &[Entry]) -> Result<(), Error> {
+            let mut disk = self.0.borrow_mut();
+            let persisted = disk.as_mut().expect("The snapshot is saved first");
+            persisted.entries.retain(|entry| entry.index < from);
+            persisted.entries.extend_from_slice(entries);
+            Ok(())
+        }
+        fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+            let mut disk = self.0.borrow_mut();
+            let persisted = disk.get_or_insert_with(|| Persisted {
+                term: 0,
+                voted_for: None,
+                snapshot: snapshot.clone(),
+                entries: vec![],
+            });
+            persisted.snapshot = snapshot.clone();
+            persisted
+                .entries
+                .retain(|entry| entry.index > snapshot.index);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn restarts_with_its_vote_and_log() {
+        let voters = [NodeId(1), NodeId(2), NodeId(3)];
+        let disk = Disk::default();
+        let mut replica = Replica::open(NodeId(1), &voters, Box::new(disk.clone())).unwrap();
+        let (last_index, last_term) = (0, 0);
+        let vote = RaftMessage::RequestVote {
+            term: 1,
+            last_index,
+            last_term,
+        };
+        replica.step(NodeId(2), vote.clone());
+        let granted = RaftMessage::Vote {
+            term: 1,
+            granted: true,
+        };
+        assert_eq!(replica.take_messages().unwrap(), vec![(NodeId(2), granted)]);
+        let entries = vec![
+            Entry {
+                term: 1,
+                index: 1,
+                payload: Payload::Noop,
+            },
+            Entry {
+                term: 1,
+                index: 2,
+                payload: Payload::Command(vec![7]),
+            },
+        ];
+        let append = RaftMessage::Append {
+            term: 1,
+            prev_index: 0,
+            prev_term: 0,
+            entries: entries.clone(),
+            commit: 0,
+        };
+        replica.step(NodeId(2), append);
+        replica.take_messages().unwrap();
+
+        // Same term, a different candidate, and the vote is already spent
+        let mut replica = Replica::open(NodeId(1), &voters, Box::new(disk)).unwrap();
+        assert_eq!(replica.term(), 1);
+        assert_eq!(replica.log.entries, entries);
+        replica.step(NodeId(3), vote);
+        let refused = RaftMessage::Vote {
+            term: 1,
+            granted: false,
+        };
+        assert_eq!(replica.take_messages().unwrap(), vec![(NodeId(3), refused)]);
+    }
+
+    type Applied = Rc<RefCell<Vec<u8>>>;
+
+    struct Register {
+        applied: Applied,
+    }
+
+    impl RaftApp for Register {
+        fn apply(&mut self, _ctx: &mut RaftContext, _index: u64, command: &[u8]) {
+            self.applied.borrow_mut().extend_from_slice(command);
+        }
+        fn snapshot(&mut self) -> Vec<u8> {
+            self.applied.borrow().clone()
+        }
+        fn restore(&mut self, _ctx: &mut RaftContext, _index: u64, snapshot: &[u8]) {
+            *self.applied.borrow_mut() = snapshot.to_vec();
+        }
+        fn handle_leader_change(&mut self, ctx: &mut RaftContext, leader: Option<NodeId>) {
+            if leader == Some(ctx.node_id()) {
+                ctx.propose([ctx.node_id().0 as u8]).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn replicates_over_connections() {
+        let (addr_a, addr_b) = ("127.0.0.1:13321", "127.0.0.1:13322");
+        let voters = [NodeId(1), NodeId(2), NodeId(3)];
+        let mut cores = vec![];
+        let mut applied = vec![];
+        for node in voters.iter() {
+            let register = Register {
+                applied: Rc::new(RefCell::new(vec![])),
+            };
+            applied.push(register.applied.clone());
+            let raft = Raft::new(*node, &voters, register)
+                .tick_interval(Duration::from_millis(10))
+                .election_ticks(5);
+            cores.push(Core::new(raft));
+        }
+        cores[0].listen(addr_a).unwrap();
+        cores[1].listen(addr_b).unwrap();
+        cores[1].connect(addr_a).unwrap();
+        cores[2].connect(addr_a).unwrap();
+        cores[2].connect(addr_b).unwrap();
+
+        let agreed = || {
+            let first = applied[0].borrow();
+            !first.is_empty() && applied.iter().all(|a| *a.borrow() == *first)
+        };
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !agreed() && Instant::now() < deadline {
+            for core in cores.iter_mut() {
+                core.turn(tick).unwrap();
+            }
+        }
+        assert!(agreed());
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use crate::NodeId;
+
+// Distance at which a node counts as unreachable, bounds count-to-infinity
+pub const MAX_METRIC: u8 = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub via: NodeId,
+    pub metric: u8,
+    pub(crate) conn: usize,
+}
+
+// Distance-vector routing table.
+// Neighbors send their whole table whenever it changes, so an advertisement
+// replaces everything previously heard on that connection and a missing
+// entry is a withdrawal.
+pub(crate) struct RoutingTable {
+    node: NodeId,
+    // Connections that have completed the Hello exchange
+    neighbors: HashMap<usize, NodeId>,
+    links: HashMap<NodeId, usize>,
+    advertised: HashMap<usize, HashMap<NodeId, u8>>,
+    routes: HashMap<NodeId, Route>,
+}
+
+impl RoutingTable {
+    pub fn new(node: NodeId) -> Self {
+        RoutingTable {
+            node,
+            neighbors: HashMap::new(),
+            links: HashMap::new(),
+            advertised: HashMap::new(),
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn neighbor_conns<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        self.neighbors.keys().copied()
+    }
+    pub fn links<'a>(&'a self) -> impl Iterator<Item = (NodeId, usize)> + 'a {
+        self.links.iter().map(|(node, conn)| (*node, *conn))
+    }
+    pub fn routes(&self) -> &HashMap<NodeId, Route> {
+        &self.routes
+    }
+    pub fn route(&self, dst: NodeId) -> Option<usize> {
+        self.routes.get(&dst).map(|route| route.conn)
+    }
+
+    pub fn link_up(&mut self, conn: usize, node: NodeId) -> bool {
+        self.neighbors.insert(conn, node);
+        self.links.insert(node, conn);
+        self.recompute()
+    }
+
+    pub fn link_down(&mut self, conn: usize) -> bool {
+        self.advertised.remove(&conn);
+        if let Some(node) = self.neighbors.remove(&conn) {
+            if self.links.get(&node) == Some(&conn) {
+                self.links.remove(&node);
+                // Fall back to any other connection to the same node
+                if let Some((other, _)) = self.neighbors.iter().find(|(_, n)| **n == node) {
+                    self.links.insert(node, *other);
+                }
+            }
+        }
+        self.recompute()
+    }
+
+    pub fn advertisement(&mut self, conn: usize, routes: Vec<(NodeId, u8)>) -> bool {
+        if !self.neighbors.contains_key(&conn) {
+            return false;
+        }
+        self.advertised.insert(conn, routes.into_iter().collect());
+        self.recompute()
+    }
+
+    // Split horizon: never advertise a route back to where it came from
+    pub fn advertisement_for(&self, conn: usize) -> Vec<(NodeId, u8)> {
+        self.routes
+            .iter()
+            .filter(|(_, route)| route.conn != conn)
+            .map(|(node, route)| (*node, route.metric))
+            .collect()
+    }
+
+    fn recompute(&mut self) -> bool {
+        let mut routes: HashMap<NodeId, Route> = HashMap::new();
+        for (node, conn) in self.links.iter() {
+            let route = Route {
+                via: *node,
+                metric: 1,
+                conn: *conn,
+            };
+            routes.insert(*node, route);
+        }
+        for (conn, advertised) in self.advertised.iter() {
+            let via = self.neighbors[conn];
+            for (node, metric) in advertised.iter() {
+                let metric = metric.saturating_add(1);
+                if *node == self.node || metric >= MAX_METRIC {
+                    continue;
+                }
+                let better = match routes.get(node) {
+                    Some(route) => (metric, via) < (route.metric, route.via),
+                    None => true,
+                };
+                if better {
+                    routes.insert(
+                        *node,
+                        Route {
+                            via,
+                            metric,
+                            conn: *conn,
+                        },
+                    );
+                }
+            }
+        }
+        let changed = routes != self.routes;
+        self.routes = routes;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_shortest_path_and_withdraws() {
+        let (a, b, c, d) = (NodeId(1), NodeId(2), NodeId(3), NodeId(4));
+        let mut table = RoutingTable::new(a);
+        table.link_up(10, b);
+        table.link_up(11, c);
+        table.advertisement(10, vec![(d, 3)]);
+        table.advertisement(11, vec![(d, 1)]);
+        assert_eq!(table.routes()[&d].via, c);
+        assert_eq!(table.routes()[&d].metric, 2);
+
+        assert!(table.link_down(11));
+        assert_eq!(table.routes()[&d].via, b);
+        assert_eq!(table.routes()[&d].metric, 4);
+        assert!(table.routes().get(&c).is_none());
+
+        table.advertisement(10, vec![]);
+        assert!(table.route(d).is_none());
+    }
+
+    #[test]
+    fn split_horizon() {
+        let (a, b, c) = (NodeId(1), NodeId(2), NodeId(3));
+        let mut table = RoutingTable::new(a);
+        table.link_up(10, b);
+        table.link_up(11, c);
+        table.advertisement(10, vec![(NodeId(9), 1), (a, 1)]);
+        let mut to_b = table.advertisement_for(10);
+        to_b.sort();
+        assert_eq!(to_b, vec![(c, 1)]);
+        let mut to_c = table.advertisement_for(11);
+        to_c.sort();
+        assert_eq!(to_c, vec![(b, 1), (NodeId(9), 2)]);
+    }
+}
@@ -0,0 +1,349 @@
+use bytes::{BufMut, Bytes, BytesMut, IntoBuf};
+use failure::{format_err, Error};
+use mio_framed::{App, Context, Ready};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::os::unix::io::{OwnedFd, RawFd};
+use std::time::Duration;
+
+// Frames start with the receiver's u16 little-endian id for the service,
+// the id space is announced in each side's Hello
+const HELLO_ID: u16 = u16::MAX;
+const ID_LEN: usize = 2;
+
+// Timer and fd tokens carry the service id in the low bits
+const SERVICE_BITS: usize = 16;
+
+fn service_token(service: u16, token: usize) -> usize {
+    token << SERVICE_BITS | service as usize
+}
+
+pub trait Service {
+    fn handle_init(&mut self, _ctx: &mut ServiceContext) {}
+    // The peer on `conn` finished its Hello, so its services are known
+    fn handle_connect(&mut self, _ctx: &mut ServiceContext, _conn: usize) {}
+    fn handle_close(&mut self, _ctx: &mut ServiceContext, _conn: usize) {}
+    fn handle_frames(&mut self, _ctx: &mut ServiceContext, _conn: usize, _frames: Vec<Bytes>) {}
+    fn handle_timeout(&mut self, _ctx: &mut ServiceContext, _token: usize) {}
+    fn handle_ready(&mut self, _ctx: &mut ServiceContext, _token: usize, _readiness: Ready) {}
+    fn handle_shutdown(&mut self) {}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Hello {
+    // Indexed by service id
+    services: Vec<ServiceInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServiceInfo {
+    pub name: String,
+    // False for handlers that only talk to the same service on other peers
+    pub offered: bool,
+}
+
+pub struct ServiceContext<'a> {
+    ctx: &'a Context,
+    service: u16,
+    names: &'a [ServiceInfo],
+    peers: &'a HashMap<usize, Vec<ServiceInfo>>,
+}
+
+impl<'a> ServiceContext<'a> {
+    pub fn context(&self) -> &Context {
+        self.ctx
+    }
+    pub fn name(&self) -> &str {
+        &self.names[self.service as usize].name
+    }
+    // Connections whose Hello has arrived
+    pub fn peers<'b>(&'b self) -> impl Iterator<Item = usize> + 'b {
+        self.peers.keys().copied()
+    }
+    pub fn peer_services(&self, conn: usize) -> Option<&[ServiceInfo]> {
+        self.peers.get(&conn).map(|services| &services[..])
+    }
+    pub fn peer_offers(&self, conn: usize, name: &str) -> bool {
+        self.peer_id(conn, name)
+            .is_some_and(|id| self.peers[&conn][id as usize].offered)
+    }
+    // Send to the service with the same name on the peer
+    pub fn send<B: AsRef<[u8]>>(&self, conn: usize, frame: B) -> Result<(), Error> {
+        self.send_to(conn, self.name(), frame)
+    }
+    pub fn send_to<B: AsRef<[u8]>>(&self, conn: usize, name: &str, frame: B) -> Result<(), Error> {
+        let id = self
+            .peer_id(conn, name)
+            .ok_or_else(|| format_err!("Peer {} has no service {}", conn, name))?;
+        write_frame(self.ctx, conn, id, frame.as_ref());
+        Ok(())
+    }
+    pub fn set_timeout(&self, token: usize, delay: Duration) {
+        self.ctx
+            .set_timeout(service_token(self.service, token), delay);
+    }
+    pub fn cancel_timeout(&self, token: usize) {
+        self.ctx.cancel_timeout(service_token(self.service, token));
+    }
+    // See Context::watch_fd, readiness arrives in Service::handle_ready
+    pub fn watch_fd(&self, token: usize, fd: RawFd, interest: Ready) {
+        self.ctx
+            .watch_fd(service_token(self.service, token), fd, interest);
+    }
+    pub fn unwatch_fd(&self, token: usize) {
+        self.ctx.unwatch_fd(service_token(self.service, token));
+    }
+    pub fn close_fd<F: Into<OwnedFd>>(&self, token: usize, fd: F) {
+        self.ctx.close_fd(service_token(self.service, token), fd);
+    }
+
+    fn peer_id(&self, conn: usize, name: &str) -> Option<u16> {
+        self.peers
+            .get(&conn)?
+            .iter()
+            .position(|info| info.name == name)
+            .map(|id| id as u16)
+    }
+}
+
+fn write_frame(ctx: &Context, conn: usize, id: u16, payload: &[u8]) {
+    let mut frame = BytesMut::with_capacity(ID_LEN + payload.len());
+    frame.put_u16_le(id);
+    frame.put_slice(payload);
+    ctx.write_frame(conn, frame.freeze().into_buf());
+}
+
+// Several named services sharing every connection of one Core
+#[derive(Default)]
+pub struct ServiceHost {
+    names: Vec<ServiceInfo>,
+    services: Vec<Box<dyn Service>>,
+    peers: HashMap<usize, Vec<ServiceInfo>>,
+}
+
+impl ServiceHost {
+    pub fn new() -> Self {
+        ServiceHost::default()
+    }
+
+    // Provide `name` to peers
+    pub fn offer<S: Service + 'static>(self, name: &str, service: S) -> Self {
+        self.register(name, true, Box::new(service))
+    }
+
+    // Handle the client end of a service offered by peers
+    pub fn client<S: Service + 'static>(self, name: &str, service: S) -> Self {
+        self.register(name, false, Box::new(service))
+    }
+
+    fn register(mut self, name: &str, offered: bool, service: Box<dyn Service>) -> Self {
+        assert!(
+            self.names.iter().all(|info| info.name != name),
+            "Service {} registered twice",
+            name
+        );
+        assert!(self.names.len() < HELLO_ID as usize, "Too many services");
+        let name = name.into();
+        self.names.push(ServiceInfo { name, offered });
+        self.services.push(service);
+        self
+    }
+
+    fn hello(&self, ctx: &Context, conn: usize) {
+        let hello = Hello {
+            services: self.names.clone(),
+        };
+        let hello = bincode::serialize(&hello).expect("Hello serialization can't fail");
+        write_frame(ctx, conn, HELLO_ID, &hello);
+    }
+
+    fn each_service<F>(&mut self, ctx: &Context, mut f: F)
+    where
+        F: FnMut(&mut dyn Service, &mut ServiceContext),
+    {
+        for (id, service) in self.services.iter_mut().enumerate() {
+            let mut ctx = ServiceContext {
+                ctx,
+                service: id as u16,
+                names: &self.names,
+                peers: &self.peers,
+            };
+            f(service.as_mut(), &mut ctx);
+        }
+    }
+
+    fn with_service<F>(&mut self, ctx: &Context, id: u16, f: F)
+    where
+        F: FnOnce(&mut dyn Service, &mut ServiceContext),
+    {
+        if let Some(service) = self.services.get_mut(id as usize) {
+            let mut ctx = ServiceContext {
+                ctx,
+                service: id,
+                names: &self.names,
+                peers: &self.peers,
+            };
+            f(service.as_mut(), &mut ctx);
+        }
+    }
+}
+
+impl App for ServiceHost {
+    fn handle_init(&mut self, ctx: &Context) {
+        self.each_service(ctx, |service, ctx| service.handle_init(ctx));
+    }
+    fn handle_connect(&mut self, ctx: &Context, id: usize) {
+        self.hello(ctx, id);
+    }
+    fn handle_accept(&mut self, ctx: &Context, _listen_socket: usize, id: usize) {
+        self.hello(ctx, id);
+    }
+    fn handle_close(&mut self, ctx: &Context, id: usize) {
+        if self.peers.contains_key(&id) {
+            self.each_service(ctx, |service, ctx| service.handle_close(ctx, id));
+            self.peers.remove(&id);
+        }
+    }
+    fn handle_frames(&mut self, ctx: &Context, id: usize, frames: Vec<Bytes>) {
+        let mut batch: Vec<(u16, Vec<Bytes>)> = vec![];
+        for mut frame in frames {
+            if frame.len() < ID_LEN {
+                continue;
+            }
+            let service = u16::from_le_bytes([frame[0], frame[1]]);
+            frame.advance(ID_LEN);
+            if service == HELLO_ID {
+                // Deliver what came before, it was sent under the old Hello
+                for (service, frames) in batch.drain(..) {
+                    self.with_service(ctx, service, |s, ctx| s.handle_frames(ctx, id, frames));
+                }
+                let hello: Hello = match bincode::deserialize(&frame) {
+                    Ok(hello) => hello,
+                    // XXX app.handle_decode_error?
+                    Err(_e) => continue,
+                };
+                let first = self.peers.insert(id, hello.services).is_none();
+                if first {
+                    self.each_service(ctx, |service, ctx| service.handle_connect(ctx, id));
+                }
+                continue;
+            }
+            match batch.last_mut() {
+                Some((last, frames)) if *last == service => frames.push(frame),
+                _ => batch.push((service, vec![frame])),
+            }
+        }
+        for (service, frames) in batch {
+            self.with_service(ctx, service, |s, ctx| s.handle_frames(ctx, id, frames));
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &Context, token: usize) {
+        let service = (token & ((1 << SERVICE_BITS) - 1)) as u16;
+        self.with_service(ctx, service, |s, ctx| {
+            s.handle_timeout(ctx, token >> SERVICE_BITS)
+        });
+    }
+    fn handle_ready(&mut self, ctx: &Context, token: usize, readiness: Ready) {
+        let service = (token & ((1 << SERVICE_BITS) - 1)) as u16;
+        self.with_service(ctx, service, |s, ctx| {
+            s.handle_ready(ctx, token >> SERVICE_BITS, readiness)
+        });
+    }
+    fn handle_shutdown(&mut self) {
+        for service in self.services.iter_mut() {
+            service.handle_shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio_framed::Core;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    struct Upper;
+
+    impl Service for Upper {
+        fn handle_frames(&mut self, ctx: &mut ServiceContext, conn: usize, frames: Vec<Bytes>) {
+            for frame in frames {
+                ctx.send(conn, frame.to_ascii_uppercase()).unwrap();
+            }
+        }
+    }
+
+    struct Reverse;
+
+    impl Service for Reverse {
+        fn handle_frames(&mut self, ctx: &mut ServiceContext, conn: usize, frames: Vec<Bytes>) {
+            for frame in frames {
+                let reversed: Vec<u8> = frame.iter().rev().copied().collect();
+                ctx.send(conn, reversed).unwrap();
+            }
+        }
+    }
+
+    type Replies = Rc<RefCell<Vec<(String, Bytes)>>>;
+
+    struct Caller {
+        replies: Replies,
+    }
+
+    impl Service for Caller {
+        fn handle_connect(&mut self, ctx: &mut ServiceContext, conn: usize) {
+            if ctx.peer_offers(conn, ctx.name()) {
+                ctx.send(conn, "hello").unwrap();
+            }
+            assert!(ctx.send_to(conn, "missing", "hello").is_err());
+        }
+        fn handle_frames(&mut self, ctx: &mut ServiceContext, _conn: usize, frames: Vec<Bytes>) {
+            let name = ctx.name().to_string();
+            let mut replies = self.replies.borrow_mut();
+            replies.extend(frames.into_iter().map(|frame| (name.clone(), frame)));
+        }
+    }
+
+    #[test]
+    fn multiplexes_named_services() {
+        let addr = "127.0.0.1:13310";
+        let replies = Rc::new(RefCell::new(vec![]));
+        let caller = || Caller {
+            replies: replies.clone(),
+        };
+
+        let mut server = Core::new(
+            ServiceHost::new()
+                .offer("upper", Upper)
+                .offer("reverse", Reverse),
+        );
+        server.listen(addr).unwrap();
+        // Registered in a different order, so the ids differ on each side
+        let mut client = Core::new(
+            ServiceHost::new()
+                .client("reverse", caller())
+                .client("upper", caller())
+                .client("chat", caller()),
+        );
+        client.connect(addr).unwrap();
+
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while replies.borrow().len() < 2 && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+        let mut replies = replies.borrow().clone();
+        replies.sort();
+        assert_eq!(
+            replies,
+            vec![
+                ("reverse".to_string(), Bytes::from("olleh")),
+                ("upper".to_string(), Bytes::from("HELLO")),
+            ]
+        );
+    }
+}
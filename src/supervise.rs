@@ -0,0 +1,689 @@
+use bytes::Bytes;
+use failure::{bail, format_err, Error};
+use mio_framed::Backoff;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::exec::ExitStatus;
+use crate::service::{Service, ServiceContext};
+
+const TIMER_POLL: usize = 0;
+// A run this long resets the restart backoff
+const STABLE_AFTER: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RestartPolicy {
+    Always,
+    OnFailure,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ProcessState {
+    Running,
+    // Exited and waiting out the backoff before the next start
+    Restarting,
+    Exited,
+    Stopped,
+}
+
+// A live process and its descendants, read from /proc
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProcessNode {
+    pub pid: u32,
+    pub command: String,
+    pub cpu_ms: u64,
+    pub rss_kb: u64,
+    pub children: Vec<ProcessNode>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SupervisedInfo {
+    pub name: String,
+    pub program: String,
+    pub args: Vec<String>,
+    pub policy: RestartPolicy,
+    pub state: ProcessState,
+    pub pid: Option<u32>,
+    pub restarts: u32,
+    pub started_ms: u64,
+    pub last_exit: Option<ExitStatus>,
+    // None where /proc isn't available
+    pub tree: Option<ProcessNode>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExitEvent {
+    pub name: String,
+    pub pid: u32,
+    pub status: ExitStatus,
+    pub restarting: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SuperviseRequest {
+    // Replaces a process of the same name unless it is still running
+    Start {
+        id: u64,
+        name: String,
+        program: String,
+        args: Vec<String>,
+        policy: RestartPolicy,
+    },
+    Stop {
+        id: u64,
+        name: String,
+    },
+    Tree {
+        id: u64,
+    },
+    // Every exit is sent as an Exit event tagged with this id
+    Subscribe {
+        id: u64,
+    },
+    Unsubscribe {
+        id: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SuperviseResponse {
+    Started {
+        id: u64,
+        pid: u32,
+    },
+    Tree {
+        id: u64,
+        processes: Vec<SupervisedInfo>,
+    },
+    Exit {
+        id: u64,
+        event: ExitEvent,
+    },
+    Done {
+        id: u64,
+    },
+    Error {
+        id: u64,
+        message: String,
+    },
+}
+
+impl SuperviseResponse {
+    pub fn id(&self) -> u64 {
+        match self {
+            SuperviseResponse::Started { id, .. }
+            | SuperviseResponse::Tree { id, .. }
+            | SuperviseResponse::Exit { id, .. }
+            | SuperviseResponse::Done { id }
+            | SuperviseResponse::Error { id, .. } => *id,
+        }
+    }
+}
+
+fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    bincode::serialize(message).expect("Supervise message serialization can't fail")
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+struct ProcStat {
+    ppid: u32,
+    command: String,
+    cpu_ticks: u64,
+    rss_pages: u64,
+}
+
+// Fields of /proc/<pid>/stat, see proc(5)
+fn proc_stat(pid: u32) -> Option<ProcStat> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command is in parentheses and may itself contain spaces or parentheses
+    let open = stat.find('(')?;
+    let close = stat.rfind(')')?;
+    let command = stat[open + 1..close].to_string();
+    let fields: Vec<&str> = stat[close + 1..].split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok());
+    Some(ProcStat {
+        ppid: field(4)? as u32,
+        command,
+        cpu_ticks: field(14)? + field(15)?,
+        rss_pages: field(24)?,
+    })
+}
+
+// Everything in /proc, so descendants can be found by parent pid
+fn proc_table() -> Option<HashMap<u32, ProcStat>> {
+    let mut table = HashMap::new();
+    for entry in fs::read_dir("/proc").ok()?.flatten() {
+        let pid = match entry.file_name().to_string_lossy().parse::<u32>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        // Processes come and go while we look, skip the ones that went
+        if let Some(stat) = proc_stat(pid) {
+            table.insert(pid, stat);
+        }
+    }
+    Some(table)
+}
+
+fn process_node(pid: u32, table: &HashMap<u32, ProcStat>) -> Option<ProcessNode> {
+    let stat = table.get(&pid)?;
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let page_kb = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64 / 1024;
+    let mut children: Vec<ProcessNode> = table
+        .iter()
+        .filter(|(_, child)| child.ppid == pid)
+        .filter_map(|(child, _)| process_node(*child, table))
+        .collect();
+    children.sort_by_key(|child| child.pid);
+    Some(ProcessNode {
+        pid,
+        command: stat.command.clone(),
+        cpu_ms: stat.cpu_ticks * 1000 / ticks_per_sec,
+        rss_kb: stat.rss_pages * page_kb,
+        children,
+    })
+}
+
+struct Supervised {
+    program: String,
+    args: Vec<String>,
+    policy: RestartPolicy,
+    state: ProcessState,
+    child: Option<Child>,
+    // Failed starts in a row, drives the backoff
+    attempt: u32,
+    restarts: u32,
+    started: Instant,
+    started_at: SystemTime,
+    restart_at: Option<Instant>,
+    last_exit: Option<ExitStatus>,
+}
+
+impl Supervised {
+    fn start(&mut self) -> Result<u32, Error> {
+        // XXX Output goes nowhere, a log capture would be nice
+        // Its own process group, so stop reaches whatever it spawned too
+        let child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn()?;
+        let pid = child.id();
+        self.child = Some(child);
+        self.state = ProcessState::Running;
+        self.started = Instant::now();
+        self.started_at = SystemTime::now();
+        self.restart_at = None;
+        Ok(pid)
+    }
+
+    fn stop(&mut self) -> Option<(u32, std::process::ExitStatus)> {
+        self.state = ProcessState::Stopped;
+        self.restart_at = None;
+        let mut child = self.child.take()?;
+        unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) };
+        let status = child.wait().ok()?;
+        Some((child.id(), status))
+    }
+}
+
+// Keeps named long-running processes alive according to their restart policy.
+// They belong to the node rather than a connection, so they outlive the
+// controller that started them.
+pub struct SupervisorService {
+    allowed: HashSet<String>,
+    backoff: Backoff,
+    poll_interval: Duration,
+    processes: BTreeMap<String, Supervised>,
+    subscribers: HashSet<(usize, u64)>,
+}
+
+impl Default for SupervisorService {
+    fn default() -> Self {
+        SupervisorService {
+            allowed: HashSet::new(),
+            backoff: Backoff::new(Duration::from_millis(100), Duration::from_secs(30)).jitter(0.0),
+            poll_interval: Duration::from_millis(100),
+            processes: BTreeMap::new(),
+            subscribers: HashSet::new(),
+        }
+    }
+}
+
+impl SupervisorService {
+    pub fn new() -> Self {
+        SupervisorService::default()
+    }
+
+    pub fn allow(mut self, program: &str) -> Self {
+        self.allowed.insert(program.into());
+        self
+    }
+
+    // Delay before each restart, growing while a process keeps failing
+    pub fn restart_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    // How often processes are checked for exits
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub fn info(&self) -> Vec<SupervisedInfo> {
+        let table = proc_table();
+        self.processes
+            .iter()
+            .map(|(name, process)| {
+                let pid = process.child.as_ref().map(|child| child.id());
+                let tree = match (pid, &table) {
+                    (Some(pid), Some(table)) => process_node(pid, table),
+                    _ => None,
+                };
+                SupervisedInfo {
+                    name: name.clone(),
+                    program: process.program.clone(),
+                    args: process.args.clone(),
+                    policy: process.policy,
+                    state: process.state,
+                    pid,
+                    restarts: process.restarts,
+                    started_ms: unix_ms(process.started_at),
+                    last_exit: process.last_exit.clone(),
+                    tree,
+                }
+            })
+            .collect()
+    }
+
+    fn send(ctx: &ServiceContext, conn: usize, response: &SuperviseResponse) {
+        // The peer may not run a supervise client, then there's nobody to tell
+        let _ = ctx.send(conn, encode(response));
+    }
+
+    fn publish(&self, ctx: &ServiceContext, event: ExitEvent) {
+        for (conn, id) in self.subscribers.iter() {
+            let event = event.clone();
+            Self::send(ctx, *conn, &SuperviseResponse::Exit { id: *id, event });
+        }
+    }
+
+    fn handle_request(&mut self, ctx: &ServiceContext, conn: usize, request: SuperviseRequest) {
+        let id = match &request {
+            SuperviseRequest::Start { id, .. }
+            | SuperviseRequest::Stop { id, .. }
+            | SuperviseRequest::Tree { id }
+            | SuperviseRequest::Subscribe { id }
+            | SuperviseRequest::Unsubscribe { id } => *id,
+        };
+        let response = match self.run(ctx, conn, request) {
+            Ok(response) => response,
+            Err(e) => SuperviseResponse::Error {
+                id,
+                message: e.to_string(),
+            },
+        };
+        Self::send(ctx, conn, &response);
+    }
+
+    fn run(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        request: SuperviseRequest,
+    ) -> Result<SuperviseResponse, Error> {
+        match request {
+            SuperviseRequest::Start {
+                id,
+                name,
+                program,
+                args,
+                policy,
+            } => {
+                if !self.allowed.contains(&program) {
+                    bail!("{} is not allowed", program);
+                }
+                let running = self.processes.get(&name).is_some_and(|process| {
+                    process.state == ProcessState::Running
+                        || process.state == ProcessState::Restarting
+                });
+                if running {
+                    bail!("{} is already running", name);
+                }
+                let mut process = Supervised {
+                    program,
+                    args,
+                    policy,
+                    state: ProcessState::Exited,
+                    child: None,
+                    attempt: 0,
+                    restarts: 0,
+                    started: Instant::now(),
+                    started_at: SystemTime::now(),
+                    restart_at: None,
+                    last_exit: None,
+                };
+                let pid = process.start()?;
+                self.processes.insert(name, process);
+                Ok(SuperviseResponse::Started { id, pid })
+            }
+            SuperviseRequest::Stop { id, name } => {
+                let process = self
+                    .processes
+                    .get_mut(&name)
+                    .ok_or_else(|| format_err!("No process named {}", name))?;
+                if let Some((pid, status)) = process.stop() {
+                    let status = ExitStatus {
+                        code: status.code(),
+                        signal: status.signal(),
+                        timed_out: false,
+                    };
+                    process.last_exit = Some(status.clone());
+                    let event = ExitEvent {
+                        name,
+                        pid,
+                        status,
+                        restarting: false,
+                    };
+                    self.publish(ctx, event);
+                }
+                Ok(SuperviseResponse::Done { id })
+            }
+            SuperviseRequest::Tree { id } => Ok(SuperviseResponse::Tree {
+                id,
+                processes: self.info(),
+            }),
+            SuperviseRequest::Subscribe { id } => {
+                self.subscribers.insert((conn, id));
+                Ok(SuperviseResponse::Done { id })
+            }
+            SuperviseRequest::Unsubscribe { id } => {
+                self.subscribers.remove(&(conn, id));
+                Ok(SuperviseResponse::Done { id })
+            }
+        }
+    }
+
+    // Notice exits, apply restart policies and start what is due
+    fn poll(&mut self, ctx: &ServiceContext) {
+        let now = Instant::now();
+        let mut events = vec![];
+        for (name, process) in self.processes.iter_mut() {
+            if process.restart_at.is_some_and(|at| now >= at) {
+                if process.start().is_ok() {
+                    process.restarts += 1;
+                } else {
+                    process.attempt += 1;
+                    let delay = self.backoff.delay(process.attempt, &mut rand::thread_rng());
+                    process.restart_at = Some(now + delay);
+                }
+                continue;
+            }
+            let child = match process.child.as_mut() {
+                Some(child) => child,
+                None => continue,
+            };
+            let status = match child.try_wait() {
+                Ok(Some(status)) => status,
+                _ => continue,
+            };
+            let pid = child.id();
+            process.child = None;
+            let restarting = match process.policy {
+                RestartPolicy::Always => true,
+                RestartPolicy::OnFailure => !status.success(),
+                RestartPolicy::Never => false,
+            };
+            if restarting {
+                if now.duration_since(process.started) >= STABLE_AFTER {
+                    process.attempt = 0;
+                }
+                process.attempt += 1;
+                let delay = self.backoff.delay(process.attempt, &mut rand::thread_rng());
+                process.state = ProcessState::Restarting;
+                process.restart_at = Some(now + delay);
+            } else {
+                process.state = ProcessState::Exited;
+            }
+            let status = ExitStatus {
+                code: status.code(),
+                signal: status.signal(),
+                timed_out: false,
+            };
+            process.last_exit = Some(status.clone());
+            events.push(ExitEvent {
+                name: name.clone(),
+                pid,
+                status,
+                restarting,
+            });
+        }
+        for event in events {
+            self.publish(ctx, event);
+        }
+    }
+}
+
+impl Service for SupervisorService {
+    fn handle_init(&mut self, ctx: &mut ServiceContext) {
+        ctx.set_timeout(TIMER_POLL, self.poll_interval);
+    }
+    fn handle_close(&mut self, _ctx: &mut ServiceContext, conn: usize) {
+        self.subscribers
+            .retain(|(subscriber, _)| *subscriber != conn);
+    }
+    fn handle_frames(&mut self, ctx: &mut ServiceContext, conn: usize, frames: Vec<Bytes>) {
+        for frame in frames {
+            match bincode::deserialize(&frame) {
+                Ok(request) => self.handle_request(ctx, conn, request),
+                // XXX app.handle_decode_error?
+                Err(_e) => continue,
+            }
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &mut ServiceContext, _token: usize) {
+        self.poll(ctx);
+        ctx.set_timeout(TIMER_POLL, self.poll_interval);
+    }
+    fn handle_shutdown(&mut self) {
+        for process in self.processes.values_mut() {
+            process.stop();
+        }
+    }
+}
+
+// Builds requests for the "supervise" service, for use in a client Service
+#[derive(Default)]
+pub struct SupervisorClient {
+    next_id: u64,
+}
+
+impl SupervisorClient {
+    pub fn new() -> Self {
+        SupervisorClient::default()
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<SuperviseResponse, Error> {
+        Ok(bincode::deserialize(frame)?)
+    }
+
+    fn request<F>(&mut self, ctx: &ServiceContext, conn: usize, build: F) -> Result<u64, Error>
+    where
+        F: FnOnce(u64) -> SuperviseRequest,
+    {
+        self.next_id += 1;
+        let id = self.next_id;
+        ctx.send_to(conn, "supervise", encode(&build(id)))?;
+        Ok(id)
+    }
+
+    pub fn start(
+        &mut self,
+        ctx: &ServiceContext,
+        conn: usize,
+        name: &str,
+        command: &[&str],
+        policy: RestartPolicy,
+    ) -> Result<u64, Error> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| format_err!("Empty command for {}", name))?;
+        let name = name.into();
+        let program = program.to_string();
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        self.request(ctx, conn, |id| SuperviseRequest::Start {
+            id,
+            name,
+            program,
+            args,
+            policy,
+        })
+    }
+    pub fn stop(&mut self, ctx: &ServiceContext, conn: usize, name: &str) -> Result<u64, Error> {
+        let name = name.into();
+        self.request(ctx, conn, |id| SuperviseRequest::Stop { id, name })
+    }
+    pub fn tree(&mut self, ctx: &ServiceContext, conn: usize) -> Result<u64, Error> {
+        self.request(ctx, conn, |id| SuperviseRequest::Tree { id })
+    }
+    pub fn subscribe(&mut self, ctx: &ServiceContext, conn: usize) -> Result<u64, Error> {
+        self.request(ctx, conn, |id| SuperviseRequest::Subscribe { id })
+    }
+    pub fn unsubscribe(&self, ctx: &ServiceContext, conn: usize, id: u64) -> Result<(), Error> {
+        let request = SuperviseRequest::Unsubscribe { id };
+        ctx.send_to(conn, "supervise", encode(&request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ServiceHost;
+    use mio_framed::Core;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Seen {
+        events: Vec<ExitEvent>,
+        tree: Option<Vec<SupervisedInfo>>,
+        stopped: bool,
+    }
+
+    struct Controller {
+        client: SupervisorClient,
+        tree_requested: bool,
+        seen: Rc<RefCell<Seen>>,
+    }
+
+    impl Service for Controller {
+        fn handle_connect(&mut self, ctx: &mut ServiceContext, conn: usize) {
+            let client = &mut self.client;
+            client.subscribe(ctx, conn).unwrap();
+            let flaky = ["sh", "-c", "exit 3"];
+            let once = ["sh", "-c", "exit 0"];
+            // Leaves its pid where the test can check stop took it down too
+            let grandchild = format!("sleep 5 & echo $! > {}; wait", grandchild_file().display());
+            let sleeper = ["sh", "-c", &grandchild];
+            client
+                .start(ctx, conn, "flaky", &flaky, RestartPolicy::OnFailure)
+                .unwrap();
+            client
+                .start(ctx, conn, "once", &once, RestartPolicy::OnFailure)
+                .unwrap();
+            client
+                .start(ctx, conn, "sleeper", &sleeper, RestartPolicy::Never)
+                .unwrap();
+        }
+        fn handle_frames(&mut self, ctx: &mut ServiceContext, conn: usize, frames: Vec<Bytes>) {
+            let mut seen = self.seen.borrow_mut();
+            for frame in frames {
+                match SupervisorClient::decode(&frame).unwrap() {
+                    SuperviseResponse::Exit { event, .. } => seen.events.push(event),
+                    SuperviseResponse::Tree { processes, .. } => {
+                        seen.tree = Some(processes);
+                        self.client.stop(ctx, conn, "sleeper").unwrap();
+                    }
+                    SuperviseResponse::Done { .. } if seen.tree.is_some() => seen.stopped = true,
+                    _ => {}
+                }
+            }
+            let flaky_exits = seen.events.iter().filter(|e| e.name == "flaky").count();
+            if flaky_exits >= 2 && !self.tree_requested {
+                self.tree_requested = true;
+                self.client.tree(ctx, conn).unwrap();
+            }
+        }
+    }
+
+    fn grandchild_file() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("mesh-msg-grandchild-{}", std::process::id()))
+    }
+
+    #[test]
+    fn restarts_by_policy_and_reports_the_tree() {
+        let addr = "127.0.0.1:13313";
+        let seen = Rc::new(RefCell::new(Seen::default()));
+        let backoff = Backoff::new(Duration::from_millis(5), Duration::from_millis(20));
+        let service = SupervisorService::new()
+            .allow("sh")
+            .restart_backoff(backoff.jitter(0.0))
+            .poll_interval(Duration::from_millis(5));
+        let mut server = Core::new(ServiceHost::new().offer("supervise", service));
+        server.listen(addr).unwrap();
+        let controller = Controller {
+            client: SupervisorClient::new(),
+            tree_requested: false,
+            seen: seen.clone(),
+        };
+        let mut client = Core::new(ServiceHost::new().client("supervise", controller));
+        client.connect(addr).unwrap();
+
+        let done = |seen: &Seen| seen.stopped && seen.events.iter().any(|e| e.name == "sleeper");
+        let tick = Some(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(&seen.borrow()) && Instant::now() < deadline {
+            server.turn(tick).unwrap();
+            client.turn(tick).unwrap();
+        }
+
+        let seen = seen.borrow();
+        let flaky: Vec<&ExitEvent> = seen.events.iter().filter(|e| e.name == "flaky").collect();
+        assert!(flaky.len() >= 2);
+        assert!(flaky
+            .iter()
+            .all(|e| e.restarting && e.status.code == Some(3)));
+        let once: Vec<&ExitEvent> = seen.events.iter().filter(|e| e.name == "once").collect();
+        assert_eq!(once.len(), 1);
+        assert!(!once[0].restarting);
+
+        let tree = seen.tree.as_ref().unwrap();
+        let sleeper = tree.iter().find(|info| info.name == "sleeper").unwrap();
+        assert_eq!(sleeper.state, ProcessState::Running);
+        if let Some(node) = &sleeper.tree {
+            assert_eq!(Some(node.pid), sleeper.pid);
+        }
+        let stopped = seen.events.iter().find(|e| e.name == "sleeper").unwrap();
+        assert_eq!(stopped.status.signal, Some(libc::SIGKILL));
+        let grandchild = fs::read_to_string(grandchild_file()).unwrap();
+        let _ = fs::remove_file(grandchild_file());
+        // Gone, or a zombie waiting for whoever inherited it
+        let stat = fs::read_to_string(format!("/proc/{}/stat", grandchild.trim()));
+        assert!(stat.map_or(true, |stat| stat.contains(") Z ")));
+    }
+}
@@ -0,0 +1,614 @@
+use bytes::Bytes;
+use mio_framed::{App, Context, Core};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::transport::{timer_kind, timer_token, Transport, TIMER_APP};
+use crate::NodeId;
+
+const TIMER_PROBE: usize = 1;
+const TIMER_ACK: usize = 2;
+
+// Updates piggybacked on each message
+const MAX_PIGGYBACK: usize = 16;
+// Updates are gossiped this many times log2 of the cluster size
+const RETRANSMIT_MULT: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemberState {
+    Alive,
+    // Missed a probe, dead unless it refutes within the suspicion timeout
+    Suspect,
+    Dead,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Update {
+    node: NodeId,
+    state: MemberState,
+    // Only the member itself raises its incarnation, to refute suspicion
+    incarnation: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    // Sent on every new connection, with everything the sender knows
+    Hello { members: Vec<Update> },
+    Ping { seq: u64 },
+    Ack { seq: u64 },
+    // Ask a neighbor to probe `target` for us
+    PingReq { seq: u64, target: NodeId },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Gossip {
+    message: Message,
+    updates: Vec<Update>,
+}
+
+pub trait SwimApp {
+    fn handle_init(&mut self, _ctx: &mut SwimContext) {}
+    fn handle_connect(&mut self, _ctx: &mut SwimContext, _id: usize) {}
+    fn handle_accept(&mut self, _ctx: &mut SwimContext, _listen_socket: usize, _id: usize) {}
+    fn handle_close(&mut self, _ctx: &mut SwimContext, _id: usize) {}
+    // Joined, became suspect, died or came back
+    fn handle_member(&mut self, _ctx: &mut SwimContext, _node: NodeId, _state: MemberState) {}
+    fn handle_frames(&mut self, _ctx: &mut SwimContext, _id: usize, _frames: Vec<Bytes>) {}
+    fn handle_timeout(&mut self, _ctx: &mut SwimContext, _token: usize) {}
+    fn handle_shutdown(&mut self) {}
+}
+
+pub struct SwimContext<'a> {
+    ctx: &'a Context,
+    state: &'a mut SwimState,
+}
+
+impl<'a> SwimContext<'a> {
+    pub fn context(&self) -> &Context {
+        self.ctx
+    }
+    pub fn node_id(&self) -> NodeId {
+        self.state.node
+    }
+    pub fn incarnation(&self) -> u64 {
+        self.state.incarnation
+    }
+    // Every other node ever heard of, dead ones included
+    pub fn members<'b>(&'b self) -> impl Iterator<Item = (NodeId, MemberState)> + 'b {
+        self.state
+            .members
+            .iter()
+            .map(|(node, member)| (*node, member.state))
+    }
+    pub fn member(&self, node: NodeId) -> Option<MemberState> {
+        self.state.members.get(&node).map(|member| member.state)
+    }
+    // The node on the other end of a connection, once its Hello arrived
+    pub fn node_of(&self, id: usize) -> Option<NodeId> {
+        self.state.transport.node_of(id)
+    }
+    pub fn send<B: AsRef<[u8]>>(&self, id: usize, frame: B) {
+        self.state.transport.send_app(self.ctx, id, frame.as_ref());
+    }
+    pub fn set_timeout(&self, token: usize, delay: Duration) {
+        self.ctx.set_timeout(timer_token(TIMER_APP, token), delay);
+    }
+    pub fn cancel_timeout(&self, token: usize) {
+        self.ctx.cancel_timeout(timer_token(TIMER_APP, token));
+    }
+}
+
+struct Member {
+    state: MemberState,
+    incarnation: u64,
+    suspected: Option<Instant>,
+}
+
+struct Probe {
+    seq: u64,
+    target: NodeId,
+    acked: bool,
+}
+
+struct SwimState {
+    node: NodeId,
+    incarnation: u64,
+    probe_interval: Duration,
+    ack_timeout: Duration,
+    suspicion_timeout: Duration,
+    indirect_probes: usize,
+    members: BTreeMap<NodeId, Member>,
+    transport: Transport,
+    next_seq: u64,
+    probe: Option<Probe>,
+    // Our seq of a probe made for someone else -> (their connection, their seq, when)
+    relays: HashMap<u64, (usize, u64, Instant)>,
+    // Updates still to gossip, with how many more times
+    broadcasts: Vec<(Update, u32)>,
+    events: Vec<(NodeId, MemberState)>,
+}
+
+impl SwimState {
+    fn new(node: NodeId) -> Self {
+        SwimState {
+            node,
+            incarnation: 0,
+            probe_interval: Duration::from_secs(1),
+            ack_timeout: Duration::from_millis(300),
+            suspicion_timeout: Duration::from_secs(5),
+            indirect_probes: 3,
+            members: BTreeMap::new(),
+            transport: Transport::new(node),
+            next_seq: 0,
+            probe: None,
+            relays: HashMap::new(),
+            broadcasts: vec![],
+            events: vec![],
+        }
+    }
+
+    fn own_update(&self) -> Update {
+        Update {
+            node: self.node,
+            state: MemberState::Alive,
+            incarnation: self.incarnation,
+        }
+    }
+
+    fn broadcast(&mut self, update: Update) {
+        let live = self
+            .members
+            .values()
+            .filter(|member| member.state != MemberState::Dead)
+            .count() as u32;
+        // ceil(log2(n + 2)), so even a pair repeats itself
+        let log_n = 32 - (live + 1).leading_zeros();
+        self.broadcasts
+            .retain(|(queued, _)| queued.node != update.node);
+        self.broadcasts.push((update, RETRANSMIT_MULT * log_n));
+    }
+
+    fn piggyback(&mut self) -> Vec<Update> {
+        // The least sent first, they are the newest news
+        self.broadcasts
+            .sort_by_key(|(_, left)| std::cmp::Reverse(*left));
+        let updates: Vec<Update> = self
+            .broadcasts
+            .iter_mut()
+            .take(MAX_PIGGYBACK)
+            .map(|(update, left)| {
+                *left -= 1;
+                *update
+            })
+            .collect();
+        self.broadcasts.retain(|(_, left)| *left > 0);
+        updates
+    }
+
+    fn send(&mut self, ctx: &Context, conn: usize, message: Message) {
+        let mut updates = self.piggyback();
+        // Whatever gossip has left, a suspect or dead peer needs to hear it to refute
+        let peer = self.transport.node_of(conn).and_then(|node| {
+            let member = self.members.get(&node)?;
+            let state = member.state;
+            let incarnation = member.incarnation;
+            Some(Update {
+                node,
+                state,
+                incarnation,
+            })
+        });
+        if let Some(peer) = peer.filter(|peer| peer.state != MemberState::Alive) {
+            updates.push(peer);
+        }
+        let gossip = Gossip { message, updates };
+        self.transport.send(ctx, conn, &gossip);
+    }
+
+    fn hello(&mut self, ctx: &Context, conn: usize) {
+        let mut members: Vec<Update> = self
+            .members
+            .iter()
+            .map(|(node, member)| Update {
+                node: *node,
+                state: member.state,
+                incarnation: member.incarnation,
+            })
+            .collect();
+        members.push(self.own_update());
+        self.transport.hello(ctx, conn);
+        self.send(ctx, conn, Message::Hello { members });
+    }
+
+    // Apply one piece of gossip, with the usual SWIM precedence
+    fn update(&mut self, update: Update) {
+        if update.node == self.node {
+            if update.state != MemberState::Alive && update.incarnation >= self.incarnation {
+                // Refute, everyone takes the higher incarnation over the rumor
+                self.incarnation = update.incarnation + 1;
+                let own = self.own_update();
+                self.broadcast(own);
+            }
+            return;
+        }
+        let overrides = match self.members.get(&update.node) {
+            None => true,
+            Some(member) => match (update.state, member.state) {
+                (MemberState::Alive, _) => update.incarnation > member.incarnation,
+                (MemberState::Suspect, MemberState::Alive) => {
+                    update.incarnation >= member.incarnation
+                }
+                (MemberState::Suspect, _) => update.incarnation > member.incarnation,
+                (MemberState::Dead, MemberState::Dead) => false,
+                (MemberState::Dead, _) => update.incarnation >= member.incarnation,
+            },
+        };
+        if !overrides {
+            return;
+        }
+        let previous = self.members.insert(
+            update.node,
+            Member {
+                state: update.state,
+                incarnation: update.incarnation,
+                suspected: Some(Instant::now()).filter(|_| update.state == MemberState::Suspect),
+            },
+        );
+        let changed = previous.map_or(update.state != MemberState::Dead, |previous| {
+            previous.state != update.state
+        });
+        if changed {
+            self.events.push((update.node, update.state));
+        }
+        self.broadcast(update);
+    }
+
+    fn suspect(&mut self, node: NodeId) {
+        if let Some(member) = self.members.get(&node) {
+            if member.state == MemberState::Alive {
+                let incarnation = member.incarnation;
+                let state = MemberState::Suspect;
+                self.update(Update {
+                    node,
+                    state,
+                    incarnation,
+                });
+            }
+        }
+    }
+
+    fn closed(&mut self, id: usize) {
+        if let Some(node) = self.transport.closed(id) {
+            // Nobody else may be probing it, so let it prove itself
+            if self.transport.conns_to(node).next().is_none() {
+                self.suspect(node);
+            }
+        }
+    }
+
+    fn received(&mut self, ctx: &Context, conn: usize, gossip: Gossip) {
+        for update in gossip.updates {
+            self.update(update);
+        }
+        match gossip.message {
+            Message::Hello { members } => {
+                for update in members {
+                    self.update(update);
+                }
+            }
+            Message::Ping { seq } => self.send(ctx, conn, Message::Ack { seq }),
+            Message::Ack { seq } => {
+                if let Some((requester, their_seq, _)) = self.relays.remove(&seq) {
+                    self.send(ctx, requester, Message::Ack { seq: their_seq });
+                } else if let Some(probe) = self.probe.as_mut().filter(|probe| probe.seq == seq) {
+                    probe.acked = true;
+                }
+            }
+            Message::PingReq { seq, target } => {
+                // Only a neighbor of the target can help
+                let target_conn = self.transport.conns_to(target).next();
+                if let Some(target_conn) = target_conn {
+                    self.next_seq += 1;
+                    let relay_seq = self.next_seq;
+                    self.relays.insert(relay_seq, (conn, seq, Instant::now()));
+                    self.send(ctx, target_conn, Message::Ping { seq: relay_seq });
+                }
+            }
+        }
+    }
+
+    // One protocol period: judge the last probe, time out suspicion, probe again
+    fn probe(&mut self, ctx: &Context) {
+        if let Some(probe) = self.probe.take() {
+            if !probe.acked {
+                self.suspect(probe.target);
+            }
+        }
+        let now = Instant::now();
+        let expired: Vec<Update> = self
+            .members
+            .iter()
+            .filter(|(_, member)| {
+                member
+                    .suspected
+                    .is_some_and(|since| now.duration_since(since) >= self.suspicion_timeout)
+            })
+            .map(|(node, member)| Update {
+                node: *node,
+                state: MemberState::Dead,
+                incarnation: member.incarnation,
+            })
+            .collect();
+        for update in expired {
+            self.update(update);
+        }
+        let stale = self.probe_interval * 2;
+        self.relays
+            .retain(|_, (_, _, sent)| now.duration_since(*sent) < stale);
+
+        // Members we have a connection to, the rest are someone else's to probe
+        let candidates: Vec<(NodeId, usize)> = self
+            .members
+            .iter()
+            .filter(|(_, member)| member.state != MemberState::Dead)
+            .filter_map(|(node, _)| Some((*node, self.transport.conns_to(*node).next()?)))
+            .collect();
+        if let Some((target, conn)) = candidates.choose(&mut rand::thread_rng()).copied() {
+            self.next_seq += 1;
+            let seq = self.next_seq;
+            self.probe = Some(Probe {
+                seq,
+                target,
+                acked: false,
+            });
+            self.send(ctx, conn, Message::Ping { seq });
+            ctx.set_timeout(timer_token(TIMER_ACK, 0), self.ack_timeout);
+        }
+        ctx.set_timeout(timer_token(TIMER_PROBE, 0), self.probe_interval);
+    }
+
+    // No direct ack in time, ask some other neighbors to try
+    fn ack_timeout(&mut self, ctx: &Context) {
+        let (seq, target) = match &self.probe {
+            Some(probe) if !probe.acked => (probe.seq, probe.target),
+            _ => return,
+        };
+        let mut helpers: Vec<usize> = self
+            .transport
+            .conns()
+            .filter(|(_, node)| *node != target)
+            .filter(|(_, node)| {
+                self.members
+                    .get(node)
+                    .is_some_and(|member| member.state == MemberState::Alive)
+            })
+            .map(|(conn, _)| conn)
+            .collect();
+        helpers.shuffle(&mut rand::thread_rng());
+        helpers.truncate(self.indirect_probes);
+        for conn in helpers {
+            self.send(ctx, conn, Message::PingReq { seq, target });
+        }
+    }
+}
+
+pub struct Swim<A: SwimApp> {
+    app: A,
+    state: SwimState,
+}
+
+pub fn new_swim<A: SwimApp>(node: NodeId, app: A) -> Core<Swim<A>> {
+    Core::new(Swim::new(node, app))
+}
+
+impl<A: SwimApp> Swim<A> {
+    pub fn new(node: NodeId, app: A) -> Self {
+        Swim {
+            app,
+            state: SwimState::new(node),
+        }
+    }
+
+    // How often a member is probed
+    pub fn probe_interval(mut self, interval: Duration) -> Self {
+        self.state.probe_interval = interval;
+        self
+    }
+
+    // How long a direct probe may take before others are asked to try
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.state.ack_timeout = timeout;
+        self
+    }
+
+    // How long a suspect has to refute before it is declared dead
+    pub fn suspicion_timeout(mut self, timeout: Duration) -> Self {
+        self.state.suspicion_timeout = timeout;
+        self
+    }
+
+    // How many neighbors are asked to probe on our behalf
+    pub fn indirect_probes(mut self, count: usize) -> Self {
+        self.state.indirect_probes = count;
+        self
+    }
+
+    fn wrap_context<'a>(&'a mut self, ctx: &'a Context) -> (&'a mut A, SwimContext<'a>) {
+        let ctx = SwimContext {
+            ctx,
+            state: &mut self.state,
+        };
+        (&mut self.app, ctx)
+    }
+
+    fn deliver_events(&mut self, ctx: &Context) {
+        let events = std::mem::take(&mut self.state.events);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        for (node, state) in events {
+            app.handle_member(&mut ctx, node, state);
+        }
+    }
+}
+
+impl<A: SwimApp> App for Swim<A> {
+    fn handle_init(&mut self, ctx: &Context) {
+        self.state.probe(ctx);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_init(&mut ctx);
+    }
+    fn handle_connect(&mut self, ctx: &Context, id: usize) {
+        self.state.hello(ctx, id);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_connect(&mut ctx, id);
+    }
+    fn handle_accept(&mut self, ctx: &Context, listen_socket: usize, id: usize) {
+        self.state.hello(ctx, id);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_accept(&mut ctx, listen_socket, id);
+    }
+    fn handle_close(&mut self, ctx: &Context, id: usize) {
+        self.state.closed(id);
+        self.deliver_events(ctx);
+        let (app, mut ctx) = self.wrap_context(ctx);
+        app.handle_close(&mut ctx, id);
+    }
+    fn handle_frames(&mut self, ctx: &Context, id: usize, frames: Vec<Bytes>) {
+        let (messages, delivered) = self.state.transport.receive(id, frames);
+        for (_node, gossip) in messages {
+            self.state.received(ctx, id, gossip);
+        }
+        self.deliver_events(ctx);
+        if !delivered.is_empty() {
+            let (app, mut ctx) = self.wrap_context(ctx);
+            app.handle_frames(&mut ctx, id, delivered);
+        }
+    }
+    fn handle_timeout(&mut self, ctx: &Context, token: usize) {
+        match timer_kind(token) {
+            (TIMER_PROBE, _) => {
+                self.state.probe(ctx);
+                self.deliver_events(ctx);
+            }
+            (TIMER_ACK, _) => self.state.ack_timeout(ctx),
+            (_, token) => {
+                let (app, mut ctx) = self.wrap_context(ctx);
+                app.handle_timeout(&mut ctx, token);
+            }
+        }
+    }
+    fn handle_shutdown(&mut self) {
+        self.app.handle_shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Events = Rc<RefCell<Vec<(NodeId, MemberState)>>>;
+
+    struct Recorder {
+        events: Events,
+    }
+
+    impl SwimApp for Recorder {
+        fn handle_member(&mut self, _ctx: &mut SwimContext, node: NodeId, state: MemberState) {
+            self.events.borrow_mut().push((node, state));
+        }
+    }
+
+    fn node(id: u64) -> (Core<Swim<Recorder>>, Events) {
+        let events = Rc::new(RefCell::new(vec![]));
+        let recorder = Recorder {
+            events: events.clone(),
+        };
+        let swim = Swim::new(NodeId(id), recorder)
+            .probe_interval(Duration::from_millis(50))
+            .ack_timeout(Duration::from_millis(25))
+            .suspicion_timeout(Duration::from_millis(250));
+        (Core::new(swim), events)
+    }
+
+    fn last(events: &Events, id: u64) -> Option<MemberState> {
+        let events = events.borrow();
+        let found = events.iter().rev().find(|(node, _)| *node == NodeId(id));
+        found.map(|(_, state)| *state)
+    }
+
+    #[test]
+    fn detects_hung_members_and_refutation() {
+        let (addr_a, addr_b, addr_d) = ("127.0.0.1:13317", "127.0.0.1:13318", "127.0.0.1:13328");
+        let (mut a, events_a) = node(1);
+        let (mut b, events_b) = node(2);
+        let (mut c, events_c) = node(3);
+        let (mut d, events_d) = node(4);
+        a.listen(addr_a).unwrap();
+        b.listen(addr_b).unwrap();
+        d.listen(addr_d).unwrap();
+        b.connect(addr_a).unwrap();
+        d.connect(addr_a).unwrap();
+        d.connect(addr_b).unwrap();
+        for addr in [addr_a, addr_b, addr_d].iter() {
+            c.connect(addr).unwrap();
+        }
+
+        let tick = Some(Duration::from_millis(5));
+        let run_until = |cores: &mut [&mut Core<Swim<Recorder>>], done: &dyn Fn() -> bool| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !done() && Instant::now() < deadline {
+                for core in cores.iter_mut() {
+                    core.turn(tick).unwrap();
+                }
+            }
+        };
+
+        let alive = |events: &Events, id| last(events, id) == Some(MemberState::Alive);
+        let all_alive = || {
+            let nodes = [
+                (&events_a, 1),
+                (&events_b, 2),
+                (&events_c, 3),
+                (&events_d, 4),
+            ];
+            nodes
+                .iter()
+                .all(|(events, own)| (1..=4).filter(|id| id != own).all(|id| alive(events, id)))
+        };
+        run_until(&mut [&mut a, &mut b, &mut c, &mut d], &all_alive);
+        assert!(all_alive());
+
+        // C stops answering but keeps its connections. With D around, an
+        // indirect probe between the others never has to go through C, though
+        // a slow machine can still have them suspect each other for a moment.
+        let dead = |events: &Events, id| last(events, id) == Some(MemberState::Dead);
+        let running = [(&events_a, 1), (&events_b, 2), (&events_d, 4)];
+        run_until(&mut [&mut a, &mut b, &mut d], &|| {
+            running.iter().all(|(events, _)| dead(events, 3))
+        });
+        for (events, own) in running.iter() {
+            let states: Vec<MemberState> = events
+                .borrow()
+                .iter()
+                .filter(|(node, _)| *node == NodeId(3))
+                .map(|(_, state)| *state)
+                .collect();
+            let expected = vec![MemberState::Alive, MemberState::Suspect, MemberState::Dead];
+            assert_eq!(states, expected);
+            let others_dead = events
+                .borrow()
+                .iter()
+                .any(|(node, state)| node.0 != 3 && node.0 != *own && *state == MemberState::Dead);
+            assert!(!others_dead);
+        }
+
+        // Once it runs again it hears it is dead and refutes with a new incarnation
+        run_until(&mut [&mut a, &mut b, &mut c, &mut d], &all_alive);
+        assert!(all_alive());
+    }
+}
@@ -0,0 +1,125 @@
+use bytes::{BufMut, Bytes, BytesMut, IntoBuf};
+use mio_framed::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::collections::HashMap;
+
+use crate::NodeId;
+
+// Timer tokens are tagged in the low bits so a protocol and its app can't collide
+const TIMER_BITS: usize = 2;
+pub(crate) const TIMER_APP: usize = 0;
+
+// Frames start with a kind byte, protocol traffic is bincode
+const KIND_PROTOCOL: u8 = 0;
+const KIND_APP: u8 = 1;
+// First on every connection, so messages can be addressed by node
+const KIND_HELLO: u8 = 2;
+
+pub(crate) fn timer_token(kind: usize, token: usize) -> usize {
+    token << TIMER_BITS | kind
+}
+
+// (kind, token) of a timer_token
+pub(crate) fn timer_kind(token: usize) -> (usize, usize) {
+    (token & ((1 << TIMER_BITS) - 1), token >> TIMER_BITS)
+}
+
+// The connections of a protocol that talks node to node, addressed by node
+// once each side's Hello arrives. The app shares them for its own frames.
+pub(crate) struct Transport {
+    node: NodeId,
+    conns: HashMap<usize, NodeId>,
+}
+
+impl Transport {
+    pub fn new(node: NodeId) -> Self {
+        Transport {
+            node,
+            conns: HashMap::new(),
+        }
+    }
+
+    // The node on the other end of a connection, once its Hello arrived
+    pub fn node_of(&self, conn: usize) -> Option<NodeId> {
+        self.conns.get(&conn).copied()
+    }
+
+    pub fn conns(&self) -> impl Iterator<Item = (usize, NodeId)> + '_ {
+        self.conns.iter().map(|(conn, node)| (*conn, *node))
+    }
+
+    pub fn conns_to(&self, node: NodeId) -> impl Iterator<Item = usize> + '_ {
+        self.conns()
+            .filter(move |(_, other)| *other == node)
+            .map(|(conn, _)| conn)
+    }
+
+    // Call on every new connection
+    pub fn hello(&self, ctx: &Context, conn: usize) {
+        let node = bincode::serialize(&self.node).expect("Node id serialization can't fail");
+        write(ctx, conn, KIND_HELLO, &node);
+    }
+
+    pub fn closed(&mut self, conn: usize) -> Option<NodeId> {
+        self.conns.remove(&conn)
+    }
+
+    pub fn send<M: Serialize>(&self, ctx: &Context, conn: usize, message: &M) {
+        let encoded = bincode::serialize(message).expect("Message serialization can't fail");
+        write(ctx, conn, KIND_PROTOCOL, &encoded);
+    }
+
+    // Messages to nodes without a connection are dropped, like a lost packet
+    pub fn send_to<M: Serialize>(&self, ctx: &Context, node: NodeId, message: &M) {
+        if let Some(conn) = self.conns_to(node).next() {
+            self.send(ctx, conn, message);
+        }
+    }
+
+    pub fn send_app(&self, ctx: &Context, conn: usize, frame: &[u8]) {
+        write(ctx, conn, KIND_APP, frame);
+    }
+
+    // Splits what arrived on `conn` into protocol messages and the app's
+    // frames. Messages before the Hello have nobody to come from and are
+    // dropped.
+    pub fn receive<M: DeserializeOwned>(
+        &mut self,
+        conn: usize,
+        frames: Vec<Bytes>,
+    ) -> (Vec<(NodeId, M)>, Vec<Bytes>) {
+        let mut messages = vec![];
+        let mut delivered = vec![];
+        for mut frame in frames {
+            if frame.is_empty() {
+                continue;
+            }
+            let kind = frame[0];
+            frame.advance(1);
+            match kind {
+                KIND_HELLO => {
+                    if let Ok(node) = bincode::deserialize(&frame) {
+                        self.conns.insert(conn, node);
+                    }
+                }
+                KIND_PROTOCOL => match (self.node_of(conn), bincode::deserialize(&frame)) {
+                    (Some(node), Ok(message)) => messages.push((node, message)),
+                    // XXX app.handle_decode_error?
+                    _ => continue,
+                },
+                KIND_APP => delivered.push(frame),
+                _ => continue,
+            }
+        }
+        (messages, delivered)
+    }
+}
+
+fn write(ctx: &Context, conn: usize, kind: u8, payload: &[u8]) {
+    let mut buf = BytesMut::with_capacity(1 + payload.len());
+    buf.put_u8(kind);
+    buf.put_slice(payload);
+    ctx.write_frame(conn, buf.freeze().into_buf());
+}